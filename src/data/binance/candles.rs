@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+
+use super::file::Row;
+use crate::data::error::{DataError, Result};
+
+/// OHLCV bar built from trades, with the same time bounds as Binance's klines
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+    /// Open time in unix epoch to ms, a multiple of the interval
+    pub start: u64,
+    /// Close time in unix epoch to ms, inclusive: `start + interval - 1`
+    pub end: u64,
+    pub open: f32,
+    pub high: f32,
+    pub low: f32,
+    pub close: f32,
+    /// Volume in BASE
+    pub volume: f32,
+}
+
+impl Candle {
+    fn open(start: u64, interval: u64, row: &Row) -> Self {
+        Candle {
+            start,
+            end: start + interval - 1,
+            open: row.price,
+            high: row.price,
+            low: row.price,
+            close: row.price,
+            volume: row.qty,
+        }
+    }
+
+    fn add(&mut self, row: &Row) {
+        self.high = self.high.max(row.price);
+        self.low = self.low.min(row.price);
+        self.close = row.price;
+        self.volume += row.qty;
+    }
+}
+
+/// Buckets trades sorted by time, e.g. the records of a `File`, into candles of `interval`.
+/// A trade on a boundary opens the next candle. Intervals without trades yield no candle,
+/// and a trade older than the current candle is an error.
+pub fn candles<S>(rows: S, interval: Duration) -> BoxStream<'static, Result<Candle>>
+where
+    S: Stream<Item = Result<Row>> + Send + 'static,
+{
+    let interval = (interval.as_millis() as u64).max(1);
+    let state = (rows.boxed().fuse(), None::<Candle>);
+    stream::unfold(state, move |(mut rows, mut current)| async move {
+        loop {
+            let row = match rows.next().await {
+                Some(Ok(row)) => row,
+                Some(Err(e)) => return Some((Err(e), (rows, current))),
+                None => return current.take().map(|candle| (Ok(candle), (rows, None))),
+            };
+            let start = row.time - row.time % interval;
+            match &mut current {
+                Some(candle) if candle.start == start => candle.add(&row),
+                Some(candle) if start < candle.start => {
+                    let error = DataError::InvalidData(format!(
+                        "Trade {} at {} is older than the candle opened at {}",
+                        row.id, row.time, candle.start
+                    ));
+                    return Some((Err(error), (rows, current)));
+                }
+                _ => {
+                    if let Some(done) = current.replace(Candle::open(start, interval, &row)) {
+                        return Some((Ok(done), (rows, current)));
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    fn trade(id: u32, time: u64, price: f32, qty: f32) -> Result<Row> {
+        Ok(Row {
+            id,
+            price,
+            qty,
+            quote_qty: price * qty,
+            time,
+            is_buyer_maker: false,
+            is_best_match: true,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_candles() {
+        let rows = stream::iter(vec![
+            trade(1, 0, 10.0, 1.0),
+            trade(2, 30_000, 12.0, 2.0),
+            trade(3, 59_999, 9.0, 1.0),
+            // on the boundary, opens the second minute
+            trade(4, 60_000, 11.0, 0.5),
+            // nothing traded in the third minute
+            trade(5, 180_500, 13.0, 4.0),
+            trade(6, 181_000, 14.0, 1.0),
+        ]);
+        let bars: Vec<Candle> = candles(rows, Duration::from_secs(60))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            bars,
+            vec![
+                Candle {
+                    start: 0,
+                    end: 59_999,
+                    open: 10.0,
+                    high: 12.0,
+                    low: 9.0,
+                    close: 9.0,
+                    volume: 4.0,
+                },
+                Candle {
+                    start: 60_000,
+                    end: 119_999,
+                    open: 11.0,
+                    high: 11.0,
+                    low: 11.0,
+                    close: 11.0,
+                    volume: 0.5,
+                },
+                Candle {
+                    start: 180_000,
+                    end: 239_999,
+                    open: 13.0,
+                    high: 14.0,
+                    low: 13.0,
+                    close: 14.0,
+                    volume: 5.0,
+                },
+            ]
+        );
+
+        let empty = candles(stream::iter(vec![]), Duration::from_secs(60));
+        assert_eq!(empty.try_collect::<Vec<_>>().await.unwrap(), vec![]);
+
+        let unsorted = stream::iter(vec![trade(1, 60_000, 1.0, 1.0), trade(2, 0, 1.0, 1.0)]);
+        let result: Result<Vec<Candle>> = candles(unsorted, Duration::from_secs(60))
+            .try_collect()
+            .await;
+        assert!(matches!(result, Err(DataError::InvalidData(_))));
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Suffix of Binance's checksum objects, e.g. BTCUSDT-trades-2024-01.zip.CHECKSUM
+pub const DEFAULT_SUFFIX: &str = ".CHECKSUM";
+
+/// Hash published in the checksum objects
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+    #[default]
+    Sha256,
+    Md5,
+    Crc32,
+}
+
+impl ChecksumAlgo {
+    pub fn hasher(self) -> ChecksumHasher {
+        match self {
+            ChecksumAlgo::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+            ChecksumAlgo::Md5 => ChecksumHasher::Md5(Md5::new()),
+            ChecksumAlgo::Crc32 => ChecksumHasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    /// Lower case name, e.g. the extension of the digest caches next to downloaded files
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgo::Sha256 => "sha256",
+            ChecksumAlgo::Md5 => "md5",
+            ChecksumAlgo::Crc32 => "crc32",
+        }
+    }
+
+    /// Upper case hex digest of `data`
+    pub fn digest(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+pub enum ChecksumHasher {
+    Sha256(Sha256),
+    Md5(Md5),
+    Crc32(crc32fast::Hasher),
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Sha256(hasher) => hasher.update(data),
+            ChecksumHasher::Md5(hasher) => hasher.update(data),
+            ChecksumHasher::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Upper case hex, 8 digits for CRC-32
+    pub fn finalize(self) -> String {
+        match self {
+            ChecksumHasher::Sha256(hasher) => format!("{:X}", hasher.finalize()),
+            ChecksumHasher::Md5(hasher) => format!("{:X}", hasher.finalize()),
+            ChecksumHasher::Crc32(hasher) => format!("{:08X}", hasher.finalize()),
+        }
+    }
+}
+
+/// How the checksum of a data object is found and checked
+#[derive(Debug, Clone)]
+pub struct ChecksumOptions {
+    pub algo: ChecksumAlgo,
+    /// Appended to the data object's key to get the checksum object's key
+    pub suffix: Arc<str>,
+    /// Keeps data objects without a checksum object, downloading them unverified with a
+    /// warning. They are an error otherwise
+    pub allow_missing: bool,
+}
+
+impl Default for ChecksumOptions {
+    fn default() -> Self {
+        ChecksumOptions {
+            algo: ChecksumAlgo::default(),
+            suffix: Arc::from(DEFAULT_SUFFIX),
+            allow_missing: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digests() {
+        assert_eq!(
+            ChecksumAlgo::Sha256.digest(b"hello world"),
+            "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9"
+        );
+        assert_eq!(
+            ChecksumAlgo::Md5.digest(b"hello world"),
+            "5EB63BBBE01EEED093CB22BB8F5ACDC3"
+        );
+        assert_eq!(ChecksumAlgo::Crc32.digest(b"hello world"), "0D4A1185");
+    }
+}
@@ -1,6 +1,10 @@
 use casey::lower;
+use chrono::{Datelike, Months, NaiveDate};
 use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+
+use crate::data::error::DataError;
 
 macro_rules! pub_enum_str {
     (pub enum $name:ident {
@@ -12,6 +16,11 @@ macro_rules! pub_enum_str {
         }
 
         impl $name {
+            #[doc = "Every variant, in declaration order."]
+            pub fn variants() -> &'static [Self] {
+                &[$(Self::$variant),*]
+            }
+
             #[doc = "Returns a lower string representation of the enum variant."]
             fn as_str(&self) -> &'static str {
                 match self {
@@ -27,6 +36,36 @@ macro_rules! pub_enum_str {
             }
         }
 
+        impl FromStr for $name {
+            type Err = DataError;
+
+            #[doc = "Parses the string representation of a variant, ignoring case."]
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::variants()
+                    .iter()
+                    .find(|variant| s.eq_ignore_ascii_case(variant.as_str()))
+                    .copied()
+                    .ok_or_else(|| {
+                        let expected: Vec<&str> =
+                            Self::variants().iter().map(Self::as_str).collect();
+                        DataError::Config(format!(
+                            "Unknown {}: {}, expected one of: {}",
+                            lower!(stringify!($name)),
+                            s,
+                            expected.join(", ")
+                        ))
+                    })
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = DataError;
+
+            fn try_from(s: &str) -> Result<Self, Self::Error> {
+                s.parse()
+            }
+        }
+
         impl AsRef<Path> for $name {
             #[doc = "Returns a Path reference to the enum variant's string representation."]
             fn as_ref(&self) -> &Path {
@@ -44,6 +83,13 @@ pub_enum_str! {
     }
 }
 
+pub_enum_str! {
+    pub enum FuturesMarket {
+        Cm,
+        Um,
+    }
+}
+
 pub_enum_str! {
     pub enum Cadence {
         Daily,
@@ -59,6 +105,76 @@ pub_enum_str! {
     }
 }
 
+impl Cadence {
+    /// Date at the end of an object key or file name: the day of a daily file, e.g.
+    /// BTCUSDT-trades-2023-01-15.zip, and the first day of the month of a monthly one, e.g.
+    /// BTCUSDT-trades-2023-01.zip. Parts may be separated by `-` or `_`. None when the name
+    /// does not end with a date of this cadence
+    pub fn parse_date(&self, key: &str) -> Option<NaiveDate> {
+        let name = key.rsplit('/').next()?;
+        // extensions, e.g. .zip.CHECKSUM; pair names have no dots
+        let stem = name.split('.').next()?;
+        let parts: Vec<&str> = stem.split(['-', '_']).collect();
+        let widths: &[usize] = match self {
+            Cadence::Daily => &[4, 2, 2],
+            Cadence::Monthly => &[4, 2],
+        };
+        // the pair and the data type or interval come first
+        if parts.len() <= widths.len() {
+            return None;
+        }
+        let date = &parts[parts.len() - widths.len()..];
+        let numeric = date
+            .iter()
+            .zip(widths)
+            .all(|(part, width)| part.len() == *width && part.bytes().all(|b| b.is_ascii_digit()));
+        if !numeric {
+            return None;
+        }
+        let day = date.get(2).copied().unwrap_or("01");
+        NaiveDate::from_ymd_opt(
+            date[0].parse().ok()?,
+            date[1].parse().ok()?,
+            day.parse().ok()?,
+        )
+    }
+
+    /// First day of the period holding `date`, the date itself for daily files
+    pub fn period_start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Cadence::Daily => date,
+            Cadence::Monthly => date.with_day(1).expect("every month has a first day"),
+        }
+    }
+
+    /// First day of the period after the one holding `date`
+    pub fn next_period(&self, date: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Cadence::Daily => date.succ_opt(),
+            Cadence::Monthly => self.period_start(date).checked_add_months(Months::new(1)),
+        }
+    }
+
+    /// The date as it appears in file names, e.g. 2023-01 for monthly files
+    pub fn format_period(&self, date: NaiveDate) -> String {
+        match self {
+            Cadence::Daily => date.format("%Y-%m-%d").to_string(),
+            Cadence::Monthly => date.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+impl DataType {
+    /// Directory of the data type in Binance's bucket, which is not always lowercase
+    pub fn path_segment(&self) -> &'static str {
+        match self {
+            DataType::AggTrades => "aggTrades",
+            DataType::KLines => "klines",
+            DataType::Trades => "trades",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,10 +200,41 @@ mod tests {
         assert_eq!(Asset::Futures.as_str(), "futures");
         assert_eq!(Asset::Option.as_str(), "option");
         assert_eq!(Asset::Spot.as_str(), "spot");
+        assert_eq!(FuturesMarket::Um.as_str(), "um");
         assert_eq!(Cadence::Daily.as_str(), "daily");
         assert_eq!(DataType::AggTrades.as_str(), "aggtrades");
     }
 
+    #[test]
+    fn test_from_str() {
+        assert_eq!("spot".parse::<Asset>().unwrap(), Asset::Spot);
+        assert_eq!("SPOT".parse::<Asset>().unwrap(), Asset::Spot);
+        assert_eq!(Cadence::try_from("Monthly").unwrap(), Cadence::Monthly);
+        assert_eq!(
+            "aggTrades".parse::<DataType>().unwrap(),
+            DataType::AggTrades
+        );
+        assert!(matches!(
+            "gibberish".parse::<Asset>(),
+            Err(DataError::Config(_))
+        ));
+
+        for variant in DataType::variants() {
+            assert_eq!(variant.as_str().parse::<DataType>().unwrap(), *variant);
+        }
+        assert_eq!(
+            Asset::variants(),
+            &[Asset::Futures, Asset::Option, Asset::Spot]
+        );
+    }
+
+    #[test]
+    fn test_path_segment() {
+        assert_eq!(DataType::AggTrades.path_segment(), "aggTrades");
+        assert_eq!(DataType::KLines.path_segment(), "klines");
+        assert_eq!(DataType::Trades.path_segment(), "trades");
+    }
+
     #[test]
     fn test_display() {
         assert_eq!(format!("{}", Asset::Futures), "futures");
@@ -101,4 +248,62 @@ mod tests {
         assert_eq!(Cadence::Daily.as_ref(), Path::new("daily"));
         assert_eq!(DataType::AggTrades.as_ref(), Path::new("aggtrades"));
     }
+
+    #[test]
+    fn test_parse_date() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+        let daily = "data/spot/daily/trades/BTCUSDT/BTCUSDT-trades-2023-01-15.zip";
+        let monthly = "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades-2023-01.zip";
+
+        assert_eq!(Cadence::Daily.parse_date(daily), date(2023, 1, 15));
+        assert_eq!(Cadence::Monthly.parse_date(monthly), date(2023, 1, 1));
+        assert_eq!(
+            Cadence::Monthly.parse_date(&format!("{monthly}.CHECKSUM")),
+            date(2023, 1, 1)
+        );
+        assert_eq!(
+            Cadence::Daily.parse_date("BTCUSDT_trades_2023_01_15.csv.gz"),
+            date(2023, 1, 15)
+        );
+        assert_eq!(
+            Cadence::Monthly.parse_date("BTCUSDT-1m-2024-02.zip"),
+            date(2024, 2, 1)
+        );
+
+        // a key of the other cadence
+        assert_eq!(Cadence::Monthly.parse_date(daily), None);
+        assert_eq!(Cadence::Daily.parse_date(monthly), None);
+        // malformed
+        assert_eq!(Cadence::Monthly.parse_date("BTCUSDT-trades.zip"), None);
+        assert_eq!(
+            Cadence::Daily.parse_date("BTCUSDT-trades-2023-02-30.zip"),
+            None
+        );
+        assert_eq!(Cadence::Monthly.parse_date("2023-01"), None);
+        assert_eq!(Cadence::Monthly.parse_date(""), None);
+    }
+
+    #[test]
+    fn test_periods() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        assert_eq!(
+            Cadence::Daily.period_start(date(2024, 2, 29)),
+            date(2024, 2, 29)
+        );
+        assert_eq!(
+            Cadence::Monthly.period_start(date(2024, 2, 29)),
+            date(2024, 2, 1)
+        );
+        assert_eq!(
+            Cadence::Daily.next_period(date(2024, 2, 29)),
+            Some(date(2024, 3, 1))
+        );
+        assert_eq!(
+            Cadence::Monthly.next_period(date(2023, 12, 15)),
+            Some(date(2024, 1, 1))
+        );
+        assert_eq!(Cadence::Daily.format_period(date(2024, 2, 9)), "2024-02-09");
+        assert_eq!(Cadence::Monthly.format_period(date(2024, 2, 9)), "2024-02");
+    }
 }
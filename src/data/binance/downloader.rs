@@ -1,48 +1,218 @@
-use std::path::Path;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, OnceLock};
 
-use anyhow::Result;
-use futures::future::try_join_all;
+use chrono::NaiveDate;
+use futures::future::{try_join_all, BoxFuture};
+use futures::StreamExt;
+use regex::Regex;
 use tokio::sync::Semaphore;
 
-use super::data_types::{Asset, Cadence, DataType};
+use super::checksum::ChecksumOptions;
+use super::data_types::{Asset, Cadence, DataType, FuturesMarket};
+use super::file::File;
 use super::file_collection::FileCollection;
 use super::pair::Pair;
-use super::s3::Bucket;
+use super::store::{default_store, ObjectStore};
+use crate::data::error::{DataError, Result};
+use crate::data::source::TradeSource;
+
+const DEFAULT_LIST_CONCURRENCY: usize = 100;
+
+/// A pair available under a cadence and data type, e.g. monthly/trades/BTCUSDC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub cadence: String,
+    pub data_type: String,
+    pub pair: Pair,
+}
+
+/// What `get_files` resolved to, before anything is downloaded
+#[derive(Debug, Clone)]
+pub struct DownloadPlan {
+    pub pairs: Vec<Pair>,
+    pub files: FileCollection,
+}
+
+impl DownloadPlan {
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Sum of the S3 object sizes
+    pub fn total_bytes(&self) -> u64 {
+        self.files.total_bytes()
+    }
+}
+
+/// Outcome of `Downloader::download_all`, by file
+#[derive(Debug, Default, Clone)]
+pub struct DownloadSummary {
+    pub downloaded: Vec<File>,
+    /// Already on disk, e.g. from an interrupted run
+    pub skipped: Vec<File>,
+    pub failed: Vec<(File, String)>,
+}
+
+impl DownloadSummary {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
 
 pub struct Downloader {
     pub name: Arc<str>,
     pub asset: Asset,
     pub cadence: Cadence,
     pub data_type: DataType,
+    /// Sub-market of `Asset::Futures`, ignored for other assets
+    pub futures_market: FuturesMarket,
+    /// Candle interval of `DataType::KLines`, e.g. 1m or 1h
+    pub kline_interval: Arc<str>,
+    /// Inclusive range of days to keep files for, every file when unset
+    pub date_range: Option<(NaiveDate, NaiveDate)>,
+    /// Only keeps the newest file of each pair, see `with_latest_only`
+    pub latest_only: bool,
+    /// Pairs listed concurrently by `get_files`
+    pub list_concurrency: usize,
+    /// How the files' checksum objects are named and checked
+    pub checksum: ChecksumOptions,
+    /// Where the files are downloaded to, the data dir of the config when unset
+    pub data_dir: Option<PathBuf>,
     pair_filter_excluded: Option<Vec<String>>,
     pair_filter_starts_with: Option<Vec<String>>,
     pair_filter_ends_with: Option<Vec<String>>,
+    pair_filter_regex: Option<Regex>,
+    pair_allowlist: Option<BTreeSet<String>>,
+    /// Where pairs and files are listed from, and which the files download from. The store of
+    /// the config is created on first use when unset, then shared by every file
+    store: OnceLock<Arc<dyn ObjectStore>>,
 }
 
 impl Downloader {
     pub fn new(name: &str, asset: Asset, cadence: Cadence, data_type: DataType) -> Result<Self> {
-        match asset {
-            Asset::Futures | Asset::Option => todo!("Futures | Option not implemented."),
-            Asset::Spot => (),
-        }
-
-        match data_type {
-            DataType::AggTrades | DataType::KLines => todo!("AggTrades | Klines not implemented."),
-            DataType::Trades => (),
-        }
-
         Ok(Self {
             name: Arc::from(name),
             asset,
             cadence,
             data_type,
+            futures_market: FuturesMarket::Um,
+            kline_interval: Arc::from("1m"),
+            date_range: None,
+            latest_only: false,
+            list_concurrency: DEFAULT_LIST_CONCURRENCY,
+            checksum: ChecksumOptions::default(),
+            data_dir: None,
             pair_filter_excluded: None,
             pair_filter_starts_with: None,
             pair_filter_ends_with: None,
+            pair_filter_regex: None,
+            pair_allowlist: None,
+            store: OnceLock::new(),
         })
     }
 
+    /// USD-M (`um`, the default) or COIN-M (`cm`) futures
+    pub fn with_futures_market(mut self, market: FuturesMarket) -> Self {
+        self.futures_market = market;
+        self
+    }
+
+    /// Lower it on small machines, every listing holds a connection open
+    pub fn with_list_concurrency(mut self, concurrency: usize) -> Self {
+        self.list_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// For mirrors which publish MD5 or CRC-32 checksums, or name them differently
+    pub fn with_checksum(mut self, checksum: ChecksumOptions) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Downloads under `dir` rather than the data dir of the config, e.g. to spread
+    /// downloaders over several disks
+    pub fn with_data_dir(mut self, dir: &Path) -> Self {
+        self.data_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    fn list_semaphore(&self) -> Arc<Semaphore> {
+        Arc::new(Semaphore::new(self.list_concurrency))
+    }
+
+    pub fn with_kline_interval(mut self, interval: &str) -> Self {
+        self.kline_interval = Arc::from(interval);
+        self
+    }
+
+    /// Only keeps files which overlap `start..=end`. Files whose name has no date are kept.
+    pub fn with_date_range(mut self, start: NaiveDate, end: NaiveDate) -> Self {
+        self.date_range = Some((start, end));
+        self
+    }
+
+    fn in_date_range(&self, file: &File) -> bool {
+        match (self.date_range, file.period()) {
+            (Some((start, end)), Some((file_start, file_end))) => {
+                file_start <= end && file_end >= start
+            }
+            _ => true,
+        }
+    }
+
+    /// Only keeps the file of each pair whose name has the latest date, e.g. to keep a
+    /// rolling recent dataset. Applied after the date range; pairs without any dated file
+    /// are dropped
+    pub fn with_latest_only(mut self) -> Self {
+        self.latest_only = true;
+        self
+    }
+
+    fn keep_latest(&self, files: FileCollection) -> FileCollection {
+        let mut latest: BTreeMap<Arc<str>, ((NaiveDate, NaiveDate), File)> = BTreeMap::new();
+        let mut undated = BTreeSet::new();
+        for file in files {
+            let Some(period) = file.period() else {
+                undated.insert(file.pair.clone());
+                continue;
+            };
+            match latest.get(&file.pair) {
+                Some((newest, _)) if *newest >= period => {}
+                _ => {
+                    latest.insert(file.pair.clone(), (period, file));
+                }
+            }
+        }
+        for pair in undated.iter().filter(|pair| !latest.contains_key(*pair)) {
+            log::warn!(
+                "[{}] Dropped {}, none of its files has a date",
+                self.name,
+                pair
+            );
+        }
+        latest.into_values().map(|(_, file)| file).collect()
+    }
+
+    /// `data/<asset>`, plus the sub-market for futures, e.g. data/futures/um
+    fn asset_path(&self) -> PathBuf {
+        let path = Path::new("data").join(self.asset);
+        match self.asset {
+            Asset::Futures => path.join(self.futures_market),
+            Asset::Option | Asset::Spot => path,
+        }
+    }
+
+    /// Prefix under which the pairs live, e.g. data/spot/monthly/trades
+    fn pairs_path(&self) -> String {
+        self.asset_path()
+            .join(self.cadence)
+            .join(self.data_type.path_segment())
+            .to_string_lossy()
+            .to_string()
+    }
+
     pub fn with_pair_excluded(mut self, pairs: &[&str]) -> Self {
         let pairs: Vec<String> = pairs.iter().map(|p| p.to_string()).collect();
         self.pair_filter_excluded = Some(pairs);
@@ -61,62 +231,251 @@ impl Downloader {
         self
     }
 
-    pub async fn get_pairs(&self) -> Result<Vec<Pair>> {
-        let path = Path::new("data")
-            .join(self.asset)
-            .join(self.cadence)
-            .join(self.data_type)
-            .to_string_lossy()
-            .to_string();
+    /// Only keeps pairs whose name matches `pattern`, on top of the other filters
+    pub fn with_pair_matching(mut self, pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| DataError::Config(format!("Invalid pair pattern {}: {}", pattern, e)))?;
+        self.pair_filter_regex = Some(regex);
+        Ok(self)
+    }
+
+    /// Only keeps the pairs listed in `path`, one per line, on top of the other filters.
+    /// Blank lines and lines starting with `#` are ignored
+    pub fn with_pair_allowlist_file(mut self, path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            DataError::io(
+                format!("Could not read pair allowlist: {}", path.to_string_lossy()),
+                e,
+            )
+        })?;
+        let pairs = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        self.pair_allowlist = Some(pairs);
+        Ok(self)
+    }
+
+    /// Lists and downloads from `store` instead of the S3 bucket of the config
+    pub fn with_object_store(mut self, store: Arc<dyn ObjectStore>) -> Self {
+        self.store = OnceLock::from(store);
+        self
+    }
+
+    fn store(&self) -> Result<Arc<dyn ObjectStore>> {
+        if let Some(store) = self.store.get() {
+            return Ok(Arc::clone(store));
+        }
+        // a concurrent first use may build a second store, only one of them is kept
+        let _ = self.store.set(default_store()?);
+        Ok(Arc::clone(self.store.get().expect("store was just set")))
+    }
+
+    /// Every pair of the cadence and data type, before the pair filters. Use `matches` to
+    /// tell which of them `get_pairs` keeps
+    pub async fn list_all_pairs(&self) -> Result<Vec<Pair>> {
+        let path = self.pairs_path();
 
         log::info!("[{}] Fetching pairs from: {}", self.name, &path);
-        let bucket = Bucket::new()?;
-        let mut pairs = bucket.list_pairs(&path).await?;
+        self.store()?.list_pairs(&path).await
+    }
+
+    pub async fn get_pairs(&self) -> Result<Vec<Pair>> {
+        let path = self.pairs_path();
+        let mut pairs = self.list_all_pairs().await?;
 
-        pairs.retain(|p| {
-            let mut has_filters = false;
+        if let Some(allowlist) = &self.pair_allowlist {
+            let listed: BTreeSet<&str> = pairs.iter().map(|p| p.name.as_ref()).collect();
+            for missing in allowlist.iter().filter(|p| !listed.contains(p.as_str())) {
+                log::warn!(
+                    "[{}] Allowlisted pair {} is not in {}",
+                    self.name,
+                    missing,
+                    &path
+                );
+            }
+        }
+        let listed = pairs.len();
+        pairs.retain(|p| self.matches(p));
 
-            if let Some(excluded_filters) = &self.pair_filter_excluded {
-                if excluded_filters.iter().any(|f| p.name.contains(f)) {
-                    return false;
-                }
+        if listed == 0 {
+            log::warn!("[{}] No pairs listed in {}", self.name, &path);
+        } else if pairs.is_empty() {
+            log::warn!(
+                "[{}] No pairs matched the filters, out of {} listed in {}",
+                self.name,
+                listed,
+                &path
+            );
+        } else {
+            log::info!("[{}] Found {} pairs to download.", self.name, pairs.len());
+        }
+        Ok(pairs)
+    }
+
+    /// Whether the pair filters keep `p`
+    pub fn matches(&self, p: &Pair) -> bool {
+        let mut has_filters = false;
+
+        if let Some(regex) = &self.pair_filter_regex {
+            if !regex.is_match(&p.name) {
+                return false;
             }
+        }
 
-            if let Some(starts_with_filters) = &self.pair_filter_starts_with {
-                has_filters = true;
-                if starts_with_filters.iter().any(|f| p.name.starts_with(f)) {
-                    return true;
-                }
+        if let Some(allowlist) = &self.pair_allowlist {
+            if !allowlist.contains(p.name.as_ref()) {
+                return false;
             }
+        }
+
+        if let Some(excluded_filters) = &self.pair_filter_excluded {
+            if excluded_filters.iter().any(|f| p.name.contains(f)) {
+                return false;
+            }
+        }
 
-            if let Some(ends_with_filters) = &self.pair_filter_ends_with {
-                has_filters = true;
-                if ends_with_filters.iter().any(|f| p.name.ends_with(f)) {
-                    return true;
+        if let Some(starts_with_filters) = &self.pair_filter_starts_with {
+            has_filters = true;
+            if starts_with_filters.iter().any(|f| p.name.starts_with(f)) {
+                return true;
+            }
+        }
+
+        if let Some(ends_with_filters) = &self.pair_filter_ends_with {
+            has_filters = true;
+            if ends_with_filters.iter().any(|f| p.name.ends_with(f)) {
+                return true;
+            }
+        }
+
+        // If we have filters, we want the default to exclude ==> false
+        // If no filters, we want the default to return all ==> true
+        !has_filters
+    }
+
+    /// Walks `data/<asset>/` (`data/futures/<market>/` for futures) and lists every pair under every cadence and data type,
+    /// regardless of the downloader's own cadence and data type. Pair filters still apply.
+    pub async fn list_tree(&self) -> Result<Vec<TreeEntry>> {
+        let path = self.asset_path().to_string_lossy().to_string();
+
+        log::info!("[{}] Walking tree from: {}", self.name, &path);
+        let bucket = self.store()?;
+        let mut entries = Vec::new();
+        for cadence in bucket.list_pairs(&path).await? {
+            for data_type in bucket.list_pairs(&cadence.prefix).await? {
+                for pair in bucket.list_pairs(&data_type.prefix).await? {
+                    if self.matches(&pair) {
+                        entries.push(TreeEntry {
+                            cadence: cadence.name.to_string(),
+                            data_type: data_type.name.to_string(),
+                            pair,
+                        });
+                    }
                 }
             }
+        }
 
-            // If we have filters, we want the default to exclude ==> false
-            // If no filters, we want the default to return all ==> true
-            !has_filters
-        });
+        log::info!(
+            "[{}] Found {} cadence/data type/pair combinations.",
+            self.name,
+            entries.len()
+        );
+        Ok(entries)
+    }
 
-        log::info!("[{}] Found {} pairs to download.", self.name, pairs.len());
-        Ok(pairs)
+    /// Fails fast when the bucket cannot be listed, e.g. before a long run
+    pub async fn check_s3(&self) -> Result<()> {
+        self.store()?
+            .check(&format!("{}/", self.pairs_path()))
+            .await
+    }
+
+    /// Dry run: resolves the pairs and the objects which would be fetched, without
+    /// downloading any of them
+    pub async fn plan(&self) -> Result<DownloadPlan> {
+        let pairs = self.get_pairs().await?;
+        let files = self.get_files(&pairs).await?;
+        let plan = DownloadPlan { pairs, files };
+        log::info!(
+            "[{}] Plan: {}, out of {} pairs",
+            self.name,
+            plan.files,
+            plan.pairs.len()
+        );
+        Ok(plan)
+    }
+
+    /// Downloads the files of the matching pairs, `num_semaphore` at a time, without
+    /// touching ClickHouse. Files already on disk are skipped, so a run which was
+    /// interrupted picks up where it stopped
+    pub async fn download_all(&self, num_semaphore: usize) -> Result<DownloadSummary> {
+        let pairs = self.get_pairs().await?;
+        let files = self.get_files(&pairs).await?;
+
+        let mut summary = DownloadSummary::default();
+        let mut pending = Vec::new();
+        for file in files {
+            if file.is_downloaded().await? {
+                summary.skipped.push(file);
+            } else {
+                pending.push(file);
+            }
+        }
+        let mut pending = FileCollection::new(pending);
+        pending.sort_largest_first();
+
+        let mut results = pin!(pending.download_results(num_semaphore.max(1)));
+        while let Some((file, result)) = results.next().await {
+            match result {
+                Ok(()) => summary.downloaded.push(file),
+                Err(e) => summary.failed.push((file, e.to_string())),
+            }
+        }
+        log::info!(
+            "[{}] Downloaded {} files, {} already on disk, {} failed",
+            self.name,
+            summary.downloaded.len(),
+            summary.skipped.len(),
+            summary.failed.len()
+        );
+        Ok(summary)
+    }
+
+    /// Where the files of a pair live. Klines nest them one level deeper, by interval
+    fn files_pair(&self, pair: &Pair) -> Pair {
+        match self.data_type {
+            DataType::KLines => Pair::new(
+                &format!(
+                    "{}/{}/",
+                    pair.prefix.trim_end_matches('/'),
+                    self.kline_interval
+                ),
+                &pair.name,
+            ),
+            DataType::AggTrades | DataType::Trades => pair.clone(),
+        }
     }
 
-    // TODO: make configurable semaphore
     pub async fn get_files(&self, pairs: &[Pair]) -> Result<FileCollection> {
-        let semaphore = Arc::new(Semaphore::new(100));
+        let semaphore = self.list_semaphore();
+        let store = self.store()?;
         let tasks: Vec<_> = pairs
             .iter()
             .map(|pair| {
                 let semaphore = semaphore.clone();
-                let pair = pair.clone();
+                let store = Arc::clone(&store);
+                let pair = self.files_pair(pair);
+                let checksum = self.checksum.clone();
                 let downloader_name = self.name.clone();
 
                 tokio::spawn(async move {
-                    let _permit = semaphore.acquire().await?;
+                    let _permit = semaphore
+                        .acquire()
+                        .await
+                        .expect("semaphore is never closed");
                     log::info!(
                         "[{}] Getting objects for {} from: {}",
                         downloader_name,
@@ -124,7 +483,7 @@ impl Downloader {
                         pair.prefix
                     );
 
-                    let files = pair.get_files().await?;
+                    let files = pair.get_files(&store, &checksum).await?;
                     log::info!(
                         "[{}] Discovered {} objects for {} from: {}",
                         downloader_name,
@@ -132,13 +491,39 @@ impl Downloader {
                         pair.name,
                         pair.prefix
                     );
-                    Ok::<_, anyhow::Error>(files)
+                    Ok(files)
                 })
             })
             .collect();
 
-        let results = try_join_all(tasks).await?;
-        let files = results.into_iter().flatten().collect::<FileCollection>();
+        // A failed listing must fail the run rather than silently drop the pair's files
+        let mut files = try_join_all(tasks)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .collect::<FileCollection>();
+        if let Some(dir) = &self.data_dir {
+            files = files
+                .into_iter()
+                .map(|file| file.with_data_dir(dir))
+                .collect::<Result<_>>()?;
+        }
+        if let Some((start, end)) = self.date_range {
+            let before = files.len();
+            files.retain(|file| self.in_date_range(file));
+            log::info!(
+                "[{}] Kept {} of {} objects between {} and {}",
+                self.name,
+                files.len(),
+                before,
+                start,
+                end
+            );
+        }
+        if self.latest_only {
+            files = self.keep_latest(files);
+        }
 
         log::info!(
             "[{}] Found a total of {} objects from {} pairs",
@@ -151,13 +536,465 @@ impl Downloader {
     }
 }
 
+impl TradeSource for Downloader {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn list_pairs(&self) -> BoxFuture<'_, Result<Vec<Pair>>> {
+        Box::pin(self.get_pairs())
+    }
+
+    fn list_files<'a>(&'a self, pairs: &'a [Pair]) -> BoxFuture<'a, Result<FileCollection>> {
+        Box::pin(self.get_files(pairs))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::test_utils;
+    use s3::serde_types::Object;
 
     #[test]
     fn downloader_is_normal() {
         test_utils::is_normal::<Downloader>();
     }
+
+    #[test]
+    fn test_pairs_path() {
+        let spot =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        assert_eq!(spot.pairs_path(), "data/spot/monthly/trades");
+
+        let futures =
+            Downloader::new("test", Asset::Futures, Cadence::Monthly, DataType::Trades).unwrap();
+        assert_eq!(futures.pairs_path(), "data/futures/um/monthly/trades");
+
+        let futures = futures.with_futures_market(FuturesMarket::Cm);
+        assert_eq!(futures.pairs_path(), "data/futures/cm/monthly/trades");
+    }
+
+    #[test]
+    fn test_klines_paths() {
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::KLines)
+            .unwrap()
+            .with_kline_interval("1h");
+        assert_eq!(downloader.pairs_path(), "data/spot/monthly/klines");
+
+        let pair = Pair::new("data/spot/monthly/klines/BTCUSDT/", "BTCUSDT");
+        assert_eq!(
+            downloader.files_pair(&pair),
+            Pair::new("data/spot/monthly/klines/BTCUSDT/1h/", "BTCUSDT")
+        );
+    }
+
+    #[test]
+    fn test_date_range() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_date_range(date("2023-01-15"), date("2023-03-01"));
+
+        let mut files = FileCollection::new(
+            [
+                "BTCUSDT-trades-2022-12.zip",
+                "BTCUSDT-trades-2023-01.zip",
+                "BTCUSDT-trades-2023-03.zip",
+                "BTCUSDT-trades-2023-04.zip",
+                "BTCUSDT-trades-2023-01-14.zip",
+                "BTCUSDT-trades-2023-01-15.zip",
+                "BTCUSDT-trades-2023-03-02.zip",
+            ]
+            .iter()
+            .map(|name| File::from_path("BTCUSDT", Path::new(name)))
+            .collect(),
+        );
+        files.retain(|file| downloader.in_date_range(file));
+
+        let kept: Vec<String> = files
+            .iter()
+            .map(|f| f.path.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                "BTCUSDT-trades-2023-01.zip",
+                "BTCUSDT-trades-2023-03.zip",
+                "BTCUSDT-trades-2023-01-15.zip",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_latest_only() {
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_latest_only();
+        let files = FileCollection::new(
+            [
+                ("BTCUSDT", "BTCUSDT-trades-2023-12.zip"),
+                ("BTCUSDT", "BTCUSDT-trades-2024-02.zip"),
+                ("BTCUSDT", "BTCUSDT-trades-2024-01.zip"),
+                ("ETHUSDT", "ETHUSDT-trades-2024-01-30.zip"),
+                ("ETHUSDT", "ETHUSDT-trades-2024-01-31.zip"),
+                ("ETHUSDT", "ETHUSDT-trades-2023-05.zip"),
+                ("SOLUSDT", "SOLUSDT-trades.zip"),
+            ]
+            .iter()
+            .map(|(pair, name)| File::from_path(pair, Path::new(name)))
+            .collect(),
+        );
+
+        let kept: Vec<String> = downloader
+            .keep_latest(files)
+            .iter()
+            .map(|f| f.path.to_string_lossy().to_string())
+            .collect();
+        // SOLUSDT has no dated file
+        assert_eq!(
+            kept,
+            vec![
+                "BTCUSDT-trades-2024-02.zip",
+                "ETHUSDT-trades-2024-01-31.zip"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_total_bytes() {
+        let object = |key: &str, size: u64| Object {
+            last_modified: String::new(),
+            e_tag: None,
+            storage_class: None,
+            key: key.to_string(),
+            owner: None,
+            size,
+        };
+        let prefix = "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades";
+        let objects = vec![
+            object(&format!("{prefix}-2024-01.zip"), 100),
+            object(&format!("{prefix}-2024-01.zip.CHECKSUM"), 1),
+            object(&format!("{prefix}-2024-02.zip"), 250),
+            object(&format!("{prefix}-2024-02.zip.CHECKSUM"), 1),
+        ];
+        let plan = DownloadPlan {
+            pairs: vec![Pair::new("data/spot/monthly/trades/BTCUSDT/", "BTCUSDT")],
+            files: FileCollection::from_objects("BTCUSDT", objects, &ChecksumOptions::default())
+                .unwrap(),
+        };
+        assert_eq!(plan.num_files(), 2);
+        assert_eq!(plan.total_bytes(), 350);
+    }
+
+    #[test]
+    fn test_matches() {
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_pair_ends_with(&["USDC"])
+            .with_pair_excluded(&["DOWN"]);
+        assert!(downloader.matches(&Pair::new("", "BTCUSDC")));
+        assert!(!downloader.matches(&Pair::new("", "BTCUSDT")));
+        assert!(!downloader.matches(&Pair::new("", "BTCDOWNUSDC")));
+    }
+
+    #[test]
+    fn test_list_concurrency() {
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        assert_eq!(
+            downloader.list_semaphore().available_permits(),
+            DEFAULT_LIST_CONCURRENCY
+        );
+
+        let downloader = downloader.with_list_concurrency(4);
+        assert_eq!(downloader.list_semaphore().available_permits(), 4);
+        assert_eq!(downloader.with_list_concurrency(0).list_concurrency, 1);
+    }
+
+    #[test]
+    fn test_pair_matching() {
+        let names = [
+            "BTCUSDT", "ETHUSDT", "BTCUSDC", "SOLUSDT", "ETHBTC", "XBTCUSDT",
+        ];
+        let matching = |downloader: &Downloader| -> Vec<&str> {
+            names
+                .into_iter()
+                .filter(|name| downloader.matches(&Pair::new("", name)))
+                .collect()
+        };
+
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_pair_matching("^(BTC|ETH)USDT$")
+            .unwrap();
+        assert_eq!(matching(&downloader), vec!["BTCUSDT", "ETHUSDT"]);
+
+        // ANDed with the other filters
+        let downloader = downloader.with_pair_excluded(&["ETH"]);
+        assert_eq!(matching(&downloader), vec!["BTCUSDT"]);
+
+        let invalid = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_pair_matching("(BTC");
+        assert!(matches!(invalid, Err(DataError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_get_pairs_from_store() {
+        use crate::data::binance::store::MemoryStore;
+
+        let store = MemoryStore::new();
+        for pair in ["BTCUSDC", "BTCUSDT", "ETHUSDC", "BTCDOWNUSDC"] {
+            let key = format!("data/spot/monthly/trades/{pair}/{pair}-trades-2024-01.zip");
+            store.insert(&key, "zip");
+            store.insert(&format!("{key}.CHECKSUM"), "sum");
+        }
+        // another cadence is not listed
+        store.insert(
+            "data/spot/daily/trades/SOLUSDC/SOLUSDC-trades-2024-01-01.zip",
+            "",
+        );
+
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_object_store(Arc::new(store))
+            .with_pair_ends_with(&["USDC"])
+            .with_pair_excluded(&["DOWN"]);
+        let pairs = downloader.get_pairs().await.unwrap();
+        let names: Vec<&str> = pairs.iter().map(|p| p.name.as_ref()).collect();
+        assert_eq!(names, vec!["BTCUSDC", "ETHUSDC"]);
+
+        let all = downloader.list_all_pairs().await.unwrap();
+        assert_eq!(all.len(), 4);
+        let dropped: Vec<&str> = all
+            .iter()
+            .filter(|p| !downloader.matches(p))
+            .map(|p| p.name.as_ref())
+            .collect();
+        assert_eq!(dropped, vec!["BTCDOWNUSDC", "BTCUSDT"]);
+
+        let files = downloader.get_files(&pairs).await.unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files.total_bytes(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_files_share_the_store() {
+        use crate::data::binance::store::MemoryStore;
+
+        let store = MemoryStore::new();
+        for month in ["2024-01", "2024-02", "2024-03"] {
+            let key = format!("data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-{month}.zip");
+            store.insert(&key, "zip");
+            store.insert(&format!("{key}.CHECKSUM"), "sum");
+        }
+        let store: Arc<dyn ObjectStore> = Arc::new(store);
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_object_store(Arc::clone(&store));
+        let pairs = downloader.get_pairs().await.unwrap();
+        let files = downloader.get_files(&pairs).await.unwrap();
+        assert_eq!(files.len(), 3);
+        // held here, by the downloader and by each file, no other store was created
+        assert_eq!(Arc::strong_count(&store), 2 + files.len());
+
+        // the store of the config is only created once
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        let first = downloader.store().unwrap();
+        assert!(Arc::ptr_eq(&first, &downloader.store().unwrap()));
+    }
+
+    /// A `MemoryStore` whose listings of `failing` fail
+    #[derive(Debug)]
+    struct FailingListingStore {
+        inner: crate::data::binance::store::MemoryStore,
+        failing: &'static str,
+    }
+
+    impl ObjectStore for FailingListingStore {
+        fn list_pairs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>> {
+            self.inner.list_pairs(path)
+        }
+
+        fn list_objects<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+            if path.contains(self.failing) {
+                return Box::pin(async move {
+                    Err(DataError::Timeout {
+                        context: format!("Listing {path}"),
+                        after: std::time::Duration::from_secs(30),
+                    })
+                });
+            }
+            self.inner.list_objects(path)
+        }
+
+        fn get_object_to_file<'a>(
+            &'a self,
+            key: &'a str,
+            file_path: &'a Path,
+            overwrite: bool,
+            algo: crate::data::binance::checksum::ChecksumAlgo,
+        ) -> BoxFuture<'a, Result<String>> {
+            self.inner
+                .get_object_to_file(key, file_path, overwrite, algo)
+        }
+
+        fn read_object_stream<'a>(
+            &'a self,
+            path: &'a str,
+        ) -> BoxFuture<'a, Result<Box<dyn tokio::io::AsyncRead + Send + Unpin>>> {
+            self.inner.read_object_stream(path)
+        }
+
+        fn read_object<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<String>> {
+            self.inner.read_object(path)
+        }
+
+        fn check<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<()>> {
+            self.inner.check(path)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_listing_fails_get_files() {
+        use crate::data::binance::store::MemoryStore;
+
+        let inner = MemoryStore::new();
+        for pair in ["BTCUSDC", "ETHUSDC", "SOLUSDC"] {
+            let key = format!("data/spot/monthly/trades/{pair}/{pair}-trades-2024-01.zip");
+            inner.insert(&key, "zip");
+            inner.insert(&format!("{key}.CHECKSUM"), "sum");
+        }
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_object_store(Arc::new(FailingListingStore {
+                inner,
+                failing: "ETHUSDC",
+            }));
+        let pairs = downloader.get_pairs().await.unwrap();
+        assert_eq!(pairs.len(), 3);
+
+        // the files of BTCUSDC and SOLUSDC are not returned without those of ETHUSDC
+        let error = downloader.get_files(&pairs).await.unwrap_err();
+        assert!(
+            matches!(&error, DataError::Timeout { context, .. } if context.contains("ETHUSDC"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pair_allowlist_file() {
+        use crate::data::binance::store::MemoryStore;
+
+        let store = MemoryStore::new();
+        for pair in ["BTCUSDC", "BTCUSDT", "ETHUSDC", "SOLUSDC"] {
+            store.insert(
+                &format!("data/spot/monthly/trades/{pair}/{pair}-trades-2024-01.zip"),
+                "zip",
+            );
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let allowlist = dir.path().join("pairs.txt");
+        std::fs::write(
+            &allowlist,
+            "# curated pairs\nBTCUSDC\n\n  BTCUSDT  \nSOLUSDC\nDELISTEDUSDC\n",
+        )
+        .unwrap();
+
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_object_store(Arc::new(store))
+            .with_pair_allowlist_file(&allowlist)
+            .unwrap()
+            .with_pair_ends_with(&["USDC"]);
+        // DELISTEDUSDC is only warned about
+        let pairs = downloader.get_pairs().await.unwrap();
+        let names: Vec<&str> = pairs.iter().map(|p| p.name.as_ref()).collect();
+        assert_eq!(names, vec!["BTCUSDC", "SOLUSDC"]);
+
+        let missing = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_pair_allowlist_file(&dir.path().join("missing.txt"));
+        assert!(matches!(missing, Err(DataError::Io { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_data_dir() {
+        use crate::data::binance::store::MemoryStore;
+
+        let store: Arc<dyn ObjectStore> = Arc::new(MemoryStore::new().with_object(
+            "data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-2024-01.zip",
+            "zip",
+        ));
+        let downloader = |dir: &str| {
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+                .unwrap()
+                .with_object_store(Arc::clone(&store))
+                .with_checksum(ChecksumOptions {
+                    allow_missing: true,
+                    ..Default::default()
+                })
+                .with_data_dir(Path::new(dir))
+        };
+        let path = |downloader: Downloader| async move {
+            let pairs = downloader.get_pairs().await.unwrap();
+            let files = downloader.get_files(&pairs).await.unwrap();
+            files.iter().next().unwrap().path.to_path_buf()
+        };
+
+        assert_eq!(
+            path(downloader("/mnt/disk1")).await,
+            Path::new("/mnt/disk1/binance/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-2024-01.zip")
+        );
+        assert_eq!(
+            path(downloader("/mnt/disk2/")).await,
+            Path::new("/mnt/disk2/binance/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-2024-01.zip")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_all() {
+        use crate::data::binance::checksum::ChecksumAlgo;
+        use crate::data::binance::store::MemoryStore;
+
+        let store = MemoryStore::new();
+        for (pair, published) in [("BTCUSDC", "zip"), ("ETHUSDC", "zip"), ("SOLUSDC", "other")] {
+            let key = format!("data/spot/monthly/trades/{pair}/{pair}-trades-2024-01.zip");
+            store.insert(&key, "zip");
+            store.insert(
+                &format!("{key}.CHECKSUM"),
+                format!(
+                    "{}  {pair}-trades-2024-01.zip",
+                    ChecksumAlgo::Sha256.digest(published.as_bytes())
+                ),
+            );
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_object_store(Arc::new(store))
+            .with_data_dir(dir.path());
+        let names = |files: &[File]| {
+            let mut names: Vec<String> = files.iter().map(|f| f.pair.to_string()).collect();
+            names.sort();
+            names
+        };
+
+        let summary = downloader.download_all(2).await.unwrap();
+        assert_eq!(names(&summary.downloaded), vec!["BTCUSDC", "ETHUSDC"]);
+        assert!(summary.skipped.is_empty());
+        // the checksum of SOLUSDC does not match
+        assert_eq!(summary.failed.len(), 1);
+        assert!(!summary.is_ok());
+        for file in &summary.downloaded {
+            assert_eq!(std::fs::read_to_string(&*file.path).unwrap(), "zip");
+        }
+
+        // the second run only fetches what is missing
+        let summary = downloader.download_all(2).await.unwrap();
+        assert!(summary.downloaded.is_empty());
+        assert_eq!(names(&summary.skipped), vec!["BTCUSDC", "ETHUSDC"]);
+        assert_eq!(summary.failed.len(), 1);
+    }
 }
@@ -1,28 +1,59 @@
-use std::{ops::Deref, path::Path, sync::Arc};
+use std::{
+    fmt,
+    future::Future,
+    hash::{Hash, Hasher},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
 
-use anyhow::{anyhow, Context, Result};
+use async_compression::tokio::bufread::GzipDecoder;
 use async_zip::tokio::read::seek::ZipFileReader;
-use csv_async::DeserializeRecordsIntoStream;
+use chrono::{DateTime, Months, NaiveDate, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
 use serde::{
-    de::{self, Unexpected},
+    de::{self, DeserializeOwned, Unexpected},
     Deserialize, Deserializer, Serialize,
 };
-use sha2::{Digest, Sha256};
+use serde_json::value::RawValue;
 use tokio::{
     fs,
-    io::{AsyncRead, AsyncReadExt, BufReader},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+        BufReader, BufWriter,
+    },
 };
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
-use super::s3::Bucket;
+use super::checksum::ChecksumAlgo;
+use super::data_types::Cadence;
+use super::store::{default_store, ObjectStore};
+use crate::data::error::{DataError, Result};
 use crate::utils::config;
+use crate::utils::metrics;
+use crate::utils::retry::RetryPolicy;
 
-trait DeserializableFromCSV<'r> {
+/// Rows of the CSVs inside Binance's zips, see `skip_header` for the ones with a header
+pub trait DeserializableFromCSV<'r>: DeserializeOwned + 'r {
     fn into_deserialize_from_csv_reader<R: AsyncRead + Send + Unpin + 'r>(
         reader: R,
     ) -> csv_async::DeserializeRecordsIntoStream<'r, R, Self>
     where
-        Self: Sized;
+        Self: Sized,
+    {
+        csv_async::AsyncReaderBuilder::new()
+            .has_headers(false)
+            .create_deserializer(reader)
+            .into_deserialize()
+    }
 }
 
 // https://github.com/BurntSushi/rust-csv/issues/135#issuecomment-1058584727
@@ -30,25 +61,122 @@ fn bool_from_str<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
     D: Deserializer<'de>,
 {
-    match String::deserialize(deserializer)?.as_str() {
-        "true" | "True" => Ok(true),
-        "false" | "False" => Ok(false),
+    let value = String::deserialize(deserializer)?;
+    match value.trim() {
+        "true" | "True" | "1" => Ok(true),
+        "false" | "False" | "0" => Ok(false),
+        // an empty side is not guessed, `TradesTable::with_lenient_rows` can drop the row
         other => Err(de::Error::invalid_value(
             Unexpected::Str(other),
-            &"Must be truthy (true, True) or falsey (false, False)",
+            &"Must be truthy (true, True, 1) or falsey (false, False, 0)",
         )),
     }
 }
 
+struct F32Visitor;
+
+impl de::Visitor<'_> for F32Visitor {
+    type Value = f32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a float, possibly in scientific notation, or an empty field")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<f32, E> {
+        let v = v.trim();
+        if v.is_empty() {
+            return Ok(f32::NAN);
+        }
+        v.parse()
+            .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<f32, E> {
+        Ok(v as f32)
+    }
+}
+
+/// Accepts 1.5 as well as 1.23E-4; an empty field becomes NaN for `EmptyFieldPolicy`
+fn f32_from_str<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(F32Visitor)
+}
+
+/// What to do with rows which have an empty price, qty or quote_qty
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyFieldPolicy {
+    /// Fail the file
+    #[default]
+    Error,
+    /// Replace the empty fields with 0
+    Zero,
+    /// Drop the row
+    SkipRow,
+}
+
+impl EmptyFieldPolicy {
+    fn apply(&self, mut row: Row) -> Result<Option<Row>> {
+        if !row.has_empty_fields() {
+            return Ok(Some(row));
+        }
+
+        match self {
+            EmptyFieldPolicy::Error => Err(DataError::InvalidData(format!(
+                "Row {} has an empty numeric field",
+                row.id
+            ))),
+            EmptyFieldPolicy::Zero => {
+                for field in [&mut row.price, &mut row.qty, &mut row.quote_qty] {
+                    if field.is_nan() {
+                        *field = 0.0;
+                    }
+                }
+                Ok(Some(row))
+            }
+            EmptyFieldPolicy::SkipRow => {
+                log::warn!("Skipping row {} with an empty numeric field", row.id);
+                Ok(None)
+            }
+        }
+    }
+
+    fn apply_to_stream<S>(self, rows: S) -> BoxStream<'static, Result<Row>>
+    where
+        S: Stream<Item = Result<Row>> + Send + 'static,
+    {
+        rows.try_filter_map(move |row| futures::future::ready(self.apply(row)))
+            .boxed()
+    }
+}
+
+/// Counts the rows dropped so far, e.g. the malformed ones of `File::records_lenient`
+#[derive(Debug, Clone, Default)]
+pub struct SkippedRows(Arc<AtomicU64>);
+
+impl SkippedRows {
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Row {
     /// Trade id
     pub id: u32,
     /// Execution price in DENOM
+    #[serde(deserialize_with = "f32_from_str")]
     pub price: f32,
     /// Trade quantity in BASE
+    #[serde(deserialize_with = "f32_from_str")]
     pub qty: f32,
     /// Notional value; price * qty
+    #[serde(deserialize_with = "f32_from_str")]
     pub quote_qty: f32,
     /// Trade time in unix epoch to ms
     pub time: u64,
@@ -60,78 +188,553 @@ pub struct Row {
     pub is_best_match: bool,
 }
 
-impl<'r> DeserializableFromCSV<'r> for Row {
-    fn into_deserialize_from_csv_reader<R: AsyncRead + Send + Unpin + 'r>(
-        reader: R,
-    ) -> csv_async::DeserializeRecordsIntoStream<'r, R, Self>
-    where
-        Self: Sized,
-    {
-        csv_async::AsyncReaderBuilder::new()
-            .has_headers(false)
-            .create_deserializer(reader)
-            .into_deserialize()
+impl Row {
+    fn has_empty_fields(&self) -> bool {
+        self.price.is_nan() || self.qty.is_nan() || self.quote_qty.is_nan()
+    }
+}
+
+impl DeserializableFromCSV<'_> for Row {}
+
+/// How long a download may go without receiving a byte, unless
+/// `binance.download_timeout_secs` says otherwise
+const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn default_download_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        // a missing config fails the download itself, with the actual error
+        config::Config::create()
+            .ok()
+            .and_then(|config| config.binance.download_timeout_secs)
+            .map_or(DEFAULT_DOWNLOAD_TIMEOUT, Duration::from_secs)
+    })
+}
+
+/// Runs `download` to completion unless `path` stops growing for `stall`. A slow or
+/// throttled download is never cut short as long as bytes keep arriving.
+async fn unless_stalled<T>(
+    download: impl Future<Output = Result<T>>,
+    path: &Path,
+    stall: Duration,
+    context: &str,
+) -> Result<T> {
+    let mut download = pin!(download);
+    let mut ticks = tokio::time::interval((stall / 4).max(Duration::from_millis(1)));
+    let mut len = 0;
+    let mut progressed_at = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            result = &mut download => return result,
+            _ = ticks.tick() => {
+                // a store may only create the file once it is connected
+                let current = fs::metadata(path).await.map_or(0, |m| m.len());
+                if current != len {
+                    len = current;
+                    progressed_at = tokio::time::Instant::now();
+                } else if progressed_at.elapsed() >= stall {
+                    return Err(DataError::Timeout {
+                        context: context.to_string(),
+                        after: stall,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Contents of a digest cache, see `File::cached_disk_digest`
+#[derive(Debug, Serialize, Deserialize)]
+struct DigestCache {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    digest: String,
+}
+
+impl DigestCache {
+    fn is_valid_for(&self, size: u64, modified: Duration) -> bool {
+        self.size == size
+            && self.modified_secs == modified.as_secs()
+            && self.modified_nanos == modified.subsec_nanos()
+    }
+}
+
+/// A `Row` as written by `File::to_jsonl`
+#[derive(Serialize)]
+struct JsonRow {
+    id: u32,
+    #[serde(serialize_with = "plain_f32")]
+    price: f32,
+    #[serde(serialize_with = "plain_f32")]
+    qty: f32,
+    #[serde(serialize_with = "plain_f32")]
+    quote_qty: f32,
+    time: u64,
+    is_buyer_maker: bool,
+}
+
+impl From<&Row> for JsonRow {
+    fn from(row: &Row) -> Self {
+        JsonRow {
+            id: row.id,
+            price: row.price,
+            qty: row.qty,
+            quote_qty: row.quote_qty,
+            time: row.time,
+            is_buyer_maker: row.is_buyer_maker,
+        }
+    }
+}
+
+/// Writes a float in plain decimal notation, e.g. 0.00000001 rather than the 1e-8 of
+/// serde_json, and NaN as null
+fn plain_f32<S: serde::Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+    let number = if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    };
+    RawValue::from_string(number)
+        .map_err(serde::ser::Error::custom)?
+        .serialize(serializer)
+}
+
+/// A row of an aggTrades file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AggTradeRow {
+    /// Aggregate trade id
+    pub agg_trade_id: u64,
+    /// Execution price in DENOM
+    #[serde(deserialize_with = "f32_from_str")]
+    pub price: f32,
+    /// Aggregated quantity in BASE
+    #[serde(deserialize_with = "f32_from_str")]
+    pub qty: f32,
+    /// Id of the first trade in the aggregate
+    pub first_trade_id: u64,
+    /// Id of the last trade in the aggregate
+    pub last_trade_id: u64,
+    /// Trade time in unix epoch to ms
+    pub transact_time: u64,
+    /// Is the buyer the maker in this trade ==> true is a short trade
+    #[serde(deserialize_with = "bool_from_str")]
+    pub is_buyer_maker: bool,
+    /// Was this the best price available on the exchange?
+    #[serde(skip)]
+    pub is_best_match: bool,
+}
+
+impl DeserializableFromCSV<'_> for AggTradeRow {}
+
+/// A row of a klines file, one OHLCV candle
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KLineRow {
+    /// Candle open time in unix epoch to ms
+    pub open_time: u64,
+    #[serde(deserialize_with = "f32_from_str")]
+    pub open: f32,
+    #[serde(deserialize_with = "f32_from_str")]
+    pub high: f32,
+    #[serde(deserialize_with = "f32_from_str")]
+    pub low: f32,
+    #[serde(deserialize_with = "f32_from_str")]
+    pub close: f32,
+    /// Volume in BASE
+    #[serde(deserialize_with = "f32_from_str")]
+    pub volume: f32,
+    /// Candle close time in unix epoch to ms
+    pub close_time: u64,
+    /// Volume in DENOM
+    #[serde(deserialize_with = "f32_from_str")]
+    pub quote_volume: f32,
+    /// Number of trades
+    pub count: u64,
+    #[serde(deserialize_with = "f32_from_str")]
+    pub taker_buy_volume: f32,
+    #[serde(deserialize_with = "f32_from_str")]
+    pub taker_buy_quote_volume: f32,
+}
+
+impl DeserializableFromCSV<'_> for KLineRow {}
+
+/// Reads the digest from a checksum object, formatted as `<digest>  <filename>`.
+/// Only the first line is read.
+async fn read_checksum<R: AsyncRead + Unpin>(reader: R) -> Result<String> {
+    let mut first_line = String::new();
+    BufReader::new(reader)
+        .read_line(&mut first_line)
+        .await
+        .map_err(|e| DataError::io("Could not read checksum", e))?;
+    first_line
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .ok_or_else(|| DataError::InvalidData("Checksum is empty".to_string()))
+}
+
+async fn open_zip(path: &Path) -> Result<ZipFileReader<BufReader<fs::File>>> {
+    let file = fs::File::open(path).await.map_err(|e| {
+        DataError::io(
+            format!("Could not open file: {}", path.to_string_lossy()),
+            e,
+        )
+    })?;
+    ZipFileReader::with_tokio(BufReader::new(file))
+        .await
+        .map_err(|source| DataError::Zip {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+/// Signature of the end of central directory record, which closes every zip
+const EOCD_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+/// Size of the end of central directory record without its trailing comment
+const EOCD_SIZE: usize = 22;
+
+/// Cheap check that the zip at `path` is whole, by finding its end of central directory
+/// record and checking the central directory it points at fits in the file. Truncated
+/// downloads otherwise fail deep in async_zip with an opaque error.
+async fn validate_zip(path: &Path) -> Result<()> {
+    let corrupt = |reason: String| DataError::CorruptZip {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let context = || format!("Could not read file: {}", path.to_string_lossy());
+    let mut file = fs::File::open(path)
+        .await
+        .map_err(|e| DataError::io(context(), e))?;
+    let len = file
+        .metadata()
+        .await
+        .map_err(|e| DataError::io(context(), e))?
+        .len();
+    if len < EOCD_SIZE as u64 {
+        return Err(corrupt(format!("only {} bytes long", len)));
+    }
+
+    // The record is followed by a comment of at most u16::MAX bytes
+    let tail_len = len.min((EOCD_SIZE + u16::MAX as usize) as u64);
+    let mut tail = vec![0; tail_len as usize];
+    file.seek(SeekFrom::Start(len - tail_len))
+        .await
+        .map_err(|e| DataError::io(context(), e))?;
+    file.read_exact(&mut tail)
+        .await
+        .map_err(|e| DataError::io(context(), e))?;
+
+    let Some(eocd) = (0..=tail.len() - EOCD_SIZE)
+        .rev()
+        .find(|&i| tail[i..i + 4] == EOCD_SIGNATURE)
+    else {
+        return Err(corrupt(
+            "no end of central directory record, the download is likely truncated".to_string(),
+        ));
+    };
+    let record = &tail[eocd..eocd + EOCD_SIZE];
+    let entries = u16::from_le_bytes([record[10], record[11]]);
+    let cd_size = u32::from_le_bytes([record[12], record[13], record[14], record[15]]);
+    let cd_offset = u32::from_le_bytes([record[16], record[17], record[18], record[19]]);
+    if entries == 0 {
+        return Err(corrupt("the archive has no entries".to_string()));
+    }
+    // Zip64 archives keep the real values in another record, leave those to async_zip
+    let zip64 = entries == u16::MAX || cd_size == u32::MAX || cd_offset == u32::MAX;
+    let eocd_offset = len - tail_len + eocd as u64;
+    if !zip64 && cd_offset as u64 + cd_size as u64 > eocd_offset {
+        return Err(corrupt(format!(
+            "the central directory of {} entries runs past the end of the file",
+            entries
+        )));
+    }
+    Ok(())
+}
+
+/// Decodes a gzip compressed CSV, as some mirrors store `.csv.gz` instead of zips
+async fn open_gzip(path: &Path) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let file = fs::File::open(path).await.map_err(|e| {
+        DataError::io(
+            format!("Could not open file: {}", path.to_string_lossy()),
+            e,
+        )
+    })?;
+    let mut decoder = GzipDecoder::new(BufReader::new(file));
+    decoder.multiple_members(true);
+    Ok(Box::new(decoder))
+}
+
+type CsvReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Lines with anything but line breaks on them, the last one with or without a line break
+async fn count_lines(mut reader: impl AsyncRead + Unpin) -> std::io::Result<u64> {
+    let mut buffer = [0; 8192];
+    let (mut lines, mut in_line) = (0, false);
+    loop {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            break;
+        }
+        for byte in &buffer[..count] {
+            match byte {
+                b'\n' => {
+                    lines += u64::from(in_line);
+                    in_line = false;
+                }
+                b'\r' => {}
+                _ => in_line = true,
+            }
+        }
     }
+    Ok(lines + u64::from(in_line))
+}
+
+/// Drops the header row which newer Binance files start with, e.g. `id,price,qty,...`.
+/// Every data row starts with a number, an id or a timestamp, so a first line starting
+/// with a letter is taken for the header
+async fn skip_header<R>(reader: R) -> Result<CsvReader>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let read_error = |e| DataError::io("Could not read the first line of a CSV", e);
+    let mut reader = BufReader::new(reader);
+    let first = reader.fill_buf().await.map_err(read_error)?;
+    let first = first.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(first);
+    if first.first().is_some_and(u8::is_ascii_alphabetic) {
+        let mut header = Vec::new();
+        reader
+            .read_until(b'\n', &mut header)
+            .await
+            .map_err(read_error)?;
+        log::debug!(
+            "Skipped CSV header: {}",
+            String::from_utf8_lossy(&header).trim()
+        );
+    }
+    Ok(Box::new(reader))
+}
+
+/// Reads the `index`th entry of the zip at `path`. Every entry gets its own file handle, as
+/// reading an entry consumes the zip reader
+async fn open_entry(path: Arc<Path>, index: usize) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let entry = open_zip(&path)
+        .await?
+        .into_entry(index)
+        .await
+        .map_err(|source| DataError::Zip {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    Ok(Box::new(entry.compat()))
+}
+
+/// Where `object_key` is mirrored under `data_dir`: data/spot/... ==> <data_dir>/binance/spot/...
+fn local_path(data_dir: &str, object_key: &str) -> Result<PathBuf> {
+    let path =
+        Path::new(data_dir.trim_end_matches('/')).join(object_key.replace("data/", "binance/"));
+    let path = shellexpand::full(path.to_str().unwrap())
+        .map_err(|e| DataError::Config(format!("Failed to expand path: {}", e)))?;
+    Ok(Path::new(path.as_ref()).to_path_buf())
 }
 
 #[derive(Debug, Clone)]
 pub struct File {
-    checksum_key: Arc<str>,
+    /// None when no checksum is published, the download is not verified then
+    checksum_key: Option<Arc<str>>,
+    checksum_algo: ChecksumAlgo,
     object_key: Arc<str>,
     pub pair: Arc<str>,
     pub path: Arc<Path>,
+    /// Size of the S3 object in bytes, 0 when unknown
+    pub size: u64,
+    /// When the S3 object was last modified, None when unknown
+    pub last_modified: Option<DateTime<Utc>>,
+    /// Where the file is downloaded from, the bucket of the config when unset
+    store: Option<Arc<dyn ObjectStore>>,
+    /// How long a download may go without progress, `binance.download_timeout_secs` when unset
+    download_timeout: Option<Duration>,
+}
+
+// A file is identified by the object it mirrors
+impl PartialEq for File {
+    fn eq(&self, other: &Self) -> bool {
+        self.object_key == other.object_key
+    }
+}
+
+impl Eq for File {}
+
+impl Hash for File {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.object_key.hash(state);
+    }
 }
 
 impl File {
+    /// A file mirrored under the data dir of the config
     pub fn new(pair: &str, object_key: &str, checksum_key: &str) -> Result<Self> {
-        let config = config::Config::create();
-        let data_dir = Path::new(config.data.dir.trim_end_matches('/'));
-
-        let path = data_dir.join(object_key.replace("data/", "binance/"));
-        let path = shellexpand::full(path.to_str().unwrap())
-            .map_err(|e| anyhow!("Failed to expand path: {}", e))?;
-        let path = Path::new(path.as_ref()).to_path_buf();
+        let config = config::Config::create().map_err(|e| DataError::Config(format!("{:#}", e)))?;
+        let path = local_path(&config.data.dir, object_key)?;
 
         Ok(File {
             object_key: Arc::from(object_key),
-            checksum_key: Arc::from(checksum_key),
+            checksum_key: Some(Arc::from(checksum_key)),
+            checksum_algo: ChecksumAlgo::default(),
             pair: Arc::from(pair),
             path: Arc::from(path),
+            size: 0,
+            last_modified: None,
+            store: None,
+            download_timeout: None,
         })
     }
 
-    async fn is_downloaded(&self) -> Result<bool> {
-        let exists = fs::try_exists(&self.path).await.with_context(|| {
-            format!(
-                "Could not check file exists: {}",
-                &self.path.to_string_lossy()
+    pub fn with_object_store(mut self, store: Arc<dyn ObjectStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_download_timeout(mut self, timeout: Duration) -> Self {
+        self.download_timeout = Some(timeout);
+        self
+    }
+
+    /// Mirrors the object under `data_dir` instead of the data dir of the config
+    pub fn with_data_dir(mut self, data_dir: &Path) -> Result<Self> {
+        self.path = Arc::from(local_path(&data_dir.to_string_lossy(), &self.object_key)?);
+        Ok(self)
+    }
+
+    fn store(&self) -> Result<Arc<dyn ObjectStore>> {
+        match &self.store {
+            Some(store) => Ok(Arc::clone(store)),
+            None => default_store(),
+        }
+    }
+
+    /// Hash published in the checksum object, SHA-256 by default
+    pub fn with_checksum_algo(mut self, algo: ChecksumAlgo) -> Self {
+        self.checksum_algo = algo;
+        self
+    }
+
+    /// Object holding the published checksum of the file
+    pub fn with_checksum_key(mut self, checksum_key: &str) -> Self {
+        self.checksum_key = Some(Arc::from(checksum_key));
+        self
+    }
+
+    /// Downloads the file without verifying it, for objects with no published checksum
+    pub fn without_checksum(mut self) -> Self {
+        self.checksum_key = None;
+        self
+    }
+
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_last_modified(mut self, last_modified: Option<DateTime<Utc>>) -> Self {
+        self.last_modified = last_modified;
+        self
+    }
+
+    /// A file that already exists on disk and has no S3 counterpart. `download` is a
+    /// no-op for it as long as the file exists.
+    pub fn from_path(pair: &str, path: &Path) -> Self {
+        File {
+            object_key: Arc::from(path.to_string_lossy().as_ref()),
+            checksum_key: None,
+            checksum_algo: ChecksumAlgo::default(),
+            pair: Arc::from(pair),
+            path: Arc::from(path),
+            size: 0,
+            last_modified: None,
+            store: None,
+            download_timeout: None,
+        }
+    }
+
+    /// Days covered by the file, parsed from its name: `BTCUSDT-trades-2023-01.zip` covers
+    /// January 2023 and `BTCUSDT-trades-2023-01-15.zip` only that day. Both bounds inclusive.
+    pub fn period(&self) -> Option<(NaiveDate, NaiveDate)> {
+        let name = self.path.file_name()?.to_str()?;
+        if let Some(date) = Cadence::Daily.parse_date(name) {
+            return Some((date, date));
+        }
+        let start = Cadence::Monthly.parse_date(name)?;
+        let end = start.checked_add_months(Months::new(1))?.pred_opt()?;
+        Some((start, end))
+    }
+
+    pub async fn is_downloaded(&self) -> Result<bool> {
+        let exists = fs::try_exists(&self.path).await.map_err(|e| {
+            DataError::io(
+                format!(
+                    "Could not check file exists: {}",
+                    &self.path.to_string_lossy()
+                ),
+                e,
             )
         })?;
-        // TODO: We need a mechanism to verify that this file is not being downloaded
-        // by some other process / thread at this moment in time
-        // - check checksum
-        // - name files being downloaded as .download like in Chrome
+        // downloads in progress live under `download_path` until they are verified
         Ok(exists)
     }
 
+    /// Where the file is written while it is being downloaded
+    fn download_path(&self) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(".download");
+        PathBuf::from(path)
+    }
+
     pub async fn download(&self) -> Result<&Self> {
         if self.is_downloaded().await? {
             return Ok(self);
         }
 
-        // TODO: download into /tmp first and move to prevent unfinished downloads
-        let bucket = Bucket::new()?;
-        bucket
-            .get_object_to_file(&self.object_key, self.path.deref())
-            .await?;
+        // Written to a temp file and only renamed into place once verified, so `path`
+        // never holds a partial download. A stale temp file from a crash is overwritten.
+        let download_path = self.download_path();
+        let bucket = self.store()?;
+        let timeout = self
+            .download_timeout
+            .unwrap_or_else(default_download_timeout);
+        let context = format!("Downloading {}", self.object_key);
+        let digest = RetryPolicy::global()
+            .run(&context, || async {
+                let download = bucket.get_object_to_file(
+                    &self.object_key,
+                    &download_path,
+                    true,
+                    self.checksum_algo,
+                );
+                // a hung connection would hold its download slot forever
+                unless_stalled(download, &download_path, timeout, &context).await
+            })
+            .await;
+        let digest = match digest {
+            Ok(digest) => digest,
+            Err(e) => {
+                let _ = fs::remove_file(&download_path).await;
+                return Err(e);
+            }
+        };
 
-        if !self.checksum_matches().await? {
-            fs::remove_file(&self.path).await?;
-            return Err(anyhow!(
-                "Checksum does not match, removing file: {}",
-                self.path.to_string_lossy()
-            ));
+        self.check_size(&download_path).await?;
+
+        // the digest was computed while writing, so the file is not read a second time
+        let expected = match &self.checksum_key {
+            Some(_) => Some(self.bucket_checksum().await?),
+            None => {
+                log::warn!("No checksum for {}, not verifying it", self.object_key);
+                None
+            }
         };
+        self.move_into_place(&download_path, expected.as_deref(), digest)
+            .await?;
+        metrics::file_downloaded(self.size);
 
         log::debug!(
             "Downloaded: {} -> {}",
@@ -142,63 +745,925 @@ impl File {
         Ok(self)
     }
 
-    pub async fn records<'r>(
+    /// Removes the download if its size is not the size of the object. Unknown sizes are
+    /// not checked
+    async fn check_size(&self, download_path: &Path) -> Result<()> {
+        if self.size == 0 {
+            return Ok(());
+        }
+        let actual = fs::metadata(download_path)
+            .await
+            .map_err(|e| {
+                DataError::io(
+                    format!("Could not read file: {}", download_path.to_string_lossy()),
+                    e,
+                )
+            })?
+            .len();
+        if actual == self.size {
+            return Ok(());
+        }
+        log::warn!(
+            "Size does not match, removing file: {}",
+            download_path.to_string_lossy()
+        );
+        let _ = fs::remove_file(download_path).await;
+        Err(DataError::SizeMismatch {
+            path: self.path.to_path_buf(),
+            expected: self.size,
+            actual,
+        })
+    }
+
+    /// Renames the download to `path` if `digest` matches `expected`, removes it otherwise.
+    /// Without `expected` the download is moved unverified
+    async fn move_into_place(
+        &self,
+        download_path: &Path,
+        expected: Option<&str>,
+        digest: String,
+    ) -> Result<()> {
+        if let Some(expected) = expected.filter(|e| !e.eq_ignore_ascii_case(&digest)) {
+            log::warn!(
+                "Checksum does not match, removing file: {}",
+                download_path.to_string_lossy()
+            );
+            let _ = fs::remove_file(download_path).await;
+            return Err(DataError::ChecksumMismatch {
+                path: self.path.to_path_buf(),
+                expected: expected.to_string(),
+                actual: digest,
+            });
+        };
+        fs::rename(download_path, &self.path).await.map_err(|e| {
+            DataError::io(
+                format!(
+                    "Could not move {} into place",
+                    download_path.to_string_lossy()
+                ),
+                e,
+            )
+        })?;
+        self.write_digest_cache(&digest).await;
+        Ok(())
+    }
+
+    pub async fn records(&self) -> Result<BoxStream<'static, Result<Row>>> {
+        self.records_with_policy(EmptyFieldPolicy::default()).await
+    }
+
+    pub async fn records_with_policy(
         &self,
-    ) -> Result<DeserializeRecordsIntoStream<'r, Box<dyn AsyncRead + Send + Unpin>, Row>> {
-        let file = fs::File::open(&self.path).await?;
-        let file_reader = BufReader::new(file);
-        let zip = ZipFileReader::with_tokio(file_reader).await?;
-        let index = match zip.file().entries().len() {
-            1 => 0,
-            num => {
-                return Err(anyhow!(
-                    "The zip file has {} files, expected 1. {}",
-                    num,
-                    self.path.to_string_lossy()
-                ))
+        policy: EmptyFieldPolicy,
+    ) -> Result<BoxStream<'static, Result<Row>>> {
+        Ok(policy.apply_to_stream(self.rows::<Row>().await?))
+    }
+
+    /// Like `records_with_policy`, but rows which fail to deserialize are logged, counted
+    /// and dropped instead of failing the file
+    pub async fn records_lenient(
+        &self,
+        policy: EmptyFieldPolicy,
+    ) -> Result<(BoxStream<'static, Result<Row>>, SkippedRows)> {
+        let skipped = SkippedRows::default();
+        let counter = skipped.clone();
+        let path = Arc::clone(&self.path);
+        let rows = self.rows::<Row>().await?.filter_map(move |row| {
+            let row = match row {
+                // an I/O error would repeat on every following row
+                Err(DataError::Csv(e)) if !e.is_io_error() => {
+                    log::warn!("Skipping malformed row of {}: {}", path.display(), e);
+                    counter.increment();
+                    None
+                }
+                row => Some(row),
+            };
+            futures::future::ready(row)
+        });
+        Ok((policy.apply_to_stream(rows), skipped))
+    }
+
+    /// Writes the records as JSON Lines, one object per trade. Returns the number of rows
+    pub async fn to_jsonl<W: AsyncWrite + Unpin>(&self, writer: W) -> Result<u64> {
+        let write_error = |e| DataError::io("Could not write JSON Lines", e);
+        let mut writer = BufWriter::new(writer);
+        let mut records = self.records().await?;
+        let mut rows = 0;
+        while let Some(row) = records.next().await {
+            let mut line = serde_json::to_vec(&JsonRow::from(&row?)).map_err(|e| {
+                DataError::InvalidData(format!("Could not serialize row to JSON: {}", e))
+            })?;
+            line.push(b'\n');
+            writer.write_all(&line).await.map_err(write_error)?;
+            rows += 1;
+        }
+        writer.flush().await.map_err(write_error)?;
+        Ok(rows)
+    }
+
+    /// Rows of any of the CSV layouts, e.g. `AggTradeRow` or `KLineRow`. The CSVs of a zip
+    /// with several of them are read one after the other, in entry order. A `.gz` file is
+    /// read as a single gzip compressed CSV.
+    pub async fn rows<T>(&self) -> Result<BoxStream<'static, Result<T>>>
+    where
+        T: DeserializableFromCSV<'static> + Send,
+    {
+        Ok(self
+            .csv_readers()
+            .await?
+            .map_ok(|reader| T::into_deserialize_from_csv_reader(reader).map_err(DataError::from))
+            .try_flatten()
+            .boxed())
+    }
+
+    /// Number of rows, counted from the lines of the CSVs without deserializing them, e.g.
+    /// before indexing. Headers and blank lines, such as a trailing one, are not rows
+    pub async fn count_rows(&self) -> Result<u64> {
+        let mut readers = self.csv_readers().await?;
+        let mut rows = 0;
+        while let Some(reader) = readers.try_next().await? {
+            rows += count_lines(reader).await.map_err(|e| {
+                DataError::io(
+                    format!("Could not read file: {}", self.path.to_string_lossy()),
+                    e,
+                )
+            })?;
+        }
+        Ok(rows)
+    }
+
+    /// The CSVs of the file without their header, see `rows`
+    async fn csv_readers(&self) -> Result<BoxStream<'static, Result<CsvReader>>> {
+        if self.is_gzip() {
+            let reader = skip_header(open_gzip(&self.path).await?).await?;
+            return Ok(stream::once(futures::future::ready(Ok(reader))).boxed());
+        }
+
+        let path = Arc::clone(&self.path);
+        let entries = self.csv_entries().await?;
+        Ok(stream::iter(entries)
+            .then(move |index| {
+                let path = Arc::clone(&path);
+                async move { skip_header(open_entry(path, index).await?).await }
+            })
+            .boxed())
+    }
+
+    fn is_gzip(&self) -> bool {
+        self.path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+    }
+
+    /// Indices of the CSV entries of the zip. Anything else, e.g. a `_SUCCESS` marker, is
+    /// skipped
+    async fn csv_entries(&self) -> Result<Vec<usize>> {
+        validate_zip(&self.path).await?;
+        let zip = open_zip(&self.path).await?;
+        let entries: Vec<usize> = zip
+            .file()
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                entry
+                    .filename()
+                    .as_str()
+                    .is_ok_and(|name| name.to_ascii_lowercase().ends_with(".csv"))
+            })
+            .map(|(index, _)| index)
+            .collect();
+        if entries.is_empty() {
+            return Err(DataError::InvalidData(format!(
+                "The zip file has no CSV files. {}",
+                self.path.to_string_lossy()
+            )));
+        }
+        Ok(entries)
+    }
+
+    /// Hashes the file on disk with the checksum algorithm and compares it with the
+    /// published checksum
+    pub async fn checksum_matches(&self) -> Result<bool> {
+        let bucket_digest = self.bucket_checksum().await?;
+        let disk_digest = self.cached_disk_digest().await?;
+        Ok(bucket_digest.eq_ignore_ascii_case(&disk_digest))
+    }
+
+    /// Sidecar holding the last digest of the file on disk, e.g. BTCUSDT-trades-2024-01.zip.sha256
+    fn digest_cache_path(&self) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(".");
+        path.push(self.checksum_algo.name());
+        PathBuf::from(path)
+    }
+
+    /// Size and modification time of the file on disk, which a cached digest is valid for
+    async fn disk_stamp(&self) -> Result<(u64, Duration)> {
+        let metadata = fs::metadata(&self.path).await.map_err(|e| {
+            DataError::io(
+                format!("Could not read file: {}", self.path.to_string_lossy()),
+                e,
+            )
+        })?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Ok((metadata.len(), modified))
+    }
+
+    /// The digest of the sidecar cache while size and modification time of the file are
+    /// unchanged, otherwise the file is hashed and the cache rewritten
+    async fn cached_disk_digest(&self) -> Result<String> {
+        let (size, modified) = self.disk_stamp().await?;
+        let cached = fs::read(self.digest_cache_path())
+            .await
+            .ok()
+            .and_then(|cache| serde_json::from_slice::<DigestCache>(&cache).ok());
+        if let Some(cache) = cached.filter(|cache| cache.is_valid_for(size, modified)) {
+            log::debug!("Cached digest of {}", self.path.to_string_lossy());
+            return Ok(cache.digest);
+        }
+
+        let digest = self.disk_digest().await?;
+        self.write_digest_cache(&digest).await;
+        Ok(digest)
+    }
+
+    /// Failing to write the cache only costs a hash the next time round
+    async fn write_digest_cache(&self, digest: &str) {
+        let cache_path = self.digest_cache_path();
+        let written = match self.disk_stamp().await {
+            Ok((size, modified)) => {
+                let cache = DigestCache {
+                    size,
+                    modified_secs: modified.as_secs(),
+                    modified_nanos: modified.subsec_nanos(),
+                    digest: digest.to_string(),
+                };
+                let json = serde_json::to_vec(&cache).expect("digest cache serializes");
+                fs::write(&cache_path, json)
+                    .await
+                    .map_err(|e| DataError::io("Could not write digest cache", e))
             }
+            Err(e) => Err(e),
         };
-        let reader =
-            Box::new(zip.into_entry(index).await?.compat()) as Box<dyn AsyncRead + Unpin + Send>;
-        Ok(Row::into_deserialize_from_csv_reader(reader))
+        if let Err(e) = written {
+            log::debug!("{}: {}", cache_path.to_string_lossy(), e);
+        }
     }
 
-    async fn checksum_matches(&self) -> Result<bool> {
-        let bucket = Bucket::new()?;
-        let bucket_sha_string = bucket.read_object(&self.checksum_key).await?;
-        let bucket_sha = bucket_sha_string.split(' ').next().unwrap();
-        let disk_sha = self.sha256_digest().await?;
-        Ok(bucket_sha.eq_ignore_ascii_case(&disk_sha))
+    async fn bucket_checksum(&self) -> Result<String> {
+        let checksum_key = self
+            .checksum_key
+            .as_deref()
+            .ok_or_else(|| DataError::MissingObject(format!("checksum of {}", self.object_key)))?;
+        let bucket = self.store()?;
+        RetryPolicy::global()
+            .run(&format!("Reading {}", checksum_key), || async {
+                let reader = bucket.read_object_stream(checksum_key).await?;
+                read_checksum(reader).await
+            })
+            .await
     }
 
     // TODO: Refactor to utilities
-    async fn sha256_digest(&self) -> Result<String> {
-        let input = fs::File::open(&self.path).await?;
+    async fn disk_digest(&self) -> Result<String> {
+        let read_error = |e| {
+            DataError::io(
+                format!("Could not read file: {}", self.path.to_string_lossy()),
+                e,
+            )
+        };
+        let input = fs::File::open(&self.path).await.map_err(read_error)?;
         let mut reader = BufReader::new(input);
 
-        let digest = {
-            let mut hasher = Sha256::new();
-            let mut buffer = [0; 8192];
-            loop {
-                let count = reader.read(&mut buffer).await?;
-                if count == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..count]);
+        let mut hasher = self.checksum_algo.hasher();
+        let mut buffer = [0; 8192];
+        loop {
+            let count = reader.read(&mut buffer).await.map_err(read_error)?;
+            if count == 0 {
+                break;
             }
-            hasher.finalize()
-        };
-        Ok(format!("{:X}", digest))
+            hasher.update(&buffer[..count]);
+        }
+        Ok(hasher.finalize())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::binance::pair::Pair;
     use crate::test_utils;
+    use futures::future::BoxFuture;
+    use s3::serde_types::Object;
+    use std::sync::atomic::AtomicU64;
+    use tokio::io::AsyncRead;
 
     #[test]
     fn file_is_normal() {
         test_utils::is_normal::<File>();
     }
+
+    async fn parse(csv: &str, policy: EmptyFieldPolicy) -> Result<Vec<Row>> {
+        let reader = std::io::Cursor::new(csv.as_bytes().to_vec());
+        let rows = Row::into_deserialize_from_csv_reader(reader).map_err(DataError::from);
+        policy.apply_to_stream(rows).try_collect().await
+    }
+
+    #[tokio::test]
+    async fn test_read_checksum() {
+        let checksum = b"ABC123  BTCUSDC-trades-2024-01.zip\nsomething else\n";
+        assert_eq!(read_checksum(&checksum[..]).await.unwrap(), "ABC123");
+        assert!(read_checksum(&b""[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scientific_notation() {
+        let rows = parse(
+            "1,1.23E-4,100,0.0123,1704067200000,true,true\n",
+            EmptyFieldPolicy::Error,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rows[0].price, 1.23e-4);
+        assert_eq!(rows[0].qty, 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_bool_from_str() {
+        let rows = parse(
+            "1,10.5,1.0,10.5,1704067200000,1,true\n\
+             2,10.5,1.0,10.5,1704067200001,0,true\n\
+             3,10.5,1.0,10.5,1704067200002, True ,true\n",
+            EmptyFieldPolicy::Error,
+        )
+        .await
+        .unwrap();
+        let sides: Vec<bool> = rows.iter().map(|row| row.is_buyer_maker).collect();
+        assert_eq!(sides, vec![true, false, true]);
+
+        for side in ["2", "yes", ""] {
+            let csv = format!("1,10.5,1.0,10.5,1704067200000,{side},true\n");
+            assert!(parse(&csv, EmptyFieldPolicy::Error).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_field_policy() {
+        const CSV: &str = "1,10.5,,1.0,1704067200000,true,true\n\
+                           2,10.5,2.0,21.0,1704067200001,false,true\n";
+
+        assert!(parse(CSV, EmptyFieldPolicy::Error).await.is_err());
+
+        let rows = parse(CSV, EmptyFieldPolicy::Zero).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].qty, 0.0);
+
+        let rows = parse(CSV, EmptyFieldPolicy::SkipRow).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, 2);
+    }
+
+    #[tokio::test]
+    async fn test_agg_trade_and_kline_rows() {
+        let csv = "26129,0.01633102,4.70443515,27781,27781,1498793709153,true,true\n";
+        let rows: Vec<AggTradeRow> = AggTradeRow::into_deserialize_from_csv_reader(csv.as_bytes())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows[0].agg_trade_id, 26129);
+        assert_eq!(rows[0].last_trade_id, 27781);
+        assert!(rows[0].is_buyer_maker);
+
+        let csv = "1601510340000,4.15070000,4.15870000,4.15060000,4.15540000,539.23000000,\
+                   1601510399999,2240.39,13,401.82,1669.2,0\n";
+        let rows: Vec<KLineRow> = KLineRow::into_deserialize_from_csv_reader(csv.as_bytes())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows[0].open_time, 1601510340000);
+        assert_eq!(rows[0].close_time, 1601510399999);
+        assert_eq!(rows[0].count, 13);
+        assert_eq!(rows[0].high, 4.1587);
+    }
+
+    #[test]
+    fn test_period() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let file = |name: &str| File::from_path("BTCUSDT", Path::new(name));
+
+        assert_eq!(
+            file("BTCUSDT-trades-2023-02.zip").period(),
+            Some((date("2023-02-01"), date("2023-02-28")))
+        );
+        assert_eq!(
+            file("BTCUSDT-trades-2023-12-31.zip").period(),
+            Some((date("2023-12-31"), date("2023-12-31")))
+        );
+        assert_eq!(
+            file("BTCUSDT-1m-2024-01.zip").period(),
+            Some((date("2024-01-01"), date("2024-01-31")))
+        );
+        assert_eq!(
+            file("BTCUSDT-trades-2023-02.csv.gz").period(),
+            Some((date("2023-02-01"), date("2023-02-28")))
+        );
+        assert_eq!(file("BTCUSDT-trades.zip").period(), None);
+    }
+
+    #[tokio::test]
+    async fn test_leftover_download_is_not_downloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::from_path("BTCUSDT", &dir.path().join("BTCUSDT-trades-2024-01.zip"));
+        assert_eq!(
+            file.download_path(),
+            dir.path().join("BTCUSDT-trades-2024-01.zip.download")
+        );
+
+        // an interrupted download leaves only the temp file behind
+        std::fs::write(file.download_path(), b"truncated").unwrap();
+        assert!(!file.is_downloaded().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::from_path("BTCUSDT", &dir.path().join("BTCUSDT-trades-2024-01.zip"));
+        std::fs::write(file.download_path(), b"corrupt").unwrap();
+
+        let err = file
+            .move_into_place(&file.download_path(), Some("ABC123"), "DEF456".to_string())
+            .await
+            .unwrap_err();
+        match err {
+            DataError::ChecksumMismatch {
+                expected, actual, ..
+            } => {
+                assert_eq!(expected, "ABC123");
+                assert_eq!(actual, "DEF456");
+            }
+            e => panic!("Expected a checksum mismatch, got: {}", e),
+        }
+        assert!(!file.download_path().exists());
+        assert!(!file.is_downloaded().await.unwrap());
+
+        std::fs::write(file.download_path(), b"ok").unwrap();
+        file.move_into_place(&file.download_path(), Some("abc123"), "ABC123".to_string())
+            .await
+            .unwrap();
+        assert!(file.is_downloaded().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_multi_file_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        test_utils::write_zip(
+            &path,
+            &[
+                (
+                    "BTCUSDT-trades-2024-01-a.csv",
+                    "1,10.5,1.0,10.5,1704067200000,true,true\n",
+                ),
+                ("_SUCCESS", ""),
+                (
+                    "BTCUSDT-trades-2024-01-b.csv",
+                    "2,10.5,2.0,21.0,1704067200001,false,true\n\
+                     3,10.5,3.0,31.5,1704067200002,false,true\n",
+                ),
+            ],
+        )
+        .await;
+
+        let rows: Vec<Row> = File::from_path("BTCUSDT", &path)
+            .records()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let ids: Vec<u32> = rows.iter().map(|row| row.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_count_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        test_utils::write_zip(
+            &path,
+            &[
+                (
+                    "BTCUSDT-trades-2024-01-a.csv",
+                    "id,price,qty,quote_qty,time,is_buyer_maker,is_best_match\r\n\
+                     1,10.5,1.0,10.5,1704067200000,true,true\r\n\
+                     2,10.5,2.0,21.0,1704067200001,false,true\r\n",
+                ),
+                // no trailing newline
+                (
+                    "BTCUSDT-trades-2024-01-b.csv",
+                    "3,10.5,3.0,31.5,1704067200002,false,true\n\
+                     4,10.5,4.0,42.0,1704067200003,false,true",
+                ),
+                ("BTCUSDT-trades-2024-01-c.csv", ""),
+            ],
+        )
+        .await;
+
+        let file = File::from_path("BTCUSDT", &path);
+        let records: Vec<Row> = file.records().await.unwrap().try_collect().await.unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(file.count_rows().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_count_lines() {
+        let count = |csv: &'static [u8]| async move { count_lines(csv).await.unwrap() };
+        assert_eq!(count(b"").await, 0);
+        assert_eq!(count(b"1\n").await, 1);
+        assert_eq!(count(b"1\n2").await, 2);
+        assert_eq!(count(b"1\r\n2\r\n\r\n").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        test_utils::write_zip(
+            &path,
+            &[(
+                "BTCUSDT-trades-2024-01.csv",
+                "1,10.5,1.0,10.5,1704067200000,true,true\n",
+            )],
+        )
+        .await;
+
+        let file = File::from_path("BTCUSDT", &path);
+        assert!(file.records().await.is_ok());
+
+        let len = fs::metadata(&path).await.unwrap().len();
+        let zip = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        zip.set_len(len / 2).unwrap();
+
+        let err = file.records().await.err().unwrap();
+        assert!(matches!(err, DataError::CorruptZip { .. }));
+        let message = err.to_string();
+        assert!(message.contains("BTCUSDT-trades-2024-01.zip"));
+        assert!(message.contains("truncated"));
+        assert!(message.contains("downloaded again"));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_records() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncWriteExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.csv.gz");
+        let mut encoder = GzipEncoder::new(fs::File::create(&path).await.unwrap());
+        encoder
+            .write_all(
+                b"id,price,qty,quote_qty,time,is_buyer_maker,is_best_match\n\
+                  1,10.5,1.0,10.5,1704067200000,true,true\n\
+                  2,10.5,2.0,21.0,1704067200001,false,true\n",
+            )
+            .await
+            .unwrap();
+        encoder.shutdown().await.unwrap();
+
+        let rows: Vec<Row> = File::from_path("BTCUSDT", &path)
+            .records()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let ids: Vec<u32> = rows.iter().map(|row| row.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(rows[0].is_buyer_maker);
+    }
+
+    #[tokio::test]
+    async fn test_skip_header() {
+        let read = |csv: &'static [u8]| async move {
+            let reader = skip_header(csv).await.unwrap();
+            Row::into_deserialize_from_csv_reader(reader)
+                .map_err(DataError::from)
+                .try_collect::<Vec<Row>>()
+                .await
+        };
+        let headered = b"id,price,qty,quote_qty,time,is_buyer_maker,is_best_match\n\
+              1,10.5,1.0,10.5,1704067200000,true,true\n";
+        let rows = read(headered).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, 1);
+
+        // a byte order mark does not hide it
+        let rows = read(b"\xEF\xBB\xBFid,price\n2,10.5,1.0,10.5,1704067200000,false,true\n")
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+
+        // the first row of a headerless file is data
+        let rows = read(b"3,10.5,1.0,10.5,1704067200000,true,true\n")
+            .await
+            .unwrap();
+        assert_eq!(rows[0].id, 3);
+        assert!(read(b"").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_md5_checksum() {
+        use crate::data::binance::store::MemoryStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        let key = path.to_string_lossy().to_string();
+        let store = Arc::new(MemoryStore::new().with_object(&key, "zip").with_object(
+            &format!("{key}.md5"),
+            format!(
+                "{}  BTCUSDT-trades-2024-01.zip",
+                ChecksumAlgo::Md5.digest(b"other")
+            ),
+        ));
+        let file = File::from_path("BTCUSDT", &path)
+            .with_checksum_algo(ChecksumAlgo::Md5)
+            .with_checksum_key(&format!("{key}.md5"))
+            .with_object_store(store.clone());
+        assert!(matches!(
+            file.download().await,
+            Err(DataError::ChecksumMismatch { .. })
+        ));
+
+        store.insert(
+            &format!("{key}.md5"),
+            format!(
+                "{}  BTCUSDT-trades-2024-01.zip",
+                ChecksumAlgo::Md5.digest(b"zip")
+            ),
+        );
+        file.download().await.unwrap();
+        assert!(file.is_downloaded().await.unwrap());
+        assert!(file.checksum_matches().await.unwrap());
+        assert!(!file
+            .clone()
+            .with_checksum_algo(ChecksumAlgo::Sha256)
+            .checksum_matches()
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_digest_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        std::fs::write(&path, "zip").unwrap();
+        let file = File::from_path("BTCUSDT", &path);
+        let digest = ChecksumAlgo::Sha256.digest(b"zip");
+        assert_eq!(file.cached_disk_digest().await.unwrap(), digest);
+        assert!(file
+            .digest_cache_path()
+            .ends_with("BTCUSDT-trades-2024-01.zip.sha256"));
+
+        // a doctored cache is believed, so the file was not hashed again
+        let cache_path = file.digest_cache_path();
+        let mut cache: DigestCache =
+            serde_json::from_slice(&std::fs::read(&cache_path).unwrap()).unwrap();
+        cache.digest = "CACHED".to_string();
+        std::fs::write(&cache_path, serde_json::to_vec(&cache).unwrap()).unwrap();
+        assert_eq!(file.cached_disk_digest().await.unwrap(), "CACHED");
+
+        // a new modification time invalidates it
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(file.cached_disk_digest().await.unwrap(), digest);
+
+        // and so does a new size
+        std::fs::write(&path, "zip file").unwrap();
+        assert_eq!(
+            file.cached_disk_digest().await.unwrap(),
+            ChecksumAlgo::Sha256.digest(b"zip file")
+        );
+    }
+
+    /// Never answers a download
+    #[derive(Debug, Default)]
+    struct HangingStore {
+        attempts: AtomicU64,
+    }
+
+    impl ObjectStore for HangingStore {
+        fn list_pairs<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>> {
+            unimplemented!()
+        }
+
+        fn list_objects<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+            unimplemented!()
+        }
+
+        fn get_object_to_file<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a Path,
+            _: bool,
+            _: ChecksumAlgo,
+        ) -> BoxFuture<'a, Result<String>> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            Box::pin(futures::future::pending())
+        }
+
+        fn read_object_stream<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> BoxFuture<'a, Result<Box<dyn AsyncRead + Send + Unpin>>> {
+            unimplemented!()
+        }
+
+        fn read_object<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<String>> {
+            unimplemented!()
+        }
+
+        fn check<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<()>> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        let store = Arc::new(HangingStore::default());
+        let file = File::from_path("BTCUSDT", &path)
+            .without_checksum()
+            .with_object_store(store.clone())
+            .with_download_timeout(Duration::from_millis(20));
+
+        assert!(matches!(
+            file.download().await,
+            Err(DataError::Timeout { .. })
+        ));
+        // every attempt timed out and was retried
+        assert_eq!(
+            store.attempts.load(Ordering::SeqCst),
+            RetryPolicy::global().max_attempts as u64
+        );
+        assert!(!file.is_downloaded().await.unwrap());
+    }
+
+    /// Sends a download in small chunks, pausing between them
+    #[derive(Debug)]
+    struct TricklingStore {
+        chunks: usize,
+        pause: Duration,
+    }
+
+    impl ObjectStore for TricklingStore {
+        fn list_pairs<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>> {
+            unimplemented!()
+        }
+
+        fn list_objects<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+            unimplemented!()
+        }
+
+        fn get_object_to_file<'a>(
+            &'a self,
+            _: &'a str,
+            file_path: &'a Path,
+            _: bool,
+            algo: ChecksumAlgo,
+        ) -> BoxFuture<'a, Result<String>> {
+            Box::pin(async move {
+                let mut output = fs::File::create(file_path).await.unwrap();
+                let chunks = stream::iter(0..self.chunks).then(|_| async {
+                    tokio::time::sleep(self.pause).await;
+                    Ok(b"chunk".as_slice())
+                });
+                crate::data::binance::s3::write_hashed(chunks, &mut output, algo).await
+            })
+        }
+
+        fn read_object_stream<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> BoxFuture<'a, Result<Box<dyn AsyncRead + Send + Unpin>>> {
+            unimplemented!()
+        }
+
+        fn read_object<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<String>> {
+            unimplemented!()
+        }
+
+        fn check<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<()>> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_download_is_not_timed_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        let store = Arc::new(TricklingStore {
+            chunks: 10,
+            pause: Duration::from_millis(20),
+        });
+        // takes 4 times the timeout, but never goes the whole timeout without a chunk
+        let file = File::from_path("BTCUSDT", &path)
+            .without_checksum()
+            .with_object_store(store)
+            .with_download_timeout(Duration::from_millis(50));
+
+        file.download().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"chunk".repeat(10));
+    }
+
+    #[tokio::test]
+    async fn test_download_without_checksum() {
+        use crate::data::binance::store::MemoryStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        let store = MemoryStore::new().with_object(&path.to_string_lossy(), "zip");
+        let file = File::from_path("BTCUSDT", &path)
+            .without_checksum()
+            .with_object_store(Arc::new(store));
+
+        file.download().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"zip");
+        assert!(matches!(
+            file.checksum_matches().await,
+            Err(DataError::MissingObject(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_size_mismatch() {
+        use crate::data::binance::store::MemoryStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        let store = MemoryStore::new().with_object(&path.to_string_lossy(), "truncated");
+        let file = File::from_path("BTCUSDT", &path)
+            .without_checksum()
+            .with_size(100)
+            .with_object_store(Arc::new(store));
+
+        match file.download().await {
+            Err(DataError::SizeMismatch {
+                expected, actual, ..
+            }) => {
+                assert_eq!(expected, 100);
+                assert_eq!(actual, 9);
+            }
+            other => panic!("Expected a size mismatch, got: {:?}", other.map(|_| ())),
+        }
+        assert!(!file.download_path().exists());
+        assert!(!file.is_downloaded().await.unwrap());
+
+        file.clone().with_size(9).download().await.unwrap();
+        assert!(file.is_downloaded().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_to_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT-trades-2024-01.zip");
+        test_utils::write_zip(
+            &path,
+            &[(
+                "BTCUSDT-trades-2024-01.csv",
+                "1,10.5,1.0,10.5,1704067200000,true,true\n\
+                 2,0.00000001,2000000.0,0.02,1704067200001,false,true\n",
+            )],
+        )
+        .await;
+
+        let mut output = Vec::new();
+        let rows = File::from_path("BTCUSDT", &path)
+            .to_jsonl(&mut output)
+            .await
+            .unwrap();
+        assert_eq!(rows, 2);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"id":1,"price":10.5,"qty":1,"quote_qty":10.5,"time":1704067200000,"is_buyer_maker":true}"#
+        );
+        assert!(lines[1].contains(r#""price":0.00000001,"qty":2000000,"#));
+        for line in lines {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(value.get("is_best_match").is_none());
+        }
+    }
 }
@@ -1,13 +1,59 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
+use std::future::ready;
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
 use futures::stream::StreamExt;
 use futures::Stream;
 use s3::serde_types::Object;
+use tokio::sync::mpsc;
+use tracing::Instrument;
 
+use super::checksum::ChecksumOptions;
 use super::file::File;
+use crate::data::error::{DataError, ObjectSide, Result};
+use crate::utils::metrics;
+use crate::utils::progress as progress_bars;
+
+/// Sent by `download_with_progress` whenever a file is done, successfully or not. Drives
+/// the progress bars of downloads and of `verify_on_disk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub pair: Arc<str>,
+    pub path: Arc<Path>,
+    /// Size of this file
+    pub bytes: u64,
+    pub ok: bool,
+    /// Files done so far, including failed ones
+    pub completed: usize,
+    pub total: usize,
+    /// Size of the files downloaded successfully so far
+    pub downloaded_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Outcome of `verify_on_disk`, by file
+#[derive(Debug, Default, Clone)]
+pub struct VerifyReport {
+    /// On disk and matching the published checksum
+    pub ok: Vec<File>,
+    /// On disk but not matching, to be deleted and downloaded again
+    pub mismatch: Vec<File>,
+    /// Not on disk, so not verified
+    pub missing: Vec<File>,
+    /// Files which could not be verified, e.g. because the checksum could not be read
+    pub failed: Vec<(File, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatch.is_empty() && self.failed.is_empty()
+    }
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct FileCollection {
@@ -23,10 +69,15 @@ impl FileCollection {
         FileCollection { files }
     }
 
-    // Assumes objects are stored in pairs
-    // - name.zip
-    // - name.zip.CHECKSUM
-    pub fn from_objects(pair: &str, objects: Vec<Object>, checksum_suffix: &str) -> Result<Self> {
+    // Assumes objects are stored in pairs, whatever the extension of the data
+    // - name.zip (or name.csv.gz)
+    // - name.zip.CHECKSUM, the suffix and the algorithm coming from `checksum`
+    pub fn from_objects(
+        pair: &str,
+        objects: Vec<Object>,
+        checksum: &ChecksumOptions,
+    ) -> Result<Self> {
+        let checksum_suffix: &str = &checksum.suffix;
         // Create a HashMap to group objects by prefix
         let grouped_objects: HashMap<String, (Option<Object>, Option<Object>)> =
             objects.into_iter().fold(HashMap::new(), |mut map, object| {
@@ -50,48 +101,350 @@ impl FileCollection {
         // Create a FileCollection from the grouped objects
         let files = grouped_objects
             .into_iter()
-            .map(|(_, (object, checksum))| match (object, checksum) {
-                (Some(object), Some(checksum)) => File::new(pair, &object.key, &checksum.key),
-                _ => Err(anyhow!("Missing an object or a checksum")),
+            .map(|(prefix, (object, checksum_object))| {
+                // S3 reports RFC 3339, e.g. 2024-02-01T10:11:12.000Z
+                let last_modified = object
+                    .as_ref()
+                    .and_then(|object| DateTime::parse_from_rfc3339(&object.last_modified).ok())
+                    .map(|dt| dt.with_timezone(&Utc));
+                match (object, checksum_object) {
+                    (Some(object), Some(checksum_object)) => {
+                        Ok(File::new(pair, &object.key, &checksum_object.key)?
+                            .with_checksum_algo(checksum.algo)
+                            .with_size(object.size)
+                            .with_last_modified(last_modified))
+                    }
+                    (Some(object), None) if checksum.allow_missing => {
+                        log::warn!("No checksum for {}, it will not be verified", object.key);
+                        Ok(File::new(pair, &object.key, "")?
+                            .without_checksum()
+                            .with_size(object.size)
+                            .with_last_modified(last_modified))
+                    }
+                    (Some(object), None) => Err(DataError::UnpairedObject {
+                        missing: ObjectSide::Checksum,
+                        key: format!("{}{}", prefix, checksum_suffix),
+                        listed: object.key,
+                    }),
+                    (None, Some(checksum_object)) => Err(DataError::UnpairedObject {
+                        missing: ObjectSide::Data,
+                        key: prefix,
+                        listed: checksum_object.key,
+                    }),
+                    (None, None) => unreachable!("every prefix comes from a listed object"),
+                }
             })
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| anyhow!("Could not create FileCollection from objects: {}", e))?;
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(FileCollection::new(files))
     }
 
+    /// Collects every `.zip` and `.csv.gz` under `dir` as an already downloaded file, so it can be
+    /// indexed without S3. The pair is taken from the Binance file name,
+    /// e.g. BTCUSDC-trades-2024-01.zip ==> BTCUSDC
+    pub async fn from_local_dir(dir: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut dirs: Vec<PathBuf> = vec![dir.to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            let read_error = |e| {
+                DataError::io(
+                    format!("Could not read directory: {}", dir.to_string_lossy()),
+                    e,
+                )
+            };
+            let mut entries = tokio::fs::read_dir(&dir).await.map_err(read_error)?;
+            while let Some(entry) = entries.next_entry().await.map_err(read_error)? {
+                let path = entry.path();
+                if entry.file_type().await.map_err(read_error)?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                if file_name.ends_with(".zip") || file_name.ends_with(".csv.gz") {
+                    let pair = file_name.split('-').next().unwrap_or_default().to_string();
+                    files.push(File::from_path(&pair, &path));
+                }
+            }
+        }
+
+        log::info!(
+            "Found {} local files in: {}",
+            files.len(),
+            dir.to_string_lossy()
+        );
+        Ok(FileCollection::new(files))
+    }
+
     pub fn len(&self) -> usize {
         self.files.len()
     }
 
+    pub fn iter(&self) -> std::slice::Iter<'_, File> {
+        self.files.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Sum of the S3 object sizes of the files
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// Number of distinct pairs in the collection
+    pub fn num_pairs(&self) -> usize {
+        self.files
+            .iter()
+            .map(|f| &f.pair)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// First and last day covered by the files whose name has a date
+    pub fn period(&self) -> Option<(NaiveDate, NaiveDate)> {
+        self.files
+            .iter()
+            .filter_map(File::period)
+            .reduce(|(start, end), (file_start, file_end)| {
+                (start.min(file_start), end.max(file_end))
+            })
+    }
+
+    /// One line for logs, e.g. `12 files of 3 pairs, 4096 bytes, 2024-01-01 to 2024-04-30`,
+    /// unlike `Debug` which dumps every file
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} files of {} pairs, {} bytes",
+            self.len(),
+            self.num_pairs(),
+            self.total_bytes()
+        );
+        if let Some((start, end)) = self.period() {
+            summary.push_str(&format!(", {} to {}", start, end));
+        }
+        summary
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&File) -> bool) {
+        self.files.retain(f);
+    }
+
+    /// Orders the files largest first, so the long downloads start early
+    pub fn sort_largest_first(&mut self) {
+        self.files.sort_by_key(|f| std::cmp::Reverse(f.size));
+    }
+
+    /// Files that are not on disk yet
+    pub async fn not_downloaded(&self) -> Result<FileCollection> {
+        let mut files = Vec::new();
+        for file in &self.files {
+            if !file.is_downloaded().await? {
+                files.push(file.clone());
+            }
+        }
+        Ok(FileCollection::new(files))
+    }
+
+    /// Downloads the files `num_semaphore` at a time, moving the progress bars if they are
+    /// drawn
     pub fn download_stream(&self, num_semaphore: usize) -> impl Stream<Item = Result<File>> {
-        futures::stream::iter(self.files.clone())
+        self.download_results(num_semaphore)
+            .map(|(file, result)| result.map(|_| file))
+    }
+
+    /// Like `download_stream`, keeping the file of a failed download
+    pub(super) fn download_results(
+        &self,
+        num_semaphore: usize,
+    ) -> impl Stream<Item = (File, Result<()>)> {
+        self.progress_events(num_semaphore)
+            .map(|(event, file, result)| {
+                progress_bars::on_event(&event);
+                (file, result)
+            })
+    }
+
+    /// Like `download_stream`, sending a `DownloadProgress` for every finished file instead.
+    /// Progress is dropped once the receiver is gone, the downloads carry on.
+    pub fn download_with_progress(
+        &self,
+        num_semaphore: usize,
+        progress: mpsc::Sender<DownloadProgress>,
+    ) -> impl Stream<Item = Result<File>> {
+        self.progress_events(num_semaphore)
+            .then(move |(event, file, result)| {
+                let progress = progress.clone();
+                async move {
+                    let _ = progress.send(event).await;
+                    result.map(|_| file)
+                }
+            })
+    }
+
+    fn progress_events(
+        &self,
+        num_semaphore: usize,
+    ) -> impl Stream<Item = (DownloadProgress, File, Result<()>)> {
+        let total = self.len();
+        let total_bytes = self.total_bytes();
+        self.downloads(num_semaphore).enumerate().scan(
+            0,
+            move |downloaded_bytes, (i, (file, result))| {
+                if result.is_ok() {
+                    *downloaded_bytes += file.size;
+                }
+                let event = DownloadProgress {
+                    pair: file.pair.clone(),
+                    path: file.path.clone(),
+                    bytes: file.size,
+                    ok: result.is_ok(),
+                    completed: i + 1,
+                    total,
+                    downloaded_bytes: *downloaded_bytes,
+                    total_bytes,
+                };
+                ready(Some((event, file, result)))
+            },
+        )
+    }
+
+    /// Hashes the files already on disk and compares them with their published checksums,
+    /// `num_semaphore` files at a time. Nothing is downloaded
+    pub async fn verify_on_disk(&self, num_semaphore: usize) -> VerifyReport {
+        let total = self.len();
+        let total_bytes = self.total_bytes();
+        let mut results = futures::stream::iter(self.files.clone())
             .map(|file| async move {
-                match file.download().await {
-                    Ok(_) => Ok(file),
-                    Err(e) => {
-                        log::error!("Could not download file. {}", e);
-                        Err(anyhow::anyhow!("Failed to download file: {}", e))
-                    }
+                let _bar = progress_bars::DownloadBar::start(&file.path);
+                let result = match file.is_downloaded().await {
+                    Ok(false) => Ok(None),
+                    Ok(true) => file.checksum_matches().await.map(Some),
+                    Err(e) => Err(e),
+                };
+                (file, result)
+            })
+            .buffer_unordered(num_semaphore.max(1));
+
+        let mut report = VerifyReport::default();
+        let mut verified_bytes = 0;
+        let mut completed = 0;
+        while let Some((file, result)) = results.next().await {
+            completed += 1;
+            if let Ok(Some(true)) = result {
+                verified_bytes += file.size;
+            }
+            // the bars count the files verified as if they were downloaded
+            progress_bars::on_event(&DownloadProgress {
+                pair: file.pair.clone(),
+                path: file.path.clone(),
+                bytes: file.size,
+                ok: matches!(result, Ok(Some(true) | None)),
+                completed,
+                total,
+                downloaded_bytes: verified_bytes,
+                total_bytes,
+            });
+            match result {
+                Ok(Some(true)) => report.ok.push(file),
+                Ok(Some(false)) => {
+                    log::warn!("Checksum does not match: {}", file.path.to_string_lossy());
+                    report.mismatch.push(file);
                 }
+                Ok(None) => report.missing.push(file),
+                Err(e) => {
+                    log::error!("Could not verify {}: {}", file.path.to_string_lossy(), e);
+                    report.failed.push((file, e.to_string()));
+                }
+            }
+        }
+        log::info!(
+            "Verified {} files: {} ok, {} mismatched, {} not on disk, {} failed",
+            self.len(),
+            report.ok.len(),
+            report.mismatch.len(),
+            report.missing.len(),
+            report.failed.len()
+        );
+        report
+    }
+
+    fn downloads(&self, num_semaphore: usize) -> impl Stream<Item = (File, Result<()>)> {
+        futures::stream::iter(self.files.clone())
+            .map(|file| {
+                let span = tracing::info_span!(
+                    "download",
+                    pair = %file.pair,
+                    file = %file.path.display()
+                );
+                async move {
+                    let _in_flight = metrics::InFlight::start("download");
+                    let _bar = progress_bars::DownloadBar::start(&file.path);
+                    // the errors name the object or the path already
+                    let result = match file.download().await {
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            tracing::error!("Could not download file. {}", e);
+                            Err(e)
+                        }
+                    };
+                    (file, result)
+                }
+                .instrument(span)
             })
             .buffer_unordered(num_semaphore)
     }
 }
 
+impl fmt::Display for FileCollection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary())
+    }
+}
+
+impl IntoIterator for FileCollection {
+    type Item = File;
+    type IntoIter = std::vec::IntoIter<File>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a FileCollection {
+    type Item = &'a File;
+    type IntoIter = std::slice::Iter<'a, File>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.iter()
+    }
+}
+
 impl FromIterator<FileCollection> for FileCollection {
     fn from_iter<T: IntoIterator<Item = FileCollection>>(iter: T) -> Self {
-        let files = iter.into_iter().fold(Vec::new(), |mut acc, collection| {
-            acc.extend(collection.files);
-            acc
-        });
-        FileCollection::new(files)
+        iter.into_iter().flatten().collect()
     }
 }
 
 impl FromIterator<File> for FileCollection {
+    /// Keeps the first of any files sharing an object key, e.g. when unioning overlapping
+    /// monthly and daily collections
     fn from_iter<T: IntoIterator<Item = File>>(iter: T) -> Self {
-        let files: Vec<File> = iter.into_iter().collect();
+        let mut seen = HashSet::new();
+        let mut duplicates = 0;
+        let files: Vec<File> = iter
+            .into_iter()
+            .filter(|file| {
+                let is_new = seen.insert(file.clone());
+                duplicates += usize::from(!is_new);
+                is_new
+            })
+            .collect();
+
+        if duplicates > 0 {
+            log::debug!("Skipped {} duplicate objects", duplicates);
+        }
         FileCollection::new(files)
     }
 }
@@ -99,10 +452,245 @@ impl FromIterator<File> for FileCollection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::binance::checksum::ChecksumAlgo;
     use crate::test_utils;
 
     #[test]
     fn file_collection_is_normal() {
         test_utils::is_normal::<FileCollection>();
     }
+
+    #[test]
+    fn test_summary() {
+        let file = |pair: &str, name: &str, size: u64| {
+            File::from_path(pair, Path::new(name)).with_size(size)
+        };
+        let files = FileCollection::new(vec![
+            file("BTCUSDT", "BTCUSDT-trades-2023-12.zip", 100),
+            file("BTCUSDT", "BTCUSDT-trades-2024-01.zip", 200),
+            file("ETHUSDT", "ETHUSDT-trades-2024-02-10.zip", 50),
+            file("SOLUSDT", "SOLUSDT-trades.zip", 0),
+        ]);
+        assert_eq!(
+            files.to_string(),
+            "4 files of 3 pairs, 350 bytes, 2023-12-01 to 2024-02-10"
+        );
+        assert_eq!(files.to_string(), files.summary());
+
+        assert_eq!(
+            FileCollection::empty().to_string(),
+            "0 files of 0 pairs, 0 bytes"
+        );
+    }
+
+    fn file(pair: &str, month: &str) -> File {
+        let key = format!("data/spot/monthly/trades/{pair}/{pair}-trades-{month}.zip");
+        File::new(pair, &key, &format!("{key}.CHECKSUM")).unwrap()
+    }
+
+    #[test]
+    fn test_from_objects_sizes() {
+        let object = |key: &str, size: u64| Object {
+            last_modified: "2024-02-01T10:11:12.000Z".to_string(),
+            e_tag: None,
+            storage_class: None,
+            key: key.to_string(),
+            owner: None,
+            size,
+        };
+        let prefix = "data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades";
+        let objects = vec![
+            object(&format!("{prefix}-2024-01.zip"), 100),
+            object(&format!("{prefix}-2024-01.zip.CHECKSUM"), 1),
+            object(&format!("{prefix}-2024-02.zip"), 300),
+            object(&format!("{prefix}-2024-02.zip.CHECKSUM"), 1),
+            // mirrors may store gzip compressed CSVs instead
+            object(&format!("{prefix}-2024-03.csv.gz"), 200),
+            object(&format!("{prefix}-2024-03.csv.gz.CHECKSUM"), 1),
+        ];
+        let mut collection =
+            FileCollection::from_objects("BTCUSDC", objects, &ChecksumOptions::default()).unwrap();
+        assert_eq!(collection.total_bytes(), 600);
+        assert!(collection.iter().all(|f| f.last_modified
+            == Some(
+                DateTime::parse_from_rfc3339("2024-02-01T10:11:12Z")
+                    .unwrap()
+                    .into()
+            )));
+
+        collection.sort_largest_first();
+        let sizes: Vec<u64> = collection.iter().map(|f| f.size).collect();
+        assert_eq!(sizes, vec![300, 200, 100]);
+        assert!(collection
+            .iter()
+            .any(|f| f.path.ends_with("BTCUSDC-trades-2024-03.csv.gz")));
+    }
+
+    #[test]
+    fn test_from_objects_checksum_options() {
+        let object = |key: &str| Object {
+            last_modified: "2024-02-01T10:11:12.000Z".to_string(),
+            e_tag: None,
+            storage_class: None,
+            key: key.to_string(),
+            owner: None,
+            size: 1,
+        };
+        let prefix = "data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades";
+        let objects = || {
+            vec![
+                object(&format!("{prefix}-2024-01.zip")),
+                object(&format!("{prefix}-2024-01.zip.md5")),
+                object(&format!("{prefix}-2024-02.zip")),
+            ]
+        };
+
+        let mut checksum = ChecksumOptions {
+            algo: ChecksumAlgo::Md5,
+            suffix: Arc::from(".md5"),
+            allow_missing: false,
+        };
+        let error = FileCollection::from_objects("BTCUSDC", objects(), &checksum).unwrap_err();
+        assert!(matches!(
+            &error,
+            DataError::UnpairedObject { missing: ObjectSide::Checksum, key, listed }
+                if *key == format!("{prefix}-2024-02.zip.md5")
+                    && *listed == format!("{prefix}-2024-02.zip")
+        ));
+        assert!(error.to_string().starts_with("Missing the checksum object"));
+
+        // a checksum without its data is an error either way
+        let mut orphaned = objects();
+        orphaned.push(object(&format!("{prefix}-2024-03.zip.md5")));
+        checksum.allow_missing = true;
+        assert!(matches!(
+            FileCollection::from_objects("BTCUSDC", orphaned, &checksum),
+            Err(DataError::UnpairedObject { missing: ObjectSide::Data, key, .. })
+                if key == format!("{prefix}-2024-03.zip")
+        ));
+
+        // data only files are kept, unverified, and still know when they were modified
+        let collection = FileCollection::from_objects("BTCUSDC", objects(), &checksum).unwrap();
+        assert_eq!(collection.len(), 2);
+        let modified = "2024-02-01T10:11:12Z".parse::<DateTime<Utc>>().unwrap();
+        assert!(collection
+            .iter()
+            .all(|file| file.last_modified == Some(modified)));
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let collection = FileCollection::new(vec![
+            file("BTCUSDC", "2024-01"),
+            file("ETHUSDC", "2024-01"),
+            file("BTCUSDC", "2024-02"),
+        ]);
+
+        let pairs: Vec<&str> = (&collection).into_iter().map(|f| f.pair.as_ref()).collect();
+        assert_eq!(pairs, vec!["BTCUSDC", "ETHUSDC", "BTCUSDC"]);
+
+        let (btc, eth): (Vec<File>, Vec<File>) = collection
+            .into_iter()
+            .partition(|f| f.pair.as_ref() == "BTCUSDC");
+        assert_eq!(btc.len(), 2);
+        assert_eq!(eth.len(), 1);
+    }
+
+    #[test]
+    fn test_from_iter_dedups() {
+        let monthly =
+            FileCollection::new(vec![file("BTCUSDC", "2024-01"), file("BTCUSDC", "2024-02")]);
+        let overlap =
+            FileCollection::new(vec![file("BTCUSDC", "2024-02"), file("ETHUSDC", "2024-02")]);
+
+        let collection: FileCollection = vec![monthly, overlap].into_iter().collect();
+        assert_eq!(collection.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_download_with_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        for month in ["2024-01", "2024-02"] {
+            std::fs::write(dir.path().join(format!("BTCUSDC-trades-{month}.zip")), b"").unwrap();
+        }
+        // files on disk are passed through without touching S3
+        let collection = FileCollection::from_local_dir(dir.path()).await.unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let files: Vec<File> = collection
+            .download_with_progress(2, tx)
+            .filter_map(|file| ready(file.ok()))
+            .collect()
+            .await;
+        assert_eq!(files.len(), 2);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(events.len(), collection.len());
+        assert!(events.iter().all(|e| e.ok && e.pair.as_ref() == "BTCUSDC"));
+        assert_eq!(events.last().unwrap().completed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_from_local_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let pair_dir = dir.path().join("spot/monthly/trades/BTCUSDC");
+        std::fs::create_dir_all(&pair_dir).unwrap();
+        std::fs::write(pair_dir.join("BTCUSDC-trades-2024-01.zip"), b"").unwrap();
+        std::fs::write(pair_dir.join("BTCUSDC-trades-2024-01.zip.CHECKSUM"), b"").unwrap();
+        std::fs::write(dir.path().join("ETHUSDC-trades-2024-01.zip"), b"").unwrap();
+
+        let mut collection = FileCollection::from_local_dir(dir.path()).await.unwrap();
+        collection.files.sort_by(|a, b| a.pair.cmp(&b.pair));
+        let pairs: Vec<&str> = collection.files.iter().map(|f| f.pair.as_ref()).collect();
+        assert_eq!(pairs, vec!["BTCUSDC", "ETHUSDC"]);
+        assert_eq!(
+            collection.files[0].path.as_ref(),
+            pair_dir.join("BTCUSDC-trades-2024-01.zip")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_on_disk() {
+        use crate::data::binance::store::MemoryStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let local = |month: &str, contents: Option<&str>| {
+            let name = format!("BTCUSDC-trades-{month}.zip");
+            let path = dir.path().join(&name);
+            if let Some(contents) = contents {
+                std::fs::write(&path, contents).unwrap();
+            }
+            let checksum = ChecksumAlgo::Sha256.digest(b"zip");
+            store.insert(&format!("{name}.CHECKSUM"), format!("{checksum}  {name}"));
+            File::from_path("BTCUSDC", &path)
+                .with_checksum_key(&format!("{name}.CHECKSUM"))
+                .with_object_store(store.clone())
+        };
+        let unpublished = dir.path().join("BTCUSDC-trades-2024-04.zip");
+        std::fs::write(&unpublished, "zip").unwrap();
+        let files = FileCollection::new(vec![
+            local("2024-01", Some("zip")),
+            local("2024-02", Some("corrupted")),
+            local("2024-03", None),
+            File::from_path("BTCUSDC", &unpublished),
+        ]);
+
+        let report = files.verify_on_disk(2).await;
+        let names = |files: &[File]| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.path.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        assert_eq!(names(&report.ok), vec!["BTCUSDC-trades-2024-01.zip"]);
+        assert_eq!(names(&report.mismatch), vec!["BTCUSDC-trades-2024-02.zip"]);
+        assert_eq!(names(&report.missing), vec!["BTCUSDC-trades-2024-03.zip"]);
+        // no checksum to compare with
+        assert_eq!(report.failed.len(), 1);
+        assert!(!report.is_ok());
+    }
 }
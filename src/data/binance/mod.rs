@@ -1,6 +1,9 @@
+pub mod candles;
+pub mod checksum;
 pub mod data_types;
 pub mod downloader;
 pub mod file;
-mod file_collection;
-mod pair;
-mod s3;
+pub mod file_collection;
+pub mod pair;
+pub mod s3;
+pub mod store;
@@ -1,8 +1,7 @@
 use std::sync::Arc;
 
-use anyhow::Result;
-
-use super::{file_collection::FileCollection, s3::Bucket};
+use super::{checksum::ChecksumOptions, file_collection::FileCollection, store::ObjectStore};
+use crate::data::error::Result;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Pair {
@@ -18,12 +17,19 @@ impl Pair {
         }
     }
 
-    pub async fn get_files(&self) -> Result<FileCollection> {
-        let bucket = Bucket::new()?;
-        let objects = bucket.list_objects(&self.prefix).await?;
-        let files = FileCollection::from_objects(&self.name, objects, ".CHECKSUM")?;
-
-        Ok(files)
+    /// Lists the files of the pair in `store`, which they are downloaded from later on
+    pub async fn get_files(
+        &self,
+        store: &Arc<dyn ObjectStore>,
+        checksum: &ChecksumOptions,
+    ) -> Result<FileCollection> {
+        let objects = store.list_objects(&self.prefix).await?;
+        let files = FileCollection::from_objects(&self.name, objects, checksum)?;
+
+        Ok(files
+            .into_iter()
+            .map(|file| file.with_object_store(Arc::clone(store)))
+            .collect())
     }
 }
 
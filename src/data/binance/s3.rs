@@ -1,13 +1,78 @@
+use std::io;
 use std::path::Path;
 
-use anyhow::{anyhow, Context, Result};
-use s3::{serde_types::Object, Bucket as S3Bucket};
-use tokio::fs;
+use std::pin::pin;
 
-use crate::utils::config;
+use futures::{Stream, StreamExt, TryStreamExt};
+use s3::{creds::Credentials, serde_types::Object, Bucket as S3Bucket, Region};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::io::StreamReader;
 
+use crate::data::error::{DataError, Result};
+use crate::utils::config::{self, BinanceConfig};
+use crate::utils::throttle::Throttle;
+
+use super::checksum::ChecksumAlgo;
 use super::pair::Pair;
 
+/// Region of Binance's public bucket
+const DEFAULT_REGION: &str = "ap-northeast-1";
+/// Signed along with a custom endpoint when no region is configured, MinIO's default
+const CUSTOM_ENDPOINT_REGION: &str = "us-east-1";
+
+/// Credentials from the environment, then from the config. None for anonymous access
+fn credentials(config: &BinanceConfig) -> Result<Option<Credentials>> {
+    if let Ok(credentials) = Credentials::from_env() {
+        return Ok(Some(credentials));
+    }
+    match (&config.access_key, &config.secret_key) {
+        (Some(access_key), Some(secret_key)) => Ok(Some(
+            Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+                .map_err(|e| DataError::Config(format!("Invalid S3 credentials: {}", e)))?,
+        )),
+        (None, None) => Ok(None),
+        _ => Err(DataError::Config(
+            "Both access_key and secret_key must be set for S3 credentials".to_string(),
+        )),
+    }
+}
+
+/// Writes `chunks` to `output`, hashing them on the way. Returns the upper case hex digest
+pub(super) async fn write_hashed<S, B, W>(
+    chunks: S,
+    output: &mut W,
+    algo: ChecksumAlgo,
+) -> Result<String>
+where
+    S: Stream<Item = Result<B>>,
+    B: AsRef<[u8]>,
+    W: AsyncWrite + Unpin,
+{
+    let throttle = Throttle::global();
+    let mut hasher = algo.hasher();
+    let mut chunks = pin!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        let chunk = chunk.as_ref();
+        if let Some(throttle) = throttle {
+            throttle.consume(chunk.len() as u64).await;
+        }
+        hasher.update(chunk);
+        output
+            .write_all(chunk)
+            .await
+            .map_err(|e| DataError::io("Could not write object", e))?;
+    }
+    output
+        .flush()
+        .await
+        .map_err(|e| DataError::io("Could not write object", e))?;
+    Ok(hasher.finalize())
+}
+
 #[derive(Debug)]
 pub struct Bucket {
     bucket: S3Bucket,
@@ -15,41 +80,106 @@ pub struct Bucket {
 
 impl Bucket {
     pub fn new() -> Result<Self> {
-        let config = config::Config::create();
-        let region = "ap-northeast-1".parse().unwrap();
-        let mut bucket = S3Bucket::new_public(config.binance.bucket_name.as_str(), region)
-            .context("Failed to create S3 bucket")?
-            .with_path_style();
+        let config = config::Config::create().map_err(|e| DataError::Config(format!("{:#}", e)))?;
+        Bucket::from_config(&config.binance)
+    }
+
+    pub fn from_config(config: &BinanceConfig) -> Result<Self> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: config
+                    .region
+                    .clone()
+                    .unwrap_or_else(|| CUSTOM_ENDPOINT_REGION.to_string()),
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+            },
+            None => config
+                .region
+                .as_deref()
+                .unwrap_or(DEFAULT_REGION)
+                .parse()
+                .map_err(|e| DataError::Config(format!("Invalid S3 region: {}", e)))?,
+        };
+        let mut bucket = match credentials(config)? {
+            Some(credentials) => S3Bucket::new(config.bucket_name.as_str(), region, credentials),
+            None => S3Bucket::new_public(config.bucket_name.as_str(), region),
+        }
+        .map_err(|e| DataError::s3("Failed to create S3 bucket", e))?
+        .with_path_style();
         bucket.set_listobjects_v2();
+        if let Some(user_agent) = &config.user_agent {
+            bucket.add_header("User-Agent", user_agent);
+        }
 
         Ok(Bucket { bucket })
     }
 
-    pub async fn get_object_to_file(&self, key: &str, file_path: &Path) -> Result<()> {
+    /// Waits for the request throttle, if one is configured. A listing counts as a single
+    /// request even when it is paginated
+    async fn throttle_request(&self) {
+        if let Some(throttle) = Throttle::requests() {
+            throttle.consume(1).await;
+        }
+    }
+
+    /// Streams `key` into `file_path` and returns the `algo` digest of what was written, as
+    /// upper case hex. Without `overwrite` an existing file is left untouched and an
+    /// `io::ErrorKind::AlreadyExists` error is returned.
+    pub async fn get_object_to_file(
+        &self,
+        key: &str,
+        file_path: &Path,
+        overwrite: bool,
+        algo: ChecksumAlgo,
+    ) -> Result<String> {
         // create parent dirs
         match file_path.parent() {
-            Some(path) if !path.exists() => fs::create_dir_all(path).await.with_context(|| {
-                format!("Failed to create directory: {}", path.to_string_lossy())
+            Some(path) if !path.exists() => fs::create_dir_all(path).await.map_err(|e| {
+                DataError::io(
+                    format!("Failed to create directory: {}", path.to_string_lossy()),
+                    e,
+                )
             })?,
-            None => return Err(anyhow!("{} has no parent", file_path.to_string_lossy())),
+            None => {
+                return Err(DataError::InvalidData(format!(
+                    "{} has no parent",
+                    file_path.to_string_lossy()
+                )))
+            }
             _ => (),
         };
 
-        let mut output_file = fs::File::create_new(file_path).await?;
-        self.bucket
-            .get_object_to_writer(key, &mut output_file)
-            .await
-            .with_context(|| {
+        let output_file = if overwrite {
+            fs::File::create(file_path).await
+        } else {
+            fs::File::create_new(file_path).await
+        };
+        let mut output_file = output_file.map_err(|e| {
+            DataError::io(
+                format!("Could not create file: {}", file_path.to_string_lossy()),
+                e,
+            )
+        })?;
+        self.throttle_request().await;
+        let mut response = self.bucket.get_object_stream(key).await.map_err(|e| {
+            DataError::s3(
                 format!(
                     "Could not download object to file: {} -> {}",
                     key,
                     file_path.to_string_lossy()
-                )
-            })?;
-        Ok(())
+                ),
+                e,
+            )
+        })?;
+
+        let chunks = response
+            .bytes()
+            .map_err(|e| DataError::s3(format!("Could not read object: {}", key), e));
+        write_hashed(chunks, &mut output_file, algo).await
     }
 
     pub async fn list_pairs(&self, path: &str) -> Result<Vec<Pair>> {
+        self.throttle_request().await;
         let terminated_path = if path.ends_with('/') {
             path.to_owned()
         } else {
@@ -60,10 +190,13 @@ impl Bucket {
             .bucket
             .list(terminated_path, Some("/".to_string()))
             .await
-            .with_context(|| {
-                anyhow!(
-                    "Failed to list S3 bucket objects from: {}/",
-                    path.trim_end_matches('/'),
+            .map_err(|e| {
+                DataError::s3(
+                    format!(
+                        "Failed to list S3 bucket objects from: {}/",
+                        path.trim_end_matches('/')
+                    ),
+                    e,
                 )
             })?
             .into_iter()
@@ -75,7 +208,18 @@ impl Bucket {
             .collect::<Vec<_>>())
     }
 
+    /// Lists at most one key under `path`, to find out early whether the bucket answers
+    pub async fn check(&self, path: &str) -> Result<()> {
+        self.throttle_request().await;
+        self.bucket
+            .list_page(path.to_owned(), Some("/".to_string()), None, None, Some(1))
+            .await
+            .map(|_| ())
+            .map_err(|e| DataError::s3(format!("S3 bucket is not reachable, listing {}", path), e))
+    }
+
     pub async fn list_objects(&self, path: &str) -> Result<Vec<Object>> {
+        self.throttle_request().await;
         let terminated_path = if path.ends_with('/') {
             path.to_owned()
         } else {
@@ -86,10 +230,13 @@ impl Bucket {
             .bucket
             .list(terminated_path, Some("/".to_string()))
             .await
-            .with_context(|| {
-                format!(
-                    "Failed to list s3 bucket objects from: {}/",
-                    path.trim_end_matches('/'),
+            .map_err(|e| {
+                DataError::s3(
+                    format!(
+                        "Failed to list s3 bucket objects from: {}/",
+                        path.trim_end_matches('/')
+                    ),
+                    e,
                 )
             })?
             .into_iter()
@@ -98,13 +245,30 @@ impl Bucket {
         Ok(objects)
     }
 
+    /// Streams an object instead of loading it into memory like `read_object`
+    pub async fn read_object_stream(&self, path: &str) -> Result<impl AsyncRead + Send + Unpin> {
+        self.throttle_request().await;
+        let response = self
+            .bucket
+            .get_object_stream(path)
+            .await
+            .map_err(|e| DataError::s3(format!("Could not read object: {}", path), e))?;
+        Ok(StreamReader::new(response.bytes.map_err(io::Error::other)))
+    }
+
     pub async fn read_object(&self, path: &str) -> Result<String> {
+        self.throttle_request().await;
         self.bucket
             .get_object(&path)
             .await
-            .with_context(|| format!("Could not read object: {}", path))?
+            .map_err(|e| DataError::s3(format!("Could not read object: {}", path), e))?
             .to_string()
-            .with_context(|| format!("Could not convert object contents to String: {}", path))
+            .map_err(|e| {
+                DataError::InvalidData(format!(
+                    "Could not convert object contents to String: {}: {}",
+                    path, e
+                ))
+            })
     }
 }
 
@@ -117,4 +281,106 @@ mod tests {
     fn bucket_is_normal() {
         test_utils::is_normal::<Bucket>();
     }
+
+    #[test]
+    fn test_region() {
+        let config: BinanceConfig =
+            serde_yaml::from_str("bucket_name: my-mirror\nregion: us-east-1").unwrap();
+        let bucket = Bucket::from_config(&config).unwrap();
+        assert_eq!(bucket.bucket.region().to_string(), "us-east-1");
+
+        let config: BinanceConfig = serde_yaml::from_str("bucket_name: my-mirror").unwrap();
+        let bucket = Bucket::from_config(&config).unwrap();
+        assert_eq!(bucket.bucket.region().to_string(), DEFAULT_REGION);
+    }
+
+    #[test]
+    fn test_custom_endpoint() {
+        let config: BinanceConfig =
+            serde_yaml::from_str("bucket_name: binance-cache\nendpoint: http://minio.local:9000/")
+                .unwrap();
+        let bucket = Bucket::from_config(&config).unwrap();
+        assert_eq!(bucket.bucket.region().endpoint(), "http://minio.local:9000");
+        assert_eq!(bucket.bucket.region().to_string(), CUSTOM_ENDPOINT_REGION);
+        // path style, the bucket is not a subdomain of the endpoint
+        assert_eq!(bucket.bucket.url(), "http://minio.local:9000/binance-cache");
+
+        let config: BinanceConfig = serde_yaml::from_str(
+            "bucket_name: binance-cache\nendpoint: https://account.r2.cloudflarestorage.com\nregion: auto",
+        )
+        .unwrap();
+        let bucket = Bucket::from_config(&config).unwrap();
+        assert_eq!(bucket.bucket.region().to_string(), "auto");
+        assert_eq!(
+            bucket.bucket.region().endpoint(),
+            "https://account.r2.cloudflarestorage.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_unreachable() {
+        let config: BinanceConfig =
+            serde_yaml::from_str("bucket_name: my-mirror\nregion: http://127.0.0.1:1").unwrap();
+        let bucket = Bucket::from_config(&config).unwrap();
+        let result =
+            tokio::time::timeout(std::time::Duration::from_secs(10), bucket.check("data/"))
+                .await
+                .expect("check should fail promptly");
+        assert!(matches!(result, Err(DataError::S3 { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_credentials() {
+        let config = BinanceConfig {
+            bucket_name: "my-mirror".to_string(),
+            access_key: Some("AKIDEXAMPLE".to_string()),
+            secret_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let bucket = Bucket::from_config(&config).unwrap();
+        assert!(bucket
+            .bucket
+            .credentials()
+            .await
+            .unwrap()
+            .access_key
+            .is_some());
+
+        let config = BinanceConfig {
+            bucket_name: "my-mirror".to_string(),
+            access_key: Some("AKIDEXAMPLE".to_string()),
+            ..Default::default()
+        };
+        if std::env::var("AWS_ACCESS_KEY_ID").is_err() {
+            assert!(Bucket::from_config(&config).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_hashed() {
+        let chunks = futures::stream::iter(vec![Ok(b"hello ".to_vec()), Ok(b"world".to_vec())]);
+        let mut output = Vec::new();
+        let digest = write_hashed(chunks, &mut output, ChecksumAlgo::Sha256)
+            .await
+            .unwrap();
+        assert_eq!(output, b"hello world");
+        assert_eq!(
+            digest,
+            "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9"
+        );
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let config = BinanceConfig {
+            bucket_name: "data.binance.vision".to_string(),
+            user_agent: Some("cryptoquant-test".to_string()),
+            ..Default::default()
+        };
+        let bucket = Bucket::from_config(&config).unwrap();
+        assert_eq!(
+            bucket.bucket.extra_headers().get("User-Agent").unwrap(),
+            "cryptoquant-test"
+        );
+    }
 }
@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use s3::serde_types::Object;
+use tokio::fs;
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+
+use super::checksum::ChecksumAlgo;
+use super::pair::Pair;
+use super::s3::{write_hashed, Bucket};
+use crate::data::error::{DataError, Result};
+use crate::utils::config::{self, StoreBackend};
+
+/// Where Binance's objects are listed and read from. `Bucket` talks to S3, `MemoryStore`
+/// keeps everything in memory so listing and filtering can be tested without the network.
+pub trait ObjectStore: Debug + Send + Sync {
+    /// The "directories" directly under `path`, as pairs named after their last segment
+    fn list_pairs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>>;
+
+    /// The objects directly under `path`
+    fn list_objects<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Object>>>;
+
+    /// Writes `key` into `file_path` and returns its `algo` digest as upper case hex
+    fn get_object_to_file<'a>(
+        &'a self,
+        key: &'a str,
+        file_path: &'a Path,
+        overwrite: bool,
+        algo: ChecksumAlgo,
+    ) -> BoxFuture<'a, Result<String>>;
+
+    fn read_object_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn AsyncRead + Send + Unpin>>>;
+
+    fn read_object<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<String>>;
+
+    /// Fails when the store cannot be reached
+    fn check<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// The backend selected by `binance.backend` in the config, the S3 bucket by default
+pub fn default_store() -> Result<Arc<dyn ObjectStore>> {
+    let config = config::Config::create().map_err(|e| DataError::Config(format!("{:#}", e)))?;
+    let config = &config.binance;
+    match config.backend {
+        StoreBackend::S3 => Ok(Arc::new(Bucket::from_config(config)?)),
+        StoreBackend::Local => {
+            let root = config.local_root.as_deref().ok_or_else(|| {
+                DataError::Config("binance.local_root is required by the local backend".into())
+            })?;
+            let root = shellexpand::full(root)
+                .map_err(|e| DataError::Config(format!("Failed to expand path: {}", e)))?;
+            Ok(Arc::new(LocalStore::new(Path::new(root.as_ref()))))
+        }
+    }
+}
+
+impl ObjectStore for Bucket {
+    fn list_pairs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(Bucket::list_pairs(self, path))
+    }
+
+    fn list_objects<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+        Box::pin(Bucket::list_objects(self, path))
+    }
+
+    fn get_object_to_file<'a>(
+        &'a self,
+        key: &'a str,
+        file_path: &'a Path,
+        overwrite: bool,
+        algo: ChecksumAlgo,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(Bucket::get_object_to_file(
+            self, key, file_path, overwrite, algo,
+        ))
+    }
+
+    fn read_object_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn AsyncRead + Send + Unpin>>> {
+        Box::pin(async move {
+            let reader = Bucket::read_object_stream(self, path).await?;
+            Ok(Box::new(reader) as Box<dyn AsyncRead + Send + Unpin>)
+        })
+    }
+
+    fn read_object<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(Bucket::read_object(self, path))
+    }
+
+    fn check<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(Bucket::check(self, path))
+    }
+}
+
+/// Objects kept in memory, keyed like S3 e.g. data/spot/monthly/trades/BTCUSDT/...
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    pub fn with_object(self, key: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.insert(key, contents);
+        self
+    }
+
+    pub fn insert(&self, key: &str, contents: impl Into<Vec<u8>>) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), contents.into());
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| DataError::MissingObject(key.to_string()))
+    }
+
+    /// Keys under `path`, with `path` and its trailing slash stripped
+    fn children(&self, path: &str) -> Vec<(String, u64)> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        self.objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, contents)| {
+                let rest = key.strip_prefix(&prefix)?;
+                Some((rest.to_string(), contents.len() as u64))
+            })
+            .collect()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn list_pairs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let names: BTreeSet<String> = self
+            .children(path)
+            .into_iter()
+            .filter_map(|(rest, _)| Some(rest.split_once('/')?.0.to_string()))
+            .collect();
+        let pairs = names
+            .into_iter()
+            .map(|name| Pair::new(&format!("{prefix}{name}/"), &name))
+            .collect();
+        Box::pin(async move { Ok(pairs) })
+    }
+
+    fn list_objects<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let objects = self
+            .children(path)
+            .into_iter()
+            .filter(|(rest, _)| !rest.contains('/'))
+            .map(|(rest, size)| Object {
+                last_modified: String::new(),
+                e_tag: None,
+                storage_class: None,
+                key: format!("{prefix}{rest}"),
+                owner: None,
+                size,
+            })
+            .collect();
+        Box::pin(async move { Ok(objects) })
+    }
+
+    fn get_object_to_file<'a>(
+        &'a self,
+        key: &'a str,
+        file_path: &'a Path,
+        overwrite: bool,
+        algo: ChecksumAlgo,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let contents = self.get(key)?;
+            let write_error = |e| {
+                DataError::io(
+                    format!("Could not write file: {}", file_path.to_string_lossy()),
+                    e,
+                )
+            };
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent).await.map_err(write_error)?;
+            }
+            if !overwrite && fs::try_exists(file_path).await.map_err(write_error)? {
+                return Err(write_error(io::ErrorKind::AlreadyExists.into()));
+            }
+            fs::write(file_path, &contents).await.map_err(write_error)?;
+            Ok(algo.digest(&contents))
+        })
+    }
+
+    fn read_object_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn AsyncRead + Send + Unpin>>> {
+        Box::pin(async move {
+            let contents = self.get(path)?;
+            Ok(Box::new(std::io::Cursor::new(contents)) as Box<dyn AsyncRead + Send + Unpin>)
+        })
+    }
+
+    fn read_object<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            String::from_utf8(self.get(path)?).map_err(|e| {
+                DataError::InvalidData(format!(
+                    "Could not convert object contents to String: {}: {}",
+                    path, e
+                ))
+            })
+        })
+    }
+
+    fn check<'a>(&'a self, _path: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A directory laid out like the bucket, for working offline. Keys are paths relative
+/// to `root`.
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> Self {
+        LocalStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key.trim_end_matches('/'))
+    }
+
+    fn error(&self, key: &str, e: io::Error) -> DataError {
+        if e.kind() == io::ErrorKind::NotFound {
+            DataError::MissingObject(key.to_string())
+        } else {
+            DataError::io(
+                format!("Could not read {}", self.path(key).to_string_lossy()),
+                e,
+            )
+        }
+    }
+
+    /// Entries directly under `path` with their names, sorted by name
+    async fn entries(&self, path: &str) -> Result<Vec<(String, fs::DirEntry)>> {
+        let mut dir = fs::read_dir(self.path(path))
+            .await
+            .map_err(|e| self.error(path, e))?;
+        let mut entries = Vec::new();
+        while let Some(entry) = dir.next_entry().await.map_err(|e| self.error(path, e))? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            entries.push((name, entry));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn list_pairs<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async move {
+            let prefix = path.trim_end_matches('/');
+            let mut pairs = Vec::new();
+            for (name, entry) in self.entries(path).await? {
+                let file_type = entry.file_type().await.map_err(|e| self.error(path, e))?;
+                if file_type.is_dir() {
+                    pairs.push(Pair::new(&format!("{prefix}/{name}/"), &name));
+                }
+            }
+            Ok(pairs)
+        })
+    }
+
+    fn list_objects<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+        Box::pin(async move {
+            let prefix = path.trim_end_matches('/');
+            let mut objects = Vec::new();
+            for (name, entry) in self.entries(path).await? {
+                let metadata = entry.metadata().await.map_err(|e| self.error(path, e))?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let last_modified = metadata
+                    .modified()
+                    .map(|modified| DateTime::<Utc>::from(modified).to_rfc3339())
+                    .unwrap_or_default();
+                objects.push(Object {
+                    last_modified,
+                    e_tag: None,
+                    storage_class: None,
+                    key: format!("{prefix}/{name}"),
+                    owner: None,
+                    size: metadata.len(),
+                });
+            }
+            Ok(objects)
+        })
+    }
+
+    fn get_object_to_file<'a>(
+        &'a self,
+        key: &'a str,
+        file_path: &'a Path,
+        overwrite: bool,
+        algo: ChecksumAlgo,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let input = fs::File::open(self.path(key))
+                .await
+                .map_err(|e| self.error(key, e))?;
+            let write_error = |e| {
+                DataError::io(
+                    format!("Could not write file: {}", file_path.to_string_lossy()),
+                    e,
+                )
+            };
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent).await.map_err(write_error)?;
+            }
+            let output = if overwrite {
+                fs::File::create(file_path).await
+            } else {
+                fs::File::create_new(file_path).await
+            };
+            let mut output = output.map_err(write_error)?;
+            let chunks = ReaderStream::new(input).map_err(|e| self.error(key, e));
+            write_hashed(chunks, &mut output, algo).await
+        })
+    }
+
+    fn read_object_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn AsyncRead + Send + Unpin>>> {
+        Box::pin(async move {
+            let file = fs::File::open(self.path(path))
+                .await
+                .map_err(|e| self.error(path, e))?;
+            Ok(Box::new(file) as Box<dyn AsyncRead + Send + Unpin>)
+        })
+    }
+
+    fn read_object<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            fs::read_to_string(self.path(path))
+                .await
+                .map_err(|e| self.error(path, e))
+        })
+    }
+
+    fn check<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.entries(path).await.map(|_| ()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store_listing() {
+        let store = MemoryStore::new()
+            .with_object(
+                "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades-2024-01.zip",
+                "zip",
+            )
+            .with_object(
+                "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades-2024-01.zip.CHECKSUM",
+                "sum",
+            )
+            .with_object(
+                "data/spot/monthly/trades/ETHUSDT/ETHUSDT-trades-2024-01.zip",
+                "zip",
+            );
+
+        let pairs = store.list_pairs("data/spot/monthly/trades").await.unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                Pair::new("data/spot/monthly/trades/BTCUSDT/", "BTCUSDT"),
+                Pair::new("data/spot/monthly/trades/ETHUSDT/", "ETHUSDT"),
+            ]
+        );
+
+        let objects = store.list_objects(&pairs[0].prefix).await.unwrap();
+        let keys: Vec<&str> = objects.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades-2024-01.zip",
+                "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades-2024-01.zip.CHECKSUM",
+            ]
+        );
+        assert_eq!(objects[0].size, 3);
+        assert!(matches!(
+            store.read_object("missing").await,
+            Err(DataError::MissingObject(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_local_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let pair_dir = dir.path().join("data/spot/monthly/trades/BTCUSDT");
+        std::fs::create_dir_all(&pair_dir).unwrap();
+        std::fs::write(pair_dir.join("BTCUSDT-trades-2024-01.zip"), b"zip").unwrap();
+        std::fs::write(pair_dir.join("BTCUSDT-trades-2024-01.zip.CHECKSUM"), b"sum").unwrap();
+
+        let store = LocalStore::new(dir.path());
+        let pairs = store.list_pairs("data/spot/monthly/trades").await.unwrap();
+        assert_eq!(
+            pairs,
+            vec![Pair::new("data/spot/monthly/trades/BTCUSDT/", "BTCUSDT")]
+        );
+
+        let objects = store.list_objects(&pairs[0].prefix).await.unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(
+            objects[0].key,
+            "data/spot/monthly/trades/BTCUSDT/BTCUSDT-trades-2024-01.zip"
+        );
+        assert_eq!(objects[0].size, 3);
+
+        let target = dir.path().join("download/BTCUSDT-trades-2024-01.zip");
+        let digest = store
+            .get_object_to_file(&objects[0].key, &target, false, ChecksumAlgo::Sha256)
+            .await
+            .unwrap();
+        assert_eq!(digest, ChecksumAlgo::Sha256.digest(b"zip"));
+        assert_eq!(std::fs::read(&target).unwrap(), b"zip");
+        assert_eq!(store.read_object(&objects[1].key).await.unwrap(), "sum");
+        assert!(matches!(
+            store.list_pairs("data/futures").await,
+            Err(DataError::MissingObject(_))
+        ));
+    }
+}
@@ -0,0 +1,251 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use clickhouse::{sql, Client, Row};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::pipeline::{index_files, IndexSettings};
+use super::report::RunReport;
+use super::trades::Side;
+use super::utils::{
+    create_client, create_distributed, insert_rows, local_table, AddableQuantities, Endpoints,
+};
+use crate::data::binance::file::{AggTradeRow, File};
+use crate::utils::config::{self, InserterConfig};
+use crate::{DataType, Downloader};
+
+/// Aggregated trades of a `DataType::AggTrades` downloader
+#[derive(Clone)]
+pub struct AggTradesTable {
+    client: Client,
+    /// Where the rows go, spread by pair
+    endpoints: Endpoints,
+    database: Arc<str>,
+    name: Arc<str>,
+    downloader: Arc<Downloader>,
+    inserter: InserterConfig,
+    settings: IndexSettings,
+    /// Cluster the table is created `ON CLUSTER` of, behind a Distributed table
+    cluster: Option<Arc<str>>,
+}
+
+impl AggTradesTable {
+    pub async fn new(database: &str, name: &str, downloader: Downloader) -> Result<Self> {
+        let client = create_client(database).await?;
+        let endpoints = Endpoints::from_config(database)?;
+        let config = config::Config::create()?;
+        let config = &config.clickhouse;
+        AggTradesTable::from_client(client, database, name, downloader).map(|table| {
+            AggTradesTable {
+                endpoints,
+                cluster: config.cluster.as_deref().map(Arc::from),
+                ..table.with_inserter_config(config.inserter)
+            }
+        })
+    }
+
+    fn from_client(
+        client: Client,
+        database: &str,
+        name: &str,
+        downloader: Downloader,
+    ) -> Result<Self> {
+        if downloader.data_type != DataType::AggTrades {
+            return Err(anyhow!(
+                "AggTradesTable needs an aggTrades downloader, got {}",
+                downloader.data_type
+            ));
+        }
+
+        Ok(AggTradesTable {
+            endpoints: Endpoints::from(client.clone()),
+            client,
+            database: Arc::from(database),
+            name: Arc::from(name.to_uppercase()),
+            downloader: Arc::new(downloader),
+            inserter: InserterConfig::default(),
+            settings: IndexSettings::default(),
+            cluster: None,
+        })
+    }
+
+    pub fn with_inserter_config(mut self, config: InserterConfig) -> Self {
+        self.inserter = config;
+        self
+    }
+
+    pub fn with_download_concurrency(mut self, concurrency: usize) -> Self {
+        self.settings.download_concurrency = concurrency;
+        self
+    }
+
+    pub fn with_index_concurrency(mut self, concurrency: usize) -> Self {
+        self.settings.index_concurrency = concurrency;
+        self
+    }
+
+    pub fn with_file_log_level(mut self, level: log::Level) -> Self {
+        self.settings.file_log_level = level;
+        self
+    }
+
+    /// Creates the table `ON CLUSTER` and inserts through a Distributed table
+    pub fn with_cluster(mut self, cluster: &str) -> Self {
+        self.cluster = Some(Arc::from(cluster));
+        self
+    }
+
+    /// Creates the table. On a cluster the rows live in `<NAME>_LOCAL` on every node and
+    /// `<NAME>` is a Distributed table over them
+    pub async fn create(&self) -> Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return self.create_table(&self.name, None).await;
+        };
+        self.create_table(&local_table(&self.name), Some(cluster))
+            .await?;
+        create_distributed(&self.client, &self.name, cluster).await
+    }
+
+    async fn create_table(&self, name: &str, cluster: Option<&str>) -> Result<()> {
+        let on_cluster = if cluster.is_some() {
+            "ON CLUSTER ?"
+        } else {
+            ""
+        };
+        let query = format!(
+            "
+                CREATE TABLE IF NOT EXISTS ? {on_cluster}
+                (
+                    dt DateTime64(3, 'UTC') COMMENT 'Trade datetime (dt) in ms',
+                    agg_trade_id UInt64 COMMENT 'Aggregate trade id',
+                    pair LowCardinality(String) COMMENT 'Pair being traded BASE ASSET IN DENOM',
+                    side Boolean COMMENT 'Long=True; Short=False',
+                    price Float32 COMMENT 'Asset price in DENOM',
+                    qty Float32 COMMENT 'Aggregated QTY in BASE ASSET',
+                    first_trade_id UInt64 COMMENT 'First trade id in the aggregate',
+                    last_trade_id UInt64 COMMENT 'Last trade id in the aggregate',
+                )
+                -- Deduplicates rows by key
+                ENGINE = ReplacingMergeTree
+                ORDER BY (dt, agg_trade_id, pair)
+            "
+        );
+
+        let mut query = self.client.query(&query).bind(sql::Identifier(name));
+        if let Some(cluster) = cluster {
+            query = query.bind(sql::Identifier(cluster));
+        }
+        query
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Could not create table: {}", e))
+    }
+
+    /// Indexes every file of the downloader. Files which fail are recorded in the report
+    /// instead of failing the run, check `RunReport::is_success`.
+    pub async fn index(&self) -> Result<RunReport> {
+        self.create().await?;
+
+        let pairs = self.downloader.get_pairs().await?;
+        let files = self.downloader.get_files(&pairs).await?;
+        let report = index_files(&self.name, &files, self.settings, |file| async move {
+            self.index_file(&file).await
+        })
+        .await;
+        log::info!("[{}] Indexed into {}", self.name, self.database);
+        Ok(report)
+    }
+
+    pub async fn index_file(&self, file: &File) -> Result<AddableQuantities> {
+        let pair = file.pair.to_string();
+        let rows = file
+            .rows::<AggTradeRow>()
+            .await?
+            .map_ok(move |row| AggTradesRow::new(&pair, row))
+            .err_into();
+        insert_rows(
+            &self.endpoints,
+            &file.pair,
+            &self.name,
+            &self.inserter,
+            rows,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Row, Serialize, Deserialize)]
+pub struct AggTradesRow {
+    /// Trade time in unix epoch to ms
+    pub dt: u64,
+    pub agg_trade_id: u64,
+    pub pair: String,
+    /// Side of the taker, stored as Buy=true; Sell=false
+    pub side: Side,
+    pub price: f32,
+    pub qty: f32,
+    pub first_trade_id: u64,
+    pub last_trade_id: u64,
+}
+
+impl AggTradesRow {
+    fn new(pair: &str, row: AggTradeRow) -> Self {
+        AggTradesRow {
+            dt: row.transact_time,
+            agg_trade_id: row.agg_trade_id,
+            pair: pair.to_string(),
+            side: Side::from_buyer_maker(row.is_buyer_maker),
+            price: row.price,
+            qty: row.qty,
+            first_trade_id: row.first_trade_id,
+            last_trade_id: row.last_trade_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Asset, Cadence};
+    use clickhouse::test::{handlers, Mock};
+
+    #[test]
+    fn test_requires_agg_trades_downloader() {
+        let client = Client::default();
+        let trades =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        assert!(AggTradesTable::from_client(client.clone(), "test", "agg", trades).is_err());
+
+        let agg =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::AggTrades).unwrap();
+        assert!(AggTradesTable::from_client(client, "test", "agg", agg).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create_on_cluster() {
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::AggTrades).unwrap();
+        let table = AggTradesTable::from_client(client, "test", "agg", downloader).unwrap();
+
+        let ddl = mock.add(handlers::record_ddl());
+        table.create().await.unwrap();
+        let query = ddl.query().await;
+        assert!(query.contains("CREATE TABLE IF NOT EXISTS `AGG`"));
+        assert!(!query.contains("ON CLUSTER"));
+
+        let table = table.with_cluster("analytics");
+        let local_ddl = mock.add(handlers::record_ddl());
+        let distributed_ddl = mock.add(handlers::record_ddl());
+        table.create().await.unwrap();
+        assert!(local_ddl
+            .query()
+            .await
+            .contains("CREATE TABLE IF NOT EXISTS `AGG_LOCAL` ON CLUSTER `analytics`"));
+        assert!(distributed_ddl.query().await.contains(
+            "Distributed(`analytics`, currentDatabase(), `AGG_LOCAL`, cityHash64(pair))"
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arrow_array::{
+    BooleanArray, Float32Array, RecordBatch, StringArray, TimestampMillisecondArray, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+use super::trades::{TradesRow, DT_COLUMN};
+
+/// Rows buffered into each record batch
+pub const BATCH_ROWS: usize = 8192;
+
+/// Arrow schema of `TradesRow`, `dt` becomes a UTC millisecond timestamp
+pub fn trades_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            DT_COLUMN,
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("pair", DataType::Utf8, false),
+        Field::new("side", DataType::Boolean, false),
+        Field::new("price", DataType::Float32, false),
+        Field::new("qty", DataType::Float32, false),
+        Field::new("notional", DataType::Float32, false),
+        Field::new("id", DataType::UInt32, false),
+    ]))
+}
+
+/// Writes `TradesRow`s to a Parquet file in batches of `BATCH_ROWS`
+pub struct TradesParquetWriter {
+    writer: ArrowWriter<fs::File>,
+    schema: SchemaRef,
+    buffer: Vec<TradesRow>,
+    rows: u64,
+}
+
+impl TradesParquetWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = fs::File::create(path)
+            .with_context(|| format!("Could not create {}", path.display()))?;
+        let schema = trades_schema();
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(props))?;
+        Ok(Self {
+            writer,
+            schema,
+            buffer: Vec::with_capacity(BATCH_ROWS),
+            rows: 0,
+        })
+    }
+
+    pub fn write(&mut self, row: TradesRow) -> Result<()> {
+        self.buffer.push(row);
+        if self.buffer.len() >= BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let rows = &self.buffer;
+        let batch = RecordBatch::try_new(
+            self.schema.clone(),
+            vec![
+                Arc::new(
+                    TimestampMillisecondArray::from_iter_values(rows.iter().map(|r| r.dt as i64))
+                        .with_timezone("UTC"),
+                ),
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &*r.pair))),
+                Arc::new(BooleanArray::from_iter(
+                    rows.iter().map(|r| Some(r.side.is_buy())),
+                )),
+                Arc::new(Float32Array::from_iter_values(rows.iter().map(|r| r.price))),
+                Arc::new(Float32Array::from_iter_values(rows.iter().map(|r| r.qty))),
+                Arc::new(Float32Array::from_iter_values(
+                    rows.iter().map(|r| r.notional),
+                )),
+                Arc::new(UInt32Array::from_iter_values(rows.iter().map(|r| r.id))),
+            ],
+        )?;
+        self.writer.write(&batch)?;
+        self.rows += rows.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes what is left and the footer, returns the number of rows written. Without
+    /// any rows the file still holds the schema
+    pub fn close(mut self) -> Result<u64> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(self.rows)
+    }
+}
+
+/// Runs a `TradesParquetWriter` of `path` on the blocking pool, fed with batches of rows
+/// through the sender. Dropping the sender closes the file, the handle then returns the
+/// number of rows written. The sender fails once the writer has failed
+pub fn spawn_writer(path: &Path) -> (mpsc::Sender<Vec<TradesRow>>, JoinHandle<Result<u64>>) {
+    let (tx, mut rx) = mpsc::channel::<Vec<TradesRow>>(2);
+    let path = path.to_path_buf();
+    let handle = task::spawn_blocking(move || {
+        let mut writer = TradesParquetWriter::create(&path)?;
+        while let Some(batch) = rx.blocking_recv() {
+            for row in batch {
+                writer.write(row)?;
+            }
+        }
+        writer.close()
+    });
+    (tx, handle)
+}
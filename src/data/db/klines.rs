@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use clickhouse::{sql, Client, Row};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::pipeline::{index_files, IndexSettings};
+use super::report::RunReport;
+use super::utils::{
+    create_client, create_distributed, insert_rows, local_table, AddableQuantities, Endpoints,
+};
+use crate::data::binance::file::{File, KLineRow};
+use crate::utils::config::{self, InserterConfig};
+use crate::{DataType, Downloader};
+
+/// OHLCV candles of a `DataType::KLines` downloader
+#[derive(Clone)]
+pub struct KLinesTable {
+    client: Client,
+    /// Where the rows go, spread by pair
+    endpoints: Endpoints,
+    database: Arc<str>,
+    name: Arc<str>,
+    downloader: Arc<Downloader>,
+    inserter: InserterConfig,
+    settings: IndexSettings,
+    /// Cluster the table is created `ON CLUSTER` of, behind a Distributed table
+    cluster: Option<Arc<str>>,
+}
+
+impl KLinesTable {
+    pub async fn new(database: &str, name: &str, downloader: Downloader) -> Result<Self> {
+        let client = create_client(database).await?;
+        let endpoints = Endpoints::from_config(database)?;
+        let config = config::Config::create()?;
+        let config = &config.clickhouse;
+        KLinesTable::from_client(client, database, name, downloader).map(|table| KLinesTable {
+            endpoints,
+            cluster: config.cluster.as_deref().map(Arc::from),
+            ..table.with_inserter_config(config.inserter)
+        })
+    }
+
+    fn from_client(
+        client: Client,
+        database: &str,
+        name: &str,
+        downloader: Downloader,
+    ) -> Result<Self> {
+        if downloader.data_type != DataType::KLines {
+            return Err(anyhow!(
+                "KLinesTable needs a klines downloader, got {}",
+                downloader.data_type
+            ));
+        }
+
+        Ok(KLinesTable {
+            endpoints: Endpoints::from(client.clone()),
+            client,
+            database: Arc::from(database),
+            name: Arc::from(name.to_uppercase()),
+            downloader: Arc::new(downloader),
+            inserter: InserterConfig::default(),
+            settings: IndexSettings::default(),
+            cluster: None,
+        })
+    }
+
+    pub fn with_inserter_config(mut self, config: InserterConfig) -> Self {
+        self.inserter = config;
+        self
+    }
+
+    pub fn with_download_concurrency(mut self, concurrency: usize) -> Self {
+        self.settings.download_concurrency = concurrency;
+        self
+    }
+
+    pub fn with_index_concurrency(mut self, concurrency: usize) -> Self {
+        self.settings.index_concurrency = concurrency;
+        self
+    }
+
+    pub fn with_file_log_level(mut self, level: log::Level) -> Self {
+        self.settings.file_log_level = level;
+        self
+    }
+
+    /// Creates the table `ON CLUSTER` and inserts through a Distributed table
+    pub fn with_cluster(mut self, cluster: &str) -> Self {
+        self.cluster = Some(Arc::from(cluster));
+        self
+    }
+
+    /// Creates the table. On a cluster the rows live in `<NAME>_LOCAL` on every node and
+    /// `<NAME>` is a Distributed table over them
+    pub async fn create(&self) -> Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return self.create_table(&self.name, None).await;
+        };
+        self.create_table(&local_table(&self.name), Some(cluster))
+            .await?;
+        create_distributed(&self.client, &self.name, cluster).await
+    }
+
+    async fn create_table(&self, name: &str, cluster: Option<&str>) -> Result<()> {
+        let on_cluster = if cluster.is_some() {
+            "ON CLUSTER ?"
+        } else {
+            ""
+        };
+        let query = format!(
+            "
+                CREATE TABLE IF NOT EXISTS ? {on_cluster}
+                (
+                    open_time DateTime64(3, 'UTC') COMMENT 'Candle open time in ms',
+                    pair LowCardinality(String) COMMENT 'Pair being traded BASE ASSET IN DENOM',
+                    interval LowCardinality(String) COMMENT 'Candle interval, e.g. 1m',
+                    open Float32,
+                    high Float32,
+                    low Float32,
+                    close Float32,
+                    volume Float32 COMMENT 'Volume in BASE ASSET',
+                    close_time DateTime64(3, 'UTC') COMMENT 'Candle close time in ms',
+                    quote_volume Float32 COMMENT 'Volume in DENOM',
+                    count UInt64 COMMENT 'Number of trades',
+                    taker_buy_volume Float32,
+                    taker_buy_quote_volume Float32,
+                )
+                -- Deduplicates candles by key
+                ENGINE = ReplacingMergeTree
+                ORDER BY (pair, interval, open_time)
+            "
+        );
+
+        let mut query = self.client.query(&query).bind(sql::Identifier(name));
+        if let Some(cluster) = cluster {
+            query = query.bind(sql::Identifier(cluster));
+        }
+        query
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Could not create table: {}", e))
+    }
+
+    /// Indexes every file of the downloader. Files which fail are recorded in the report
+    /// instead of failing the run, check `RunReport::is_success`.
+    pub async fn index(&self) -> Result<RunReport> {
+        self.create().await?;
+
+        let pairs = self.downloader.get_pairs().await?;
+        let files = self.downloader.get_files(&pairs).await?;
+        let report = index_files(&self.name, &files, self.settings, |file| async move {
+            self.index_file(&file).await
+        })
+        .await;
+        log::info!("[{}] Indexed into {}", self.name, self.database);
+        Ok(report)
+    }
+
+    pub async fn index_file(&self, file: &File) -> Result<AddableQuantities> {
+        let pair = file.pair.to_string();
+        let interval = self.downloader.kline_interval.to_string();
+        let rows = file
+            .rows::<KLineRow>()
+            .await?
+            .map_ok(move |row| KLinesRow::new(&pair, &interval, row))
+            .err_into();
+        insert_rows(
+            &self.endpoints,
+            &file.pair,
+            &self.name,
+            &self.inserter,
+            rows,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Row, Serialize, Deserialize)]
+pub struct KLinesRow {
+    /// Candle open time in unix epoch to ms
+    pub open_time: u64,
+    pub pair: String,
+    pub interval: String,
+    pub open: f32,
+    pub high: f32,
+    pub low: f32,
+    pub close: f32,
+    pub volume: f32,
+    /// Candle close time in unix epoch to ms
+    pub close_time: u64,
+    pub quote_volume: f32,
+    pub count: u64,
+    pub taker_buy_volume: f32,
+    pub taker_buy_quote_volume: f32,
+}
+
+impl KLinesRow {
+    fn new(pair: &str, interval: &str, row: KLineRow) -> Self {
+        KLinesRow {
+            open_time: row.open_time,
+            pair: pair.to_string(),
+            interval: interval.to_string(),
+            open: row.open,
+            high: row.high,
+            low: row.low,
+            close: row.close,
+            volume: row.volume,
+            close_time: row.close_time,
+            quote_volume: row.quote_volume,
+            count: row.count,
+            taker_buy_volume: row.taker_buy_volume,
+            taker_buy_quote_volume: row.taker_buy_quote_volume,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Asset, Cadence};
+    use clickhouse::test::{handlers, Mock};
+
+    #[test]
+    fn test_requires_klines_downloader() {
+        let client = Client::default();
+        let trades =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        assert!(KLinesTable::from_client(client.clone(), "test", "klines", trades).is_err());
+
+        let klines =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::KLines).unwrap();
+        assert!(KLinesTable::from_client(client, "test", "klines", klines).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create() {
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::KLines).unwrap();
+        let table = KLinesTable::from_client(client, "test", "klines", downloader).unwrap();
+
+        let ddl = mock.add(handlers::record_ddl());
+        table.create().await.unwrap();
+        let query = ddl.query().await;
+        assert!(query.contains("CREATE TABLE IF NOT EXISTS `KLINES`"));
+        assert!(query.contains("ORDER BY (pair, interval, open_time)"));
+    }
+
+    #[tokio::test]
+    async fn test_create_on_cluster() {
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::KLines).unwrap();
+        let table = KLinesTable::from_client(client, "test", "klines", downloader)
+            .unwrap()
+            .with_cluster("analytics");
+
+        let local_ddl = mock.add(handlers::record_ddl());
+        let distributed_ddl = mock.add(handlers::record_ddl());
+        table.create().await.unwrap();
+        assert!(local_ddl
+            .query()
+            .await
+            .contains("CREATE TABLE IF NOT EXISTS `KLINES_LOCAL` ON CLUSTER `analytics`"));
+        assert!(distributed_ddl.query().await.contains(
+            "CREATE TABLE IF NOT EXISTS `KLINES` ON CLUSTER `analytics` AS `KLINES_LOCAL`"
+        ));
+    }
+}
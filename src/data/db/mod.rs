@@ -1,3 +1,9 @@
+pub mod agg_trades;
+pub mod export;
+pub mod klines;
+pub mod notifier;
+mod pipeline;
+pub mod report;
 pub mod trades;
-mod trades_index_log;
+pub mod trades_index_log;
 mod utils;
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::json;
+
+use super::report::IndexSummary;
+
+/// Told how an index run went, e.g. to ping someone about a run left unattended. A
+/// notifier which fails is logged, the run is not failed because of it
+pub trait Notifier: Send + Sync {
+    /// Once the run is over, whether files failed or not
+    fn on_complete<'a>(&'a self, summary: &'a IndexSummary) -> BoxFuture<'a, Result<()>>;
+
+    /// For every file which failed, with the pair when it is known, and for a run which
+    /// failed as a whole
+    fn on_error<'a>(&'a self, pair: Option<&'a str>, error: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// POSTs JSON to a URL. The `text` field makes it readable as a Slack incoming webhook
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        WebhookNotifier {
+            url: url.to_string(),
+        }
+    }
+
+    async fn post(&self, body: impl Serialize) -> Result<()> {
+        let body = serde_json::to_vec(&body)?;
+        let url = self.url.clone();
+        // attohttpc blocks
+        let response = tokio::task::spawn_blocking(move || {
+            attohttpc::post(&url)
+                .header("Content-Type", "application/json")
+                .bytes(body)
+                .send()
+        })
+        .await?
+        .with_context(|| format!("Could not POST to {}", self.url))?;
+        if !response.is_success() {
+            return Err(anyhow!("{} answered {}", self.url, response.status()));
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn on_complete<'a>(&'a self, summary: &'a IndexSummary) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.post(json!({
+            "text": format!(
+                "Indexed {} files, {} rows; {} files skipped, {} failed",
+                summary.files, summary.rows, summary.skipped, summary.failed
+            ),
+            "event": "complete",
+            "summary": summary,
+        })))
+    }
+
+    fn on_error<'a>(&'a self, pair: Option<&'a str>, error: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.post(json!({
+            "text": format!("Indexing {} failed: {}", pair.unwrap_or("trades"), error),
+            "event": "error",
+            "pair": pair,
+            "error": error,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_webhook_notifier() {
+        let (url, server) = test_utils::serve_once();
+        let summary = IndexSummary {
+            files: 2,
+            rows: 6,
+            ..Default::default()
+        };
+        WebhookNotifier::new(&format!("{url}/hook"))
+            .on_complete(&summary)
+            .await
+            .unwrap();
+        let request = server.join().unwrap();
+        assert!(request.request_line.starts_with("POST /hook "));
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["event"], "complete");
+        assert_eq!(body["summary"]["rows"], 6);
+        assert!(body["text"].as_str().unwrap().contains("6 rows"));
+    }
+}
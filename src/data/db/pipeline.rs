@@ -0,0 +1,133 @@
+use std::future::Future;
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::Utc;
+use futures::StreamExt;
+
+use super::report::RunReport;
+use super::utils::AddableQuantities;
+use crate::data::binance::file::File;
+use crate::data::binance::file_collection::FileCollection;
+
+const DEFAULT_INDEX_CONCURRENCY: usize = 10;
+const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 50;
+
+/// How `index_files` downloads and indexes a collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexSettings {
+    pub download_concurrency: usize,
+    pub index_concurrency: usize,
+    /// Level of the per file progress logs
+    pub file_log_level: log::Level,
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        Self {
+            download_concurrency: DEFAULT_DOWNLOAD_CONCURRENCY,
+            index_concurrency: DEFAULT_INDEX_CONCURRENCY,
+            file_log_level: log::Level::Info,
+        }
+    }
+}
+
+/// Downloads `files` and indexes each with `index_file`. A file which could not be
+/// downloaded or indexed is logged and recorded in the report, the others carry on.
+pub async fn index_files<F, Fut>(
+    name: &str,
+    files: &FileCollection,
+    settings: IndexSettings,
+    index_file: F,
+) -> RunReport
+where
+    F: Fn(File) -> Fut,
+    Fut: Future<Output = Result<AddableQuantities>>,
+{
+    let now = Instant::now();
+    let mut report = RunReport::new(name, Utc::now(), files.len());
+
+    let index_file = &index_file;
+    let mut results = files
+        .download_stream(settings.download_concurrency)
+        .map(|file| async move {
+            let file = file?;
+            let pair = file.pair.to_string();
+            let filename = file.path.to_string_lossy().to_string();
+            log::log!(
+                settings.file_log_level,
+                "[{}] Indexing pair={}; file={}",
+                name,
+                pair,
+                filename
+            );
+            let result = index_file(file).await;
+            Ok::<_, anyhow::Error>((pair, filename, result))
+        })
+        .buffer_unordered(settings.index_concurrency.max(1));
+
+    while let Some(result) = results.next().await {
+        match result {
+            Ok((pair, _, Ok(stats))) => report.record_indexed(&pair, stats),
+            Ok((pair, filename, Err(e))) => {
+                log::error!("[{}] Could not index {}: {}", name, filename, e);
+                report.record_failure(Some(&pair), Some(&filename), e.to_string());
+            }
+            // the download already logged its error
+            Err(e) => report.record_failure(None, None, e.to_string()),
+        }
+    }
+
+    let summary = report.summary();
+    log::info!(
+        "[{}] Inserter summary: {} files, {} bytes, {} rows, {} transactions inserted; \
+         {} failed",
+        name,
+        summary.files,
+        summary.bytes,
+        summary.rows,
+        summary.transactions,
+        summary.failed,
+    );
+    report.duration_secs = now.elapsed().as_secs_f64();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_index_files_counts_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        for pair in ["BTCUSDC", "ETHUSDC", "SOLUSDC"] {
+            let path = dir.path().join(format!("{pair}-aggTrades-2024-01.zip"));
+            tokio::fs::write(path, b"").await.unwrap();
+        }
+        let files = FileCollection::from_local_dir(dir.path()).await.unwrap();
+
+        let settings = IndexSettings {
+            index_concurrency: 2,
+            ..Default::default()
+        };
+        let report = index_files("AGG", &files, settings, |file| async move {
+            if &*file.pair == "ETHUSDC" {
+                return Err(anyhow!("boom"));
+            }
+            Ok(AddableQuantities {
+                rows: 2,
+                ..Default::default()
+            })
+        })
+        .await;
+
+        assert!(!report.is_success());
+        assert_eq!(report.files, 3);
+        assert_eq!(report.files_indexed, 2);
+        assert_eq!(report.stats.rows, 4);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].pair.as_deref(), Some("ETHUSDC"));
+        assert_eq!(report.failures[0].reason, "boom");
+    }
+}
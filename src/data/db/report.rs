@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use serde::Serialize;
+
+use super::utils::AddableQuantities;
+
+/// Outcome of an index run, meant to be archived and asserted on by automated pipelines
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct RunReport {
+    pub name: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    /// Files the run was asked to index
+    pub files: usize,
+    /// Files which were fully indexed
+    pub files_indexed: usize,
+    /// Files left alone because they were already indexed
+    pub files_skipped: usize,
+    /// Totals over every indexed file
+    pub stats: AddableQuantities,
+    pub pairs: BTreeMap<String, PairReport>,
+    pub failures: Vec<Failure>,
+    /// The run was stopped through its shutdown token before every file was processed
+    pub cancelled: bool,
+}
+
+/// The totals of a run, without the per pair breakdown and the failure reasons
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct IndexSummary {
+    /// Files which were fully indexed
+    pub files: usize,
+    pub bytes: u64,
+    pub rows: u64,
+    pub transactions: u64,
+    /// Files left alone because they were already indexed
+    pub skipped: usize,
+    /// Files which could not be downloaded or indexed, or were aborted
+    pub failed: usize,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PairReport {
+    pub files_indexed: usize,
+    #[serde(flatten)]
+    pub stats: AddableQuantities,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    /// None when the failure could not be tied to a file, e.g. a panicked worker
+    pub pair: Option<String>,
+    pub file: Option<String>,
+    pub reason: String,
+}
+
+impl RunReport {
+    pub fn new(name: &str, started_at: DateTime<Utc>, files: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            started_at,
+            files,
+            ..Default::default()
+        }
+    }
+
+    pub fn record_indexed(&mut self, pair: &str, stats: AddableQuantities) {
+        self.files_indexed += 1;
+        self.stats += stats.clone();
+        let pair = self.pairs.entry(pair.to_string()).or_default();
+        pair.files_indexed += 1;
+        pair.stats += stats;
+    }
+
+    pub fn record_skipped(&mut self) {
+        self.files_skipped += 1;
+    }
+
+    pub fn record_failure(&mut self, pair: Option<&str>, file: Option<&str>, reason: String) {
+        self.failures.push(Failure {
+            pair: pair.map(str::to_string),
+            file: file.map(str::to_string),
+            reason,
+        });
+    }
+
+    pub fn summary(&self) -> IndexSummary {
+        IndexSummary {
+            files: self.files_indexed,
+            bytes: self.stats.bytes,
+            rows: self.stats.rows,
+            transactions: self.stats.transactions,
+            skipped: self.files_skipped,
+            failed: self.failures.len(),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty() && !self.cancelled
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Could not serialize run report")
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, self.to_json()?)
+            .with_context(|| format!("Could not write run report to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(rows: u64) -> AddableQuantities {
+        AddableQuantities {
+            bytes: rows * 10,
+            rows,
+            transactions: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut report = RunReport::new("test", DateTime::UNIX_EPOCH, 3);
+        report.record_indexed("BTCUSDC", stats(2));
+        report.record_indexed("BTCUSDC", stats(3));
+        assert!(report.is_success());
+
+        report.record_failure(
+            Some("ETHUSDC"),
+            Some("ETHUSDC-trades-2024-01.zip"),
+            "boom".to_string(),
+        );
+        assert!(!report.is_success());
+        assert_eq!(report.files_indexed, 2);
+        assert_eq!(report.stats.rows, 5);
+        assert_eq!(report.pairs["BTCUSDC"].files_indexed, 2);
+        assert_eq!(report.pairs["BTCUSDC"].stats.transactions, 2);
+
+        report.record_skipped();
+        let summary = report.summary();
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.rows, 5);
+        assert_eq!(summary.bytes, 50);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failed, 1);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut report = RunReport::new("test", DateTime::UNIX_EPOCH, 1);
+        report.record_indexed("BTCUSDC", stats(2));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["started_at"], "1970-01-01T00:00:00Z");
+        assert_eq!(json["stats"]["rows"], 2);
+        assert_eq!(json["pairs"]["BTCUSDC"]["files_indexed"], 1);
+        assert_eq!(json["pairs"]["BTCUSDC"]["bytes"], 20);
+        assert_eq!(json["failures"], serde_json::json!([]));
+    }
+}
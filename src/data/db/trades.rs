@@ -1,48 +1,389 @@
 use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::ops::Deref;
+use std::path::Path;
+use std::pin::pin;
 use std::time::Instant;
 use std::{sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
 use chrono::prelude::*;
+use chrono::Months;
 use clickhouse::{sql, Client, Row};
-use futures::StreamExt;
+use futures::stream::BoxStream;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-use super::utils::create_client;
+use super::export;
+use super::notifier::Notifier;
+use super::report::RunReport;
 use super::utils::AddableQuantities;
-use crate::data::binance::file::File;
-use crate::data::db::trades_index_log::{FileIndexLogRow, TradesIndexLogTable};
-use crate::{data::binance::file::Row as FileRow, Downloader};
+use super::utils::{create_client, create_distributed, local_table, Endpoints, RetryingInserter};
+use crate::data::binance::file::Row as FileRow;
+use crate::data::binance::file::{EmptyFieldPolicy, File, SkippedRows};
+use crate::data::binance::file_collection::FileCollection;
+use crate::data::binance::pair::Pair;
+use crate::data::db::trades_index_log::{FileIndexLogRow, IndexStatus, TradesIndexLogTable};
+use crate::data::error::{DataError, Result as DataResult};
+use crate::data::source::TradeSource;
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::config::{self, InserterConfig};
+use crate::utils::metrics;
+
+/// `tracing` events need their level at compile time, this picks it from a `log::Level`
+macro_rules! event_at {
+    ($level:expr, $($arg:tt)+) => {
+        match $level {
+            log::Level::Error => tracing::error!($($arg)+),
+            log::Level::Warn => tracing::warn!($($arg)+),
+            log::Level::Info => tracing::info!($($arg)+),
+            log::Level::Debug => tracing::debug!($($arg)+),
+            log::Level::Trace => tracing::trace!($($arg)+),
+        }
+    };
+}
+
+const DEFAULT_INDEX_CONCURRENCY: usize = 10;
+const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 50;
+/// Trade datetime column, must match `TradesRow::dt`
+pub const DT_COLUMN: &str = "dt";
+/// Name of the trade datetime column in tables created by older versions
+pub const LEGACY_DT_COLUMN: &str = "time";
+
+/// Merges to force once indexing has finished
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OptimizeMode {
+    /// Leave merging to ClickHouse
+    #[default]
+    Off,
+    /// `OPTIMIZE TABLE`
+    Merge,
+    /// `OPTIMIZE TABLE ... FINAL`, merges everything and fully deduplicates
+    Final,
+}
+
+/// How trades are laid out across tables
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TableStrategy {
+    /// One `<NAME>` table for all pairs
+    #[default]
+    Single,
+    /// One `<NAME>_<PAIR>` table per pair, e.g. TRADES_BTCUSDT
+    PerPair,
+}
+
+impl TableStrategy {
+    /// Table of the trades of `pair` for a trades table called `name`
+    pub fn table_for_pair(&self, name: &str, pair: &str) -> String {
+        let name = name.to_ascii_uppercase();
+        match self {
+            TableStrategy::Single => name,
+            TableStrategy::PerPair => {
+                let pair: String = pair
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_alphanumeric() {
+                            c.to_ascii_uppercase()
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect();
+                format!("{}_{}", name, pair)
+            }
+        }
+    }
+}
+
+/// Engine and keys of the trades tables. The expressions go into the DDL as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSchema {
+    /// `ReplacingMergeTree` by default, which deduplicates rows by the sorting key
+    pub engine: String,
+    /// e.g. `toYYYYMM(dt)`, not partitioned when unset
+    pub partition_by: Option<String>,
+    /// Sorting key, which is the primary key as well
+    pub order_by: String,
+}
+
+impl Default for TableSchema {
+    fn default() -> Self {
+        TableSchema {
+            engine: "ReplacingMergeTree".to_string(),
+            partition_by: None,
+            // There are duplicates on (dt, pair) because multiple tx's can happen
+            // at the same datetime, so we need id to ensure we don't miss rows.
+            order_by: format!("({DT_COLUMN}, id, pair)"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct TradesTable {
     client: Client,
+    /// Where the trades go, spread by pair
+    endpoints: Endpoints,
     database: Arc<str>,
     name: Arc<str>,
-    downloader: Arc<Downloader>,
+    source: Arc<dyn TradeSource>,
+    file_log_level: log::Level,
+    shutdown: CancellationToken,
+    clock: Arc<dyn Clock>,
+    rounding: RoundingPolicy,
+    cluster: Option<Arc<str>>,
+    empty_fields: EmptyFieldPolicy,
+    table_strategy: TableStrategy,
+    schema: TableSchema,
+    optimize: OptimizeMode,
+    inserter: InserterConfig,
+    incremental: bool,
+    skip_covered_ids: bool,
+    lenient_rows: bool,
+    validation: Option<RowValidation>,
+    timestamps: TimestampPolicy,
+    notifier: Option<Arc<dyn Notifier>>,
+    ctrl_c: bool,
+    index_concurrency: usize,
+    download_concurrency: usize,
 }
 
 // TODO: We likely want to wrap this functionality into a trait
 // but traits cannot define async functions, which makes this complicated?
 // ==> use async_traits crate
 impl TradesTable {
-    pub async fn new(database: &str, name: &str, downloader: Downloader) -> Result<Self> {
-        Ok(TradesTable {
-            client: create_client(database).await?,
+    pub async fn new(
+        database: &str,
+        name: &str,
+        source: impl TradeSource + 'static,
+    ) -> Result<Self> {
+        let config = config::Config::create()?;
+        let config = &config.clickhouse;
+        let client = create_client(database).await?;
+        let table = TradesTable {
+            endpoints: Endpoints::from_config(database)?,
+            ..TradesTable::from_client(client, database, name, source)
+        }
+        .with_inserter_config(config.inserter);
+        Ok(match &config.cluster {
+            Some(cluster) => table.with_cluster(cluster),
+            None => table,
+        })
+    }
+
+    fn from_client(
+        client: Client,
+        database: &str,
+        name: &str,
+        source: impl TradeSource + 'static,
+    ) -> Self {
+        TradesTable {
+            endpoints: Endpoints::from(client.clone()),
+            client,
             database: Arc::from(database),
             name: name.to_ascii_uppercase().into(),
-            downloader: Arc::new(downloader),
-        })
+            source: Arc::new(source),
+            file_log_level: log::Level::Info,
+            shutdown: CancellationToken::new(),
+            clock: Arc::new(SystemClock),
+            rounding: RoundingPolicy::default(),
+            cluster: None,
+            empty_fields: EmptyFieldPolicy::default(),
+            table_strategy: TableStrategy::default(),
+            schema: TableSchema::default(),
+            optimize: OptimizeMode::default(),
+            inserter: InserterConfig::default(),
+            incremental: true,
+            skip_covered_ids: false,
+            lenient_rows: false,
+            validation: None,
+            timestamps: TimestampPolicy::default(),
+            notifier: None,
+            ctrl_c: true,
+            index_concurrency: DEFAULT_INDEX_CONCURRENCY,
+            download_concurrency: DEFAULT_DOWNLOAD_CONCURRENCY,
+        }
+    }
+
+    /// Overrides `clickhouse.inserter` from the config
+    pub fn with_inserter_config(mut self, config: InserterConfig) -> Self {
+        self.inserter = config;
+        self
+    }
+
+    /// Files indexed into ClickHouse concurrently
+    pub fn with_index_concurrency(mut self, concurrency: usize) -> Self {
+        self.index_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Files downloaded concurrently ahead of indexing
+    pub fn with_download_concurrency(mut self, concurrency: usize) -> Self {
+        self.download_concurrency = concurrency.max(1);
+        self
+    }
+
+    fn index_semaphore(&self) -> Arc<Semaphore> {
+        Arc::new(Semaphore::new(self.index_concurrency))
+    }
+
+    /// Whether `index` skips the files the index log has as completely indexed, on by default.
+    /// Turn it off to re-insert everything and rely on `ReplacingMergeTree` dedup.
+    pub fn with_incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Makes `index_file` read the id range of each file first and skip the insert when a
+    /// completely indexed file in the index log already covers it, e.g. a daily file of a
+    /// month which has been indexed. Off by default, as it reads every file twice.
+    pub fn with_skip_covered_ids(mut self, skip: bool) -> Self {
+        self.skip_covered_ids = skip;
+        self
+    }
+
+    /// Drops and counts the rows of a file which cannot be deserialized, instead of failing
+    /// the file. The count ends up in `AddableQuantities::skipped_rows`.
+    pub fn with_lenient_rows(mut self, lenient: bool) -> Self {
+        self.lenient_rows = lenient;
+        self
+    }
+
+    /// Checks every row before it is inserted, see `RowValidation`. Off by default
+    pub fn with_row_validation(mut self, validation: Option<RowValidation>) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Sets how trade times outside of 2017 to a day from now are handled, e.g. times of a
+    /// mirror in seconds or microseconds. Inserted as they are by default
+    pub fn with_timestamp_policy(mut self, policy: TimestampPolicy) -> Self {
+        self.timestamps = policy;
+        self
+    }
+
+    /// Tells `notifier` about the failed files and the summary once `index` is over
+    pub fn with_notifier(mut self, notifier: impl Notifier + 'static) -> Self {
+        self.notifier = Some(Arc::new(notifier));
+        self
+    }
+
+    /// Runs `OPTIMIZE TABLE` on the indexed tables once `index` has finished. This forces
+    /// merges of the whole table, which is expensive on large tables.
+    pub fn with_optimize(mut self, mode: OptimizeMode) -> Self {
+        self.optimize = mode;
+        self
+    }
+
+    pub fn with_table_strategy(mut self, strategy: TableStrategy) -> Self {
+        self.table_strategy = strategy;
+        self
+    }
+
+    /// Engine, partitioning and sorting key of the tables `create` makes. Tables that
+    /// already exist are left as they are.
+    pub fn with_schema(mut self, schema: TableSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Sets how rows with empty price, qty or quote_qty fields are handled
+    pub fn with_empty_field_policy(mut self, policy: EmptyFieldPolicy) -> Self {
+        self.empty_fields = policy;
+        self
+    }
+
+    /// Creates the table `ON CLUSTER` and inserts through a Distributed table
+    pub fn with_cluster(mut self, cluster: &str) -> Self {
+        self.cluster = Some(Arc::from(cluster));
+        self
+    }
+
+    /// Rounds price and qty of every indexed trade
+    pub fn with_rounding(mut self, rounding: RoundingPolicy) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Sets the clock used to stamp `index_dt` in the index log
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets the level of the per-file log lines emitted by `index_file`.
+    /// Use `Level::Debug` to keep only the run summary at info when indexing many files.
+    pub fn with_file_log_level(mut self, level: log::Level) -> Self {
+        self.file_log_level = level;
+        self
+    }
+
+    /// Returns a token which, once cancelled, makes `index` stop pulling new files and
+    /// return after the in-flight workers have finished. Workers stop at their next
+    /// commit and log what they have inserted as partial.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Whether Ctrl-C cancels the `shutdown_token` while indexing, on by default. Once
+    /// installed, tokio keeps handling SIGINT for the rest of the process, so embedders
+    /// with their own signal handling should turn it off.
+    pub fn with_ctrl_c(mut self, ctrl_c: bool) -> Self {
+        self.ctrl_c = ctrl_c;
+        self
     }
 
+    /// Creates the table and the index log. With `TableStrategy::PerPair` the pair tables
+    /// are created on demand while indexing instead.
     pub async fn create(&self) -> Result<()> {
-        self.client
-            .query(
-                "
-                CREATE TABLE IF NOT EXISTS ?
+        if self.table_strategy == TableStrategy::Single {
+            self.create_named(&self.name).await?;
+        }
+        TradesIndexLogTable::from_client(self.client.clone(), &self.database)
+            .create()
+            .await
+    }
+
+    /// Table into which the trades of `pair` are indexed
+    pub fn table_for_pair(&self, pair: &str) -> String {
+        self.table_strategy.table_for_pair(&self.name, pair)
+    }
+
+    /// On a cluster the data lives in `<NAME>_LOCAL` on every node and `<NAME>` is a
+    /// Distributed table over them, so inserts and reads use `<NAME>` either way.
+    async fn create_named(&self, name: &str) -> Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return self.create_table(name, None).await;
+        };
+
+        self.create_table(&local_table(name), Some(cluster)).await?;
+        create_distributed(&self.client, name, cluster).await
+    }
+
+    async fn create_table(&self, name: &str, cluster: Option<&str>) -> Result<()> {
+        let on_cluster = if cluster.is_some() {
+            "ON CLUSTER ?"
+        } else {
+            ""
+        };
+        let TableSchema {
+            engine,
+            partition_by,
+            order_by,
+        } = &self.schema;
+        let partition_by = match partition_by {
+            Some(expr) => format!("PARTITION BY {expr}"),
+            None => String::new(),
+        };
+        let query = format!(
+            "
+                CREATE TABLE IF NOT EXISTS ? {on_cluster}
                 (
-                    dt DateTime64(3, 'UTC') COMMENT 'Trade datetime (dt) in ms',
+                    {DT_COLUMN} DateTime64(3, 'UTC') COMMENT 'Trade datetime (dt) in ms',
                     id UInt32 COMMENT 'Trade id',
                     pair LowCardinality(String) COMMENT 'Pair being traded BASE ASSET IN DENOM',
                     side Boolean COMMENT 'Long=True; Short=False',
@@ -50,189 +391,2750 @@ impl TradesTable {
                     qty Float32 COMMENT 'Trade QTY in BASE ASSET',
                     notional Float32 COMMENT 'price * qty; Notional value',
                 )
-                -- Deduplicates rows by key
-                ENGINE = ReplacingMergeTree
-                
-                -- There are duplicates on (dt, pair) because multiple tx's can happen
-                -- at the same datetime, so we need id to ensure we don't miss rows.
-                PRIMARY KEY (dt, id, pair)
-                ORDER BY (dt, id, pair)
-            ",
-            )
-            .bind(sql::Identifier(&self.name))
+                ENGINE = {engine}
+                {partition_by}
+                PRIMARY KEY {order_by}
+                ORDER BY {order_by}
+            "
+        );
+
+        let mut query = self.client.query(&query).bind(sql::Identifier(name));
+        if let Some(cluster) = cluster {
+            query = query.bind(sql::Identifier(cluster));
+        }
+        query
             .execute()
             .await
             .map_err(|e| anyhow!("Could not create table: {}", e))
     }
 
-    pub async fn index(&self) -> Result<()> {
+    #[tracing::instrument(name = "index", skip_all, fields(table = %self.name))]
+    pub async fn index(&self) -> Result<RunReport> {
+        let result = self.index_pairs().await;
+        if let Some(notifier) = &self.notifier {
+            self.notify(notifier.as_ref(), &result).await;
+        }
+        result
+    }
+
+    async fn notify(&self, notifier: &dyn Notifier, result: &Result<RunReport>) {
+        let notified = match result {
+            Ok(report) => {
+                let mut notified = Ok(());
+                for failure in &report.failures {
+                    notified = notified.and(
+                        notifier
+                            .on_error(failure.pair.as_deref(), &failure.reason)
+                            .await,
+                    );
+                }
+                notified.and(notifier.on_complete(&report.summary()).await)
+            }
+            Err(e) => notifier.on_error(None, &format!("{:#}", e)).await,
+        };
+        if let Err(e) = notified {
+            tracing::warn!("[{}] Could not notify: {:#}", self.name, e);
+        }
+    }
+
+    async fn index_pairs(&self) -> Result<RunReport> {
+        tracing::info!("[{}] Indexing from {}", self.name, self.source.name());
+        let pairs = self.source.list_pairs().await?;
+        if pairs.is_empty() {
+            tracing::warn!(
+                "[{}] No pairs matched the filters of {}, nothing to index",
+                self.name,
+                self.source.name()
+            );
+            return Ok(RunReport::new(&self.name, self.clock.now(), 0));
+        }
+
         // TODO: Db initialization procedure otw this will get called multiple times
         self.create().await?;
+        let mut files = self.source.list_files(&pairs).await?;
+        let skipped = if self.incremental {
+            self.skip_indexed(&mut files).await?
+        } else {
+            0
+        };
+        let mut report = self.index_stream(&files).await?;
+        report.files_skipped += skipped;
+        Ok(report)
+    }
+
+    /// Drops the files whose index log row says they were completely indexed into the table
+    /// they would go to. Returns how many were dropped
+    async fn skip_indexed(&self, files: &mut FileCollection) -> Result<usize> {
+        let log = TradesIndexLogTable::from_client(self.client.clone(), &self.database);
+        let indexed: HashSet<(String, String)> = log
+            .complete_files(&self.database)
+            .await?
+            .into_iter()
+            .map(|row| (row.filename, row.table))
+            .collect();
+
+        let before = files.len();
+        files.retain(|file| {
+            let filename = file.path.file_name().unwrap_or_default().to_string_lossy();
+            !indexed.contains(&(filename.to_string(), self.table_for_pair(&file.pair)))
+        });
+        let skipped = before - files.len();
+        tracing::info!(
+            "[{}] Skipping {} already indexed files, {} left to index",
+            self.name,
+            skipped,
+            files.len()
+        );
+        Ok(skipped)
+    }
+
+    /// Indexes zip files that are already on disk under `dir`, without touching S3
+    pub async fn index_local(&self, dir: &Path) -> Result<RunReport> {
+        self.create().await?;
+
+        let files = FileCollection::from_local_dir(dir).await?;
+        self.index_stream(&files).await
+    }
 
-        let downloader = Arc::clone(&self.downloader);
-        let pairs = downloader.get_pairs().await?;
-        let files = downloader.get_files(&pairs).await?;
-        let files_stream = files.download_stream(50);
+    async fn index_stream(&self, files: &FileCollection) -> Result<RunReport> {
+        let now = Instant::now();
+        let mut report = RunReport::new(&self.name, self.clock.now(), files.len());
+
+        // files already on disk are passed through without being downloaded
+        let mut files_stream = pin!(files.download_stream(self.download_concurrency));
 
+        // Workers live in a JoinSet so that dropping `index` aborts them instead of
+        // leaving detached tasks, and the semaphore bounds how many run concurrently.
         let self_clone = Arc::new(self.clone());
-        let stats = files_stream
-            .map(|file_result| {
-                let self_clone = Arc::clone(&self_clone);
-                tokio::spawn(async move {
-                    match file_result {
-                        Ok(file) => self_clone.index_file(file).await,
-                        Err(_) => Ok(AddableQuantities::default()),
-                    }
-                })
-            })
-            .buffer_unordered(10) // Process up to 10 tasks concurrently
-            .filter_map(|r| async { r.ok() })
-            .fold(AddableQuantities::default(), |mut acc, r| async move {
-                if let Ok(quantities) = r {
-                    acc += quantities;
+        let semaphore = self.index_semaphore();
+        let mut workers = JoinSet::new();
+        // pair and filename of each running worker, so failures can be attributed
+        let mut in_flight = HashMap::new();
+
+        // aborted with the JoinSet when `index_stream` returns
+        let mut signals = JoinSet::new();
+        if self.ctrl_c {
+            let shutdown = self.shutdown.clone();
+            let name = Arc::clone(&self.name);
+            signals.spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    tracing::warn!("[{}] Ctrl-C received, shutting down", name);
+                    shutdown.cancel();
                 }
-                acc
-            })
-            .await;
+            });
+        }
+
+        // biased, so no file is started once the shutdown is requested, even when a
+        // permit is free at the same time
+        loop {
+            let file_result = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                file_result = files_stream.next() => match file_result {
+                    Some(file_result) => file_result,
+                    None => break,
+                },
+            };
+            let permit = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                permit = Arc::clone(&semaphore).acquire_owned() => permit?,
+            };
+            while let Some(result) = workers.try_join_next_with_id() {
+                self.collect_worker(result, &mut in_flight, &mut report);
+            }
+
+            let file = match file_result {
+                Ok(file) => file,
+                Err(e) => {
+                    report.record_failure(None, None, e.to_string());
+                    continue;
+                }
+            };
+            let filename = file
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let pair = file.pair.to_string();
+            let self_clone = Arc::clone(&self_clone);
+            // spawned tasks don't inherit the span of `index` on their own
+            let handle = workers.spawn(
+                async move {
+                    let _permit = permit;
+                    self_clone.try_index_file(file).await
+                }
+                .in_current_span(),
+            );
+            in_flight.insert(handle.id(), (pair, filename));
+        }
+
+        if self.shutdown.is_cancelled() {
+            report.cancelled = true;
+            tracing::warn!(
+                "[{}] Shutdown requested, waiting for {} in-flight workers",
+                self.name,
+                workers.len()
+            );
+        }
+        while let Some(result) = workers.join_next_with_id().await {
+            self.collect_worker(result, &mut in_flight, &mut report);
+        }
+        // Every permit is back once all workers have released theirs
+        let _ = semaphore
+            .acquire_many(self.index_concurrency as u32)
+            .await?;
+        tracing::info!("[{}] All index workers stopped", self.name);
+
+        let summary = report.summary();
+        if summary.rows > 0 || summary.failed > 0 {
+            tracing::info!(
+                "[{}] Inserter summary: {} files, {} bytes, {} rows, {} transactions inserted; \
+                 {} files skipped, {} failed",
+                self.name,
+                summary.files,
+                summary.bytes,
+                summary.rows,
+                summary.transactions,
+                summary.skipped,
+                summary.failed,
+            );
+        }
+
+        if self.optimize != OptimizeMode::Off && report.stats.rows > 0 && !report.cancelled {
+            let tables: BTreeSet<String> =
+                files.iter().map(|f| self.table_for_pair(&f.pair)).collect();
+            for table in tables {
+                self.optimize_table(&table, self.optimize).await?;
+            }
+        }
 
-        if stats.rows > 0 {
-            log::info!(
-                "[{}] Inserter summary: {} files, {} bytes, {} rows, {} transactions inserted",
+        report.duration_secs = now.elapsed().as_secs_f64();
+        Ok(report)
+    }
+
+    /// Drops the trades of `pair` and its index log rows, then indexes the pair again from
+    /// the source. Returns None without touching anything if the pair was never indexed,
+    /// and fails without touching anything if the source has no files for it.
+    pub async fn reindex_pair(&self, pair: &str) -> Result<Option<RunReport>> {
+        self.create().await?;
+        let table = self.table_for_pair(pair);
+        let log = TradesIndexLogTable::from_client(self.client.clone(), &self.database);
+        if log.logged_files(pair, &table).await?.is_empty() {
+            tracing::warn!(
+                "[{}] {} was never indexed into {}, nothing to re-index",
                 self.name,
-                files.len(), // TODO: count is incorrect here as some files could have failed
-                stats.bytes,
-                stats.rows,
-                stats.transactions,
+                pair,
+                table
             );
+            return Ok(None);
+        }
+
+        // resolved before deleting, so a source which lost the pair cannot drop its data
+        let pairs: Vec<Pair> = self
+            .source
+            .list_pairs()
+            .await?
+            .into_iter()
+            .filter(|p| p.name.as_ref() == pair)
+            .collect();
+        if pairs.is_empty() {
+            return Err(anyhow!("{} is not listed by {}", pair, self.source.name()));
+        }
+        let files = self.source.list_files(&pairs).await?;
+        if files.is_empty() {
+            return Err(anyhow!("{} has no files in {}", pair, self.source.name()));
         }
+
+        tracing::warn!(
+            "[{}] Deleting the trades of {} from {}",
+            self.name,
+            pair,
+            table
+        );
+        self.delete_pair(&table, pair).await?;
+        log.delete_pair(pair, &table).await?;
+        self.index_stream(&files).await.map(Some)
+    }
+
+    async fn delete_pair(&self, table: &str, pair: &str) -> Result<()> {
+        // waits for the mutation, so it is done before the pair is inserted again
+        let client = self.client.clone().with_option("mutations_sync", "2");
+        let query = match &self.cluster {
+            Some(cluster) => client
+                .query("ALTER TABLE ? ON CLUSTER ? DELETE WHERE pair = ?")
+                .bind(sql::Identifier(&local_table(table)))
+                .bind(sql::Identifier(cluster)),
+            None => client
+                .query("ALTER TABLE ? DELETE WHERE pair = ?")
+                .bind(sql::Identifier(table)),
+        };
+        query
+            .bind(pair)
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Could not delete {} from {}: {}", pair, table, e))
+    }
+
+    /// Forces the merges of `table` so reads are deduplicated right away
+    pub async fn optimize_table(&self, table: &str, mode: OptimizeMode) -> Result<()> {
+        let final_clause = match mode {
+            OptimizeMode::Off => return Ok(()),
+            OptimizeMode::Merge => "",
+            OptimizeMode::Final => "FINAL",
+        };
+        tracing::warn!(
+            "[{}] Running OPTIMIZE {} on {}, this rewrites data and can take a long time",
+            self.name,
+            final_clause,
+            table
+        );
+
+        let now = Instant::now();
+        let query = match &self.cluster {
+            Some(cluster) => self
+                .client
+                .query(&format!("OPTIMIZE TABLE ? ON CLUSTER ? {final_clause}"))
+                .bind(sql::Identifier(&local_table(table)))
+                .bind(sql::Identifier(cluster)),
+            None => self
+                .client
+                .query(&format!("OPTIMIZE TABLE ? {final_clause}"))
+                .bind(sql::Identifier(table)),
+        };
+        query
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Could not optimize {}: {}", table, e))?;
+
+        tracing::info!(
+            "[{}] Optimized {} in: {:.2?}",
+            self.name,
+            table,
+            now.elapsed()
+        );
         Ok(())
     }
 
+    /// Makes a table created with the legacy `time` column readable by the current code.
+    ///
+    /// ClickHouse cannot `RENAME COLUMN` a column that is part of the sorting key, so instead
+    /// `dt` is added as an `ALIAS` of `time`: reads and `merged_stream` work as is. Inserts
+    /// still need the table to be migrated, e.g. by creating a new table with `create` and
+    /// copying the rows over with `INSERT INTO new SELECT time AS dt, * EXCEPT time FROM old`.
+    /// Returns whether the table had the legacy column.
+    pub async fn upgrade_legacy_dt_column(&self, table: &str) -> Result<bool> {
+        let legacy = self
+            .client
+            .query(
+                "
+                SELECT count() FROM system.columns
+                WHERE database = currentDatabase() AND table = ? AND name = ?
+                ",
+            )
+            .bind(table)
+            .bind(LEGACY_DT_COLUMN)
+            .fetch_one::<u64>()
+            .await
+            .map_err(|e| anyhow!("Could not describe {}: {}", table, e))?
+            > 0;
+        if !legacy {
+            return Ok(false);
+        }
+
+        tracing::warn!(
+            "[{}] {} uses the legacy `{}` column, adding `{}` as an alias. Inserts need a migrated table",
+            self.name,
+            table,
+            LEGACY_DT_COLUMN,
+            DT_COLUMN
+        );
+        self.client
+            .query(&format!(
+                "ALTER TABLE ? ADD COLUMN IF NOT EXISTS {DT_COLUMN} DateTime64(3, 'UTC') ALIAS {LEGACY_DT_COLUMN}"
+            ))
+            .bind(sql::Identifier(table))
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Could not upgrade {}: {}", table, e))?;
+        Ok(true)
+    }
+
+    /// Pre-flight for `index`: resolves pairs and files and estimates how much will be
+    /// downloaded and inserted, and how long it will take at `rows_per_sec`. Also checks
+    /// disk space in the data directory and ClickHouse connectivity. Nothing is downloaded.
+    pub async fn describe_run(&self, rows_per_sec: u64) -> Result<RunPlan> {
+        let pairs = self.source.list_pairs().await?;
+        let files = self.source.list_files(&pairs).await?;
+        let to_download = files.not_downloaded().await?;
+
+        let total_bytes = files.total_bytes();
+        let bytes_to_download = to_download.total_bytes();
+        let estimated_rows = total_bytes / ESTIMATED_ZIP_BYTES_PER_ROW;
+        let estimated_duration =
+            Duration::from_secs_f64(estimated_rows as f64 / rows_per_sec.max(1) as f64);
+        let available_disk_bytes = available_disk_bytes();
+        let clickhouse_error = self.check_clickhouse().await.err().map(|e| e.to_string());
+
+        let plan = RunPlan {
+            pairs: pairs.len(),
+            files: files.len(),
+            files_to_download: to_download.len(),
+            total_bytes,
+            bytes_to_download,
+            estimated_rows,
+            estimated_duration,
+            available_disk_bytes,
+            clickhouse_error,
+        };
+        tracing::info!("[{}] Run plan: {:?}", self.name, plan);
+        Ok(plan)
+    }
+
+    pub async fn check_clickhouse(&self) -> Result<()> {
+        self.client
+            .query("SELECT 1")
+            .fetch_one::<u8>()
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("ClickHouse is not reachable: {}", e))
+    }
+
+    /// Tables of the database with the rows and bytes on disk of their active parts, e.g.
+    /// to see what is indexed at a glance. Tables without parts, e.g. views, have 0 of both
+    pub async fn list_tables(&self) -> Result<Vec<TableInfo>> {
+        self.client
+            .query(
+                "
+                SELECT
+                    t.name AS name,
+                    sum(p.rows) AS rows,
+                    sum(p.bytes_on_disk) AS bytes
+                FROM system.tables AS t
+                LEFT JOIN (
+                    SELECT table, rows, bytes_on_disk FROM system.parts
+                    WHERE database = currentDatabase() AND active
+                ) AS p ON p.table = t.name
+                WHERE t.database = currentDatabase()
+                GROUP BY name
+                ORDER BY name
+                ",
+            )
+            .fetch_all::<TableInfo>()
+            .await
+            .map_err(|e| anyhow!("Could not list the tables of {}: {}", self.database, e))
+    }
+
+    fn collect_worker(
+        &self,
+        result: Result<(task::Id, Result<Option<AddableQuantities>>), JoinError>,
+        in_flight: &mut HashMap<task::Id, (String, String)>,
+        report: &mut RunReport,
+    ) {
+        let id = match &result {
+            Ok((id, _)) => *id,
+            Err(e) => e.id(),
+        };
+        let (pair, filename) = in_flight.remove(&id).unzip();
+        let (pair, filename) = (pair.as_deref(), filename.as_deref());
+        match result {
+            Ok((_, Ok(Some(quantities)))) => {
+                report.record_indexed(pair.unwrap_or_default(), quantities)
+            }
+            Ok((_, Ok(None))) => report.record_skipped(),
+            Ok((_, Err(e))) => {
+                tracing::error!("[{}] Could not index file: {}", self.name, e);
+                report.record_failure(pair, filename, e.to_string());
+            }
+            Err(e) if e.is_cancelled() => {
+                tracing::warn!("[{}] Index worker was aborted", self.name);
+                report.record_failure(pair, filename, "aborted".to_string());
+            }
+            Err(e) => {
+                tracing::error!("[{}] Index worker panicked: {}", self.name, e);
+                report.record_failure(pair, filename, format!("panicked: {}", e));
+            }
+        }
+    }
+
+    /// Indexes a single file. A file skipped because its ids are already indexed yields
+    /// empty quantities
     pub async fn index_file(&self, file: File) -> Result<AddableQuantities> {
-        // TODO: refactor
-        log::info!(
+        Ok(self.try_index_file(file).await?.unwrap_or_default())
+    }
+
+    /// Like `index_file`, but None when the file was skipped. Runs in an `index_file` span
+    /// with `pair` and `file` fields, so structured logs of concurrent workers can be told
+    /// apart
+    #[tracing::instrument(
+        name = "index_file",
+        skip_all,
+        fields(table = %self.name, pair = %file.pair, file = %file.path.display())
+    )]
+    async fn try_index_file(&self, file: File) -> Result<Option<AddableQuantities>> {
+        event_at!(
+            self.file_log_level,
             "[{}] Indexing pair={}; file={}",
             self.name,
             file.pair,
             file.path.to_string_lossy()
         );
 
+        let table = self.table_for_pair(&file.pair);
+        if self.skip_covered_ids && self.is_covered(&file, &table).await? {
+            return Ok(None);
+        }
+        if self.table_strategy == TableStrategy::PerPair {
+            self.create_named(&table).await?;
+        }
+
+        let now = Instant::now();
+        let mut progress = FileIndexProgress::default();
+        let in_flight = metrics::InFlight::start("index");
+        let result = self.insert_file(&file, &table, &mut progress).await;
+        drop(in_flight);
+        metrics::rows_inserted(progress.stats.rows);
+        if result.is_err() {
+            metrics::insert_error();
+        }
+
+        let status = match &result {
+            Ok(()) => {
+                event_at!(
+                    self.file_log_level,
+                    "[{}] Indexed in: {:.2?}; pair={}; file={}",
+                    self.name,
+                    now.elapsed(),
+                    file.pair,
+                    file.path.to_string_lossy()
+                );
+                IndexStatus::Complete
+            }
+            // Nothing has landed in the table, so there is nothing for the log to describe
+            Err(_) if progress.stats.rows == 0 => return result.map(|_| Some(progress.stats)),
+            Err(e) => {
+                tracing::error!(
+                    "[{}] Partially indexed {} rows; pair={}; file={}: {}",
+                    self.name,
+                    progress.stats.rows,
+                    file.pair,
+                    file.path.to_string_lossy(),
+                    e
+                );
+                IndexStatus::Partial
+            }
+        };
+
+        let index_log = TradesIndexLogTable::from_client(self.client.clone(), &self.database);
+        index_log
+            .index_row(self.index_log_row(&file, &table, &progress, status))
+            .await?;
+
+        result.map(|_| Some(progress.stats))
+    }
+
+    async fn records(
+        &self,
+        file: &File,
+    ) -> Result<(BoxStream<'static, DataResult<FileRow>>, SkippedRows)> {
+        if self.lenient_rows {
+            Ok(self.source.records_lenient(file, self.empty_fields).await?)
+        } else {
+            let records = self.source.records(file, self.empty_fields).await?;
+            Ok((records, SkippedRows::default()))
+        }
+    }
+
+    /// Whether the index log already has every id of `file` in `table`
+    async fn is_covered(&self, file: &File, table: &str) -> Result<bool> {
+        let mut bounds: Option<RowBounds> = None;
+        let mut rows = 0;
+        let (mut records, _) = self.records(file).await?;
+        while let Some(row) = records.next().await {
+            bounds.get_or_insert_with(RowBounds::default).extend(&row?);
+            rows += 1;
+        }
+        let Some(bounds) = bounds else {
+            return Ok(false);
+        };
+
+        let index_log = TradesIndexLogTable::from_client(self.client.clone(), &self.database);
+        let covered = index_log
+            .covers_ids(&file.pair, table, bounds.start_id, bounds.end_id, rows)
+            .await?;
+        if covered {
+            event_at!(
+                self.file_log_level,
+                "[{}] Skipping ids {}..={} already indexed; pair={}; file={}",
+                self.name,
+                bounds.start_id,
+                bounds.end_id,
+                file.pair,
+                file.path.to_string_lossy()
+            );
+        }
+        Ok(covered)
+    }
+
+    /// Streams the records of `file` into the table, keeping `progress` up to date with
+    /// what has been committed so far so that a failure can still be logged.
+    async fn insert_file(
+        &self,
+        file: &File,
+        table: &str,
+        progress: &mut FileIndexProgress,
+    ) -> Result<()> {
         // TODO: don't think we need inserter here -> it would be OK to use the regular
         // `client.insert("table_name")` inserter
         // https://github.com/ClickHouse/clickhouse-rs/tree/main?tab=readme-ov-file#insert-a-batch
-        let mut inserter = self
-            .client
-            .inserter::<TradesRow>(&self.name)?
-            .with_max_rows(500_000) // TODO: configurable int
-            .with_period(Some(Duration::from_secs(15)));
+        let mut inserter =
+            RetryingInserter::<TradesRow>::new(&self.endpoints, &file.pair, table, &self.inserter)?;
+        let (records, skipped) = self.records(file).await?;
+        let invalid = SkippedRows::default();
+        let records = match &self.validation {
+            Some(validation) => validation.apply_to_stream(records, invalid.clone()),
+            None => records,
+        };
+        let records = self.timestamps.apply_to_stream(
+            records,
+            self.clock.now().timestamp_millis() as u64,
+            file.path.to_string_lossy().into(),
+        );
 
-        let mut tx: u16 = 0;
-        let now = Instant::now();
-        let mut stats = AddableQuantities::default();
-        let mut records = file.records().await?;
+        // Whatever error or shutdown stops the writes, the INSERT is ended so the rows
+        // written so far land and the index log can describe them
+        let written = self
+            .write_rows(file, records, &mut inserter, progress)
+            .await;
+        match inserter.end().await {
+            Ok(stats) => {
+                progress.stats += stats;
+                progress.committed = progress.written;
+            }
+            Err(e) if written.is_ok() => return Err(e),
+            // the error which stopped the writes is the one worth reporting
+            Err(e) => tracing::error!(
+                "[{}] Could not end the insert of {}: {}",
+                self.name,
+                file.path.to_string_lossy(),
+                e
+            ),
+        }
+        progress.stats.skipped_rows = skipped.count();
+        progress.stats.invalid_rows = invalid.count();
+        written?;
+
+        if progress.stats.skipped_rows > 0 {
+            tracing::warn!(
+                "[{}] Skipped {} malformed rows; pair={}; file={}",
+                self.name,
+                progress.stats.skipped_rows,
+                file.pair,
+                file.path.to_string_lossy()
+            );
+        }
+        if progress.stats.invalid_rows > 0 {
+            tracing::warn!(
+                "[{}] Skipped {} invalid rows; pair={}; file={}",
+                self.name,
+                progress.stats.invalid_rows,
+                file.pair,
+                file.path.to_string_lossy()
+            );
+        }
 
-        let mut start_id: u32 = u32::MAX;
-        let mut end_id: u32 = 0;
-        let mut start_dt: u64 = u64::MAX;
-        let mut end_dt: u64 = 0;
+        Ok(())
+    }
 
+    /// Writes `records` to `inserter`, committing every `commit_rows` rows. The inserter is
+    /// not ended, `insert_file` does that whether this fails or not
+    async fn write_rows(
+        &self,
+        file: &File,
+        mut records: BoxStream<'static, DataResult<FileRow>>,
+        inserter: &mut RetryingInserter<TradesRow>,
+        progress: &mut FileIndexProgress,
+    ) -> Result<()> {
+        let mut tx: u64 = 0;
         while let Some(row) = records.next().await {
             let row = row?;
-            start_id = cmp::min(start_id, row.id);
-            end_id = cmp::max(end_id, row.id);
-            start_dt = cmp::min(start_dt, row.time);
-            end_dt = cmp::max(end_dt, row.time);
-            inserter.write(&TradesRow::new(&file.pair, row))?;
+            // only counted as written once the inserter took it
+            let mut written = progress.written;
+            written.extend(&row);
+            inserter.write(&TradesRow::new(&file.pair, row, &self.rounding))?;
+            progress.written = written;
             tx += 1;
 
-            // insert in batches of 8192 -> capsule size
-            // TODO: configurable int
-            if tx.rem_euclid(8192) == 0 {
+            if tx >= self.inserter.commit_rows {
                 let local_stats = inserter.commit().await?;
                 if local_stats.rows > 0 {
-                    log::debug!(
+                    tracing::debug!(
                         "[{}] [Commit] {} bytes, {} rows, {} transactions have been inserted",
                         self.name,
                         local_stats.bytes,
                         local_stats.rows,
                         local_stats.transactions,
                     );
+                    // an INSERT has ended ==> everything written so far has landed
+                    progress.committed = progress.written;
                 }
-                stats += local_stats;
+                progress.stats += local_stats;
                 tx = 0;
+
+                if self.shutdown.is_cancelled() {
+                    return Err(anyhow!(
+                        "Shutdown requested after {} rows",
+                        progress.stats.rows
+                    ));
+                }
             }
         }
-        stats += inserter.end().await?; // close the commit
-        log::info!(
-            "[{}] Indexed in: {:.2?}; pair={}; file={}",
-            self.name,
-            now.elapsed(),
-            file.pair,
-            file.path.to_string_lossy()
-        );
-
-        let index_log = TradesIndexLogTable::new(&self.database).await?;
-        index_log
-            .index_row(FileIndexLogRow {
-                filename: file
-                    .path
-                    .deref()
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .into(),
-                start_id,
-                end_id,
-                start_period_dt: start_dt,
-                end_period_dt: end_dt,
-                database: self.database.to_string(),
-                table: self.name.to_string(),
-                num_rows: stats.rows as u32,
-                index_dt: Utc::now().timestamp_millis() as u64,
-            })
-            .await?;
-
-        Ok(stats)
+        Ok(())
     }
 
-    pub async fn verify(&self) -> Result<()> {
-        // Should verify the table has valid data
-        // at the very least,
-        // the count of number of rows is equal
-        // to the sum of the highest pair (id + 1) for each pair (account for zero idx)
-        todo!("Implement verification");
+    fn index_log_row(
+        &self,
+        file: &File,
+        table: &str,
+        progress: &FileIndexProgress,
+        status: IndexStatus,
+    ) -> FileIndexLogRow {
+        FileIndexLogRow {
+            filename: file
+                .path
+                .deref()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into(),
+            start_id: progress.committed.start_id,
+            end_id: progress.committed.end_id,
+            start_period_dt: progress.committed.start_dt,
+            end_period_dt: progress.committed.end_dt,
+            database: self.database.to_string(),
+            table: table.to_string(),
+            num_rows: progress.stats.rows as u32,
+            index_dt: self.clock.now().timestamp_millis() as u64,
+            status,
+        }
     }
-}
+
+    /// Streams the trades of several pairs within `[start, end)` as a single time ordered
+    /// stream. Every table of the pairs, see `table_for_pair`, is sorted by ClickHouse and
+    /// the tables are merged as they stream in; ties on `dt` are broken by `(pair, id)` so
+    /// the output order is deterministic.
+    pub fn merged_stream(
+        &self,
+        pairs: &[&str],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> impl Stream<Item = Result<TradesRow>> {
+        let mut tables: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for pair in pairs {
+            tables
+                .entry(self.table_for_pair(pair))
+                .or_default()
+                .push(pair);
+        }
+        let streams = tables
+            .iter()
+            .map(|(table, pairs)| self.table_stream(table, pairs, start, end))
+            .collect();
+        merge_by_time(streams)
+    }
+
+    /// Trades of `pairs` in `table` within `[start, end)`, ordered by `(dt, pair, id)`
+    fn table_stream(
+        &self,
+        table: &str,
+        pairs: &[&str],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> BoxStream<'static, Result<TradesRow>> {
+        let table = table.to_string();
+        let cursor = self
+            .client
+            .query(&format!(
+                "
+                SELECT ?fields FROM ?
+                WHERE has(?, pair)
+                    AND {DT_COLUMN} >= fromUnixTimestamp64Milli(toInt64(?))
+                    AND {DT_COLUMN} < fromUnixTimestamp64Milli(toInt64(?))
+                ORDER BY {DT_COLUMN}, pair, id
+                "
+            ))
+            .bind(sql::Identifier(&table))
+            .bind(pairs)
+            .bind(start.timestamp_millis())
+            .bind(end.timestamp_millis())
+            .fetch::<TradesRow>()
+            .map_err(|e| anyhow!("Could not query {}: {}", table, e));
+
+        stream::once(async move { cursor })
+            .map_ok(|cursor| {
+                stream::try_unfold(cursor, |mut cursor| async move {
+                    Ok(cursor.next().await?.map(|row| (row, cursor)))
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Trades of `pair` during the month of `month`, any day of it will do. The bounds are
+    /// on the sorting key so ClickHouse only reads the parts of that month.
+    pub async fn trades_for_month(&self, pair: &str, month: NaiveDate) -> Result<Vec<TradesRow>> {
+        let (start, end) = month_bounds(month)?;
+        let table = self.table_for_pair(pair);
+        self.client
+            .query(&format!(
+                "
+                SELECT ?fields FROM ?
+                WHERE pair = ?
+                    AND {DT_COLUMN} >= fromUnixTimestamp64Milli(toInt64(?))
+                    AND {DT_COLUMN} < fromUnixTimestamp64Milli(toInt64(?))
+                ORDER BY {DT_COLUMN}, id
+                "
+            ))
+            .bind(sql::Identifier(&table))
+            .bind(pair)
+            .bind(start.timestamp_millis())
+            .bind(end.timestamp_millis())
+            .fetch_all::<TradesRow>()
+            .await
+            .map_err(|e| anyhow!("Could not query {}: {}", table, e))
+    }
+
+    /// Writes the trades of `pair` to a Parquet file at `path`, optionally only those in
+    /// `[start, end)`. Returns the number of rows written
+    pub async fn export_parquet(
+        &self,
+        pair: &str,
+        path: &Path,
+        range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    ) -> Result<u64> {
+        let table = self.table_for_pair(pair);
+        let range_filter = if range.is_some() {
+            format!(
+                "AND {DT_COLUMN} >= fromUnixTimestamp64Milli(toInt64(?)) \
+                 AND {DT_COLUMN} < fromUnixTimestamp64Milli(toInt64(?))"
+            )
+        } else {
+            String::new()
+        };
+        let mut query = self
+            .client
+            .query(&format!(
+                "SELECT ?fields FROM ? WHERE pair = ? {range_filter} ORDER BY {DT_COLUMN}, id"
+            ))
+            .bind(sql::Identifier(&table))
+            .bind(pair);
+        if let Some((start, end)) = range {
+            query = query
+                .bind(start.timestamp_millis())
+                .bind(end.timestamp_millis());
+        }
+        let mut cursor = query
+            .fetch::<TradesRow>()
+            .map_err(|e| anyhow!("Could not query {}: {}", table, e))?;
+
+        // the file is written on the blocking pool, the rows are fetched meanwhile
+        let (writer, written) = export::spawn_writer(path);
+        let fetched: Result<()> = async {
+            let mut batch = Vec::with_capacity(export::BATCH_ROWS);
+            while let Some(row) = cursor.next().await? {
+                batch.push(row);
+                if batch.len() >= export::BATCH_ROWS
+                    && writer.send(std::mem::take(&mut batch)).await.is_err()
+                {
+                    // the writer failed, which `written` tells
+                    return Ok(());
+                }
+            }
+            // fails like above when the writer failed
+            let _ = writer.send(batch).await;
+            Ok(())
+        }
+        .await;
+        drop(writer);
+        let rows = written.await??;
+        fetched?;
+        tracing::info!(
+            "[{}] Exported {} rows of {} to {}",
+            self.name,
+            rows,
+            pair,
+            path.display()
+        );
+        Ok(rows)
+    }
+
+    /// Compares the time range of `pair` actually in the trades table with the range the
+    /// index log claims to cover
+    pub async fn coverage(&self, pair: &str) -> Result<CoverageReport> {
+        let table = self.table_for_pair(pair);
+        let bounds = self
+            .client
+            .query(&format!(
+                "
+                SELECT
+                    count() AS rows,
+                    toUnixTimestamp64Milli(min({DT_COLUMN})) AS start_dt,
+                    toUnixTimestamp64Milli(max({DT_COLUMN})) AS end_dt
+                FROM ?
+                WHERE pair = ?
+                "
+            ))
+            .bind(sql::Identifier(&table))
+            .bind(pair)
+            .fetch_one::<PairBounds>()
+            .await
+            .map_err(|e| anyhow!("Could not query {}: {}", table, e))?;
+        let table_range = (bounds.rows > 0).then_some(CoverageRange {
+            start_dt: bounds.start_dt as u64,
+            end_dt: bounds.end_dt as u64,
+        });
+
+        let log = TradesIndexLogTable::from_client(self.client.clone(), &self.database);
+        let logged = log.files_for_pair(pair, &table).await?;
+        let log_range = logged
+            .iter()
+            .map(|row| CoverageRange {
+                start_dt: row.start_period_dt,
+                end_dt: row.end_period_dt,
+            })
+            .reduce(|a, b| CoverageRange {
+                start_dt: cmp::min(a.start_dt, b.start_dt),
+                end_dt: cmp::max(a.end_dt, b.end_dt),
+            });
+
+        let report = CoverageReport {
+            pair: pair.to_string(),
+            table: table_range,
+            log: log_range,
+            table_rows: bounds.rows,
+            logged_rows: logged.iter().map(|row| row.num_rows as u64).sum(),
+        };
+        if !report.is_consistent() {
+            tracing::warn!("[{}] Inconsistent coverage: {:?}", self.name, report);
+        }
+        Ok(report)
+    }
+
+    pub async fn verify(&self) -> Result<()> {
+        // Should verify the table has valid data
+        // at the very least,
+        // the count of number of rows is equal
+        // to the sum of the highest pair (id + 1) for each pair (account for zero idx)
+        todo!("Implement verification");
+    }
+}
+
+/// Rough compressed size of a trade in a Binance zip, used to estimate row counts
+const ESTIMATED_ZIP_BYTES_PER_ROW: u64 = 25;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunPlan {
+    pub pairs: usize,
+    pub files: usize,
+    /// Files which are not on disk yet
+    pub files_to_download: usize,
+    /// Size of all the files in bytes
+    pub total_bytes: u64,
+    /// Size of the files which are not on disk yet in bytes
+    pub bytes_to_download: u64,
+    pub estimated_rows: u64,
+    pub estimated_duration: Duration,
+    /// Free space where the data directory lives, None if it could not be determined
+    pub available_disk_bytes: Option<u64>,
+    /// None when ClickHouse answered
+    pub clickhouse_error: Option<String>,
+}
+
+impl RunPlan {
+    pub fn has_enough_disk(&self) -> bool {
+        self.available_disk_bytes
+            .is_none_or(|available| available >= self.bytes_to_download)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.has_enough_disk() && self.clickhouse_error.is_none()
+    }
+}
+
+fn available_disk_bytes() -> Option<u64> {
+    let config = config::Config::create().ok()?;
+    let data_dir = shellexpand::full(&config.data.dir).ok()?;
+    // the data dir may not exist yet, so look at its closest existing ancestor
+    Path::new(data_dir.as_ref())
+        .ancestors()
+        .find(|p| p.exists())
+        .and_then(|p| fs2::available_space(p).ok())
+}
+
+/// A table of the database, see `TradesTable::list_tables`
+#[derive(Debug, Clone, PartialEq, Eq, Row, Serialize, Deserialize)]
+pub struct TableInfo {
+    pub name: String,
+    pub rows: u64,
+    /// Compressed size on disk
+    pub bytes: u64,
+}
 
 #[derive(Debug, Row, Serialize, Deserialize)]
+struct PairBounds {
+    rows: u64,
+    start_dt: i64,
+    end_dt: i64,
+}
+
+/// Inclusive range in epoch time ms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoverageRange {
+    pub start_dt: u64,
+    pub end_dt: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub pair: String,
+    /// Range of the rows in the trades table, None when it has none
+    pub table: Option<CoverageRange>,
+    /// Range covered by the index log, None when nothing was logged
+    pub log: Option<CoverageRange>,
+    /// Rows in the trades table, may include duplicates which are not merged yet
+    pub table_rows: u64,
+    pub logged_rows: u64,
+}
+
+impl CoverageReport {
+    /// The log claims data the table does not have
+    pub fn is_missing_data(&self) -> bool {
+        self.log.is_some() && self.table.is_none()
+    }
+
+    /// The table has data no log row accounts for
+    pub fn is_missing_log(&self) -> bool {
+        self.table.is_some() && self.log.is_none()
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.table == self.log
+    }
+}
+
+/// `[start, end)` of the month `date` falls in, in UTC
+fn month_bounds(date: NaiveDate) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = date
+        .with_day(1)
+        .ok_or_else(|| anyhow!("Invalid date: {}", date))?;
+    let end = start
+        .checked_add_months(Months::new(1))
+        .ok_or_else(|| anyhow!("Month out of range: {}", date))?;
+    Ok((
+        start.and_time(NaiveTime::MIN).and_utc(),
+        end.and_time(NaiveTime::MIN).and_utc(),
+    ))
+}
+
+/// Merges streams which are each ordered by `(dt, pair, id)` into one ordered the same way.
+/// The first error ends the merged stream
+fn merge_by_time(
+    streams: Vec<BoxStream<'static, Result<TradesRow>>>,
+) -> impl Stream<Item = Result<TradesRow>> {
+    let heads: Vec<Option<TradesRow>> = vec![None; streams.len()];
+    let streams: Vec<_> = streams.into_iter().map(StreamExt::fuse).collect();
+    stream::try_unfold((streams, heads), |(mut streams, mut heads)| async move {
+        for (stream, head) in streams.iter_mut().zip(heads.iter_mut()) {
+            if head.is_none() {
+                *head = stream.try_next().await?;
+            }
+        }
+        let next = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| Some((i, head.as_ref()?)))
+            .min_by(|(_, a), (_, b)| (a.dt, &a.pair, a.id).cmp(&(b.dt, &b.pair, b.id)))
+            .map(|(i, _)| i);
+        Ok(next.map(|i| {
+            let row = heads[i].take().expect("picked from the heads");
+            (row, (streams, heads))
+        }))
+    })
+}
+
+/// Id and time bounds of the rows seen in a file
+#[derive(Debug, Clone, Copy)]
+struct RowBounds {
+    start_id: u32,
+    end_id: u32,
+    start_dt: u64,
+    end_dt: u64,
+}
+
+impl Default for RowBounds {
+    fn default() -> Self {
+        RowBounds {
+            start_id: u32::MAX,
+            end_id: 0,
+            start_dt: u64::MAX,
+            end_dt: 0,
+        }
+    }
+}
+
+impl RowBounds {
+    fn extend(&mut self, row: &FileRow) {
+        self.start_id = cmp::min(self.start_id, row.id);
+        self.end_id = cmp::max(self.end_id, row.id);
+        self.start_dt = cmp::min(self.start_dt, row.time);
+        self.end_dt = cmp::max(self.end_dt, row.time);
+    }
+}
+
+#[derive(Debug, Default)]
+struct FileIndexProgress {
+    /// Bounds of the rows written to the inserter
+    written: RowBounds,
+    /// Bounds of the rows that have actually been inserted into ClickHouse
+    committed: RowBounds,
+    stats: AddableQuantities,
+}
+
+#[derive(Debug, Clone, PartialEq, Row, Serialize, Deserialize)]
 pub struct TradesRow {
-    /// Trade time in unix epoch to ms
-    dt: u64,
-    /// Name of the pair traded
-    // Owned String is faster here than lifetime bound
-    pair: String,
-    /// Long=true; Short=False
-    side: bool,
+    /// Trade time in unix epoch to ms, stored in the `DT_COLUMN` column
+    pub dt: u64,
+    /// Name of the pair traded, shared by every row of a file. Serializes as a plain String
+    pub pair: Arc<str>,
+    /// Side of the taker, stored as Buy=true; Sell=false
+    pub side: Side,
     /// Execution price in DENOM
-    price: f32,
+    pub price: f32,
     /// Trade quantity in BASE
-    qty: f32,
+    pub qty: f32,
     /// Notional value; price * qty
-    notional: f32,
+    pub notional: f32,
     /// Trade id
-    id: u32,
+    pub id: u32,
 }
 
 impl TradesRow {
-    fn new(pair: &str, row: FileRow) -> Self {
+    fn new(pair: &Arc<str>, row: FileRow, rounding: &RoundingPolicy) -> Self {
         TradesRow {
             dt: row.time,
-            pair: pair.to_owned(),
-            side: !row.is_buyer_maker,
-            price: row.price,
-            qty: row.qty,
+            pair: Arc::clone(pair),
+            side: Side::from_buyer_maker(row.is_buyer_maker),
+            price: RoundingPolicy::apply(rounding.price, row.price),
+            qty: RoundingPolicy::apply(rounding.qty, row.qty),
             notional: row.quote_qty,
             id: row.id,
         }
     }
 }
+
+/// Side of the taker of a trade. Serializes as the bool of the `side Boolean` column,
+/// Buy=true; Sell=false
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    /// The taker sold into the bid when the buyer was the maker
+    pub fn from_buyer_maker(is_buyer_maker: bool) -> Self {
+        if is_buyer_maker {
+            Side::Sell
+        } else {
+            Side::Buy
+        }
+    }
+
+    pub fn is_buy(self) -> bool {
+        self == Side::Buy
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        })
+    }
+}
+
+impl Serialize for Side {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bool(self.is_buy())
+    }
+}
+
+impl<'de> Deserialize<'de> for Side {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match bool::deserialize(deserializer)? {
+            true => Side::Buy,
+            false => Side::Sell,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    /// Round to a number of decimal places
+    Decimals(u32),
+    /// Round to a multiple of a tick size, e.g. 0.01
+    Step(f64),
+}
+
+impl Rounding {
+    fn step(&self) -> f64 {
+        match *self {
+            Rounding::Decimals(decimals) => 10f64.powi(-(decimals as i32)),
+            Rounding::Step(step) => step,
+        }
+    }
+}
+
+/// Rounding applied to price and qty at ingest; nothing is rounded by default
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RoundingPolicy {
+    pub price: Option<Rounding>,
+    pub qty: Option<Rounding>,
+}
+
+impl RoundingPolicy {
+    /// Rounds `value` to the nearest step. A positive value never rounds down to zero,
+    /// it is clamped to the smallest step instead.
+    fn apply(rounding: Option<Rounding>, value: f32) -> f32 {
+        let Some(rounding) = rounding else {
+            return value;
+        };
+        let step = rounding.step();
+        let rounded = (value as f64 / step).round() * step;
+        if value > 0.0 && rounded <= 0.0 {
+            step as f32
+        } else {
+            rounded as f32
+        }
+    }
+}
+
+/// Earliest plausible trade time, 2017-01-01 in unix epoch ms; Binance opened in July 2017
+const MIN_TRADE_TIME_MS: u64 = 1_483_228_800_000;
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// What to do with trade times outside of 2017 to a day from now
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// Insert them as they are
+    #[default]
+    Keep,
+    /// Convert times in seconds, microseconds or nanoseconds to ms, failing the file when
+    /// no scale gives a plausible time
+    Rescale,
+    /// Fail the file
+    Error,
+}
+
+impl TimestampPolicy {
+    /// `time` in ms, None when no scale puts it between 2017 and a day after `now_ms`
+    fn rescale(time: u64, now_ms: u64) -> Option<u64> {
+        let plausible = MIN_TRADE_TIME_MS..=now_ms + DAY_MS;
+        [
+            Some(time),
+            time.checked_mul(1000),
+            Some(time / 1000),
+            Some(time / 1_000_000),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|time| plausible.contains(time))
+    }
+
+    fn apply(&self, mut row: FileRow, now_ms: u64) -> DataResult<FileRow> {
+        let plausible = (MIN_TRADE_TIME_MS..=now_ms + DAY_MS).contains(&row.time);
+        match self {
+            TimestampPolicy::Keep => Ok(row),
+            _ if plausible => Ok(row),
+            TimestampPolicy::Rescale => match TimestampPolicy::rescale(row.time, now_ms) {
+                Some(time) => {
+                    row.time = time;
+                    Ok(row)
+                }
+                None => Err(DataError::InvalidData(format!(
+                    "Row {} has an out of range time {} at any scale",
+                    row.id, row.time
+                ))),
+            },
+            TimestampPolicy::Error => Err(DataError::InvalidData(format!(
+                "Row {} has an out of range time {}, not in ms?",
+                row.id, row.time
+            ))),
+        }
+    }
+
+    /// Applies the policy to every row of `file`, warning once if its times are rescaled
+    fn apply_to_stream(
+        self,
+        rows: BoxStream<'static, DataResult<FileRow>>,
+        now_ms: u64,
+        file: Arc<str>,
+    ) -> BoxStream<'static, DataResult<FileRow>> {
+        if self == TimestampPolicy::Keep {
+            return rows;
+        }
+        let mut warned = false;
+        rows.and_then(move |row| {
+            let time = row.time;
+            let result = self.apply(row, now_ms);
+            if let Ok(row) = &result {
+                if row.time != time && !warned {
+                    tracing::warn!(
+                        "Rescaled trade time {} to {} ms in {}",
+                        time,
+                        row.time,
+                        file
+                    );
+                    warned = true;
+                }
+            }
+            futures::future::ready(result)
+        })
+        .boxed()
+    }
+}
+
+/// What `RowValidation` does with a row failing its checks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidRowAction {
+    /// Drop the row and count it in `AddableQuantities::invalid_rows`
+    #[default]
+    Skip,
+    /// Fail the file
+    Error,
+}
+
+/// Sanity checks of the rows at ingest: positive price and qty, quote_qty within a
+/// relative `tolerance` of price * qty and strictly increasing ids
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RowValidation {
+    pub tolerance: f32,
+    pub action: InvalidRowAction,
+}
+
+impl Default for RowValidation {
+    fn default() -> Self {
+        RowValidation {
+            // quote_qty is rounded to 8 decimals by Binance and both are f32 here
+            tolerance: 1e-3,
+            action: InvalidRowAction::default(),
+        }
+    }
+}
+
+impl RowValidation {
+    /// Why `row` is invalid, None if it passes. `last_id` is the id of the last valid row
+    fn violation(&self, row: &FileRow, last_id: Option<u32>) -> Option<String> {
+        if row.price.is_nan() || row.qty.is_nan() || row.price <= 0.0 || row.qty <= 0.0 {
+            return Some(format!(
+                "price {} and qty {} must be positive",
+                row.price, row.qty
+            ));
+        }
+        let notional = row.price as f64 * row.qty as f64;
+        if (row.quote_qty as f64 - notional).abs() > self.tolerance as f64 * notional {
+            return Some(format!(
+                "quote_qty {} is not price * qty = {}",
+                row.quote_qty, notional
+            ));
+        }
+        match last_id {
+            Some(last_id) if row.id <= last_id => {
+                Some(format!("id does not increase, it follows {}", last_id))
+            }
+            _ => None,
+        }
+    }
+
+    fn apply_to_stream(
+        self,
+        rows: BoxStream<'static, DataResult<FileRow>>,
+        invalid: SkippedRows,
+    ) -> BoxStream<'static, DataResult<FileRow>> {
+        let mut last_id = None;
+        rows.try_filter_map(move |row| {
+            let result = match self.violation(&row, last_id) {
+                None => {
+                    last_id = Some(row.id);
+                    Ok(Some(row))
+                }
+                Some(reason) if self.action == InvalidRowAction::Error => Err(
+                    DataError::InvalidData(format!("Row {} is invalid: {}", row.id, reason)),
+                ),
+                Some(reason) => {
+                    tracing::debug!("Skipping invalid row {}: {}", row.id, reason);
+                    invalid.increment();
+                    Ok(None)
+                }
+            };
+            futures::future::ready(result)
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::db::report::IndexSummary;
+    use crate::test_utils::log_row;
+    use crate::utils::clock::FixedClock;
+    use crate::{Asset, Cadence, DataType, Downloader};
+    use clickhouse::test::{handlers, status, Mock};
+    use futures::future::BoxFuture;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    fn mock_table(mock: &Mock) -> TradesTable {
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        let client = Client::default().with_url(mock.url());
+        TradesTable::from_client(client, "test", "trades", downloader)
+    }
+
+    /// The SQL sent by `query`, with its values bound. Its rows are always empty
+    async fn sent_query<F>(query: impl FnOnce(TradesTable) -> F) -> String
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        let (url, server) = crate::test_utils::serve_once();
+        let downloader =
+            Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades).unwrap();
+        let client = Client::default()
+            .with_url(url)
+            .with_compression(clickhouse::Compression::None);
+        query(TradesTable::from_client(
+            client, "test", "trades", downloader,
+        ))
+        .await;
+        server.join().unwrap().query()
+    }
+
+    fn trade(dt: u64, pair: &str, id: u32) -> TradesRow {
+        TradesRow {
+            dt,
+            pair: Arc::from(pair),
+            side: Side::Buy,
+            price: 1.0,
+            qty: 2.0,
+            notional: 2.0,
+            id,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_log_level() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        assert_eq!(table.file_log_level, log::Level::Info);
+        let table = table.with_file_log_level(log::Level::Debug);
+        assert_eq!(table.file_log_level, log::Level::Debug);
+    }
+
+    #[tokio::test]
+    async fn test_index_log_row() {
+        let mock = Mock::new();
+        let index_dt = Utc.with_ymd_and_hms(2024, 2, 1, 12, 0, 0).unwrap();
+        let table = mock_table(&mock).with_clock(FixedClock(index_dt));
+        let file = File::new(
+            "BTCUSDC",
+            "data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-2024-01.zip",
+            "data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-2024-01.zip.CHECKSUM",
+        )
+        .unwrap();
+        let bounds = RowBounds {
+            start_id: 1,
+            end_id: 3,
+            start_dt: 100,
+            end_dt: 300,
+        };
+        let progress = FileIndexProgress {
+            written: bounds,
+            committed: bounds,
+            stats: AddableQuantities {
+                rows: 3,
+                ..Default::default()
+            },
+        };
+
+        let row = table.index_log_row(&file, "TRADES", &progress, IndexStatus::Complete);
+        assert_eq!(
+            row,
+            FileIndexLogRow {
+                index_dt: index_dt.timestamp_millis() as u64,
+                ..log_row("BTCUSDC-trades-2024-01.zip", 100, 300)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_concurrency() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        assert_eq!(
+            table.index_semaphore().available_permits(),
+            DEFAULT_INDEX_CONCURRENCY
+        );
+
+        let table = table.with_index_concurrency(2).with_download_concurrency(3);
+        assert_eq!(table.index_semaphore().available_permits(), 2);
+        assert_eq!(table.download_concurrency, 3);
+    }
+
+    #[tokio::test]
+    async fn test_skip_indexed() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        let logged = |filename: &str, num_rows| FileIndexLogRow {
+            num_rows,
+            ..log_row(filename, 100, 300)
+        };
+        mock.add(handlers::provide(vec![
+            logged("BTCUSDC-trades-2024-01.zip", 3),
+            // Binance dropped a row from the id range, the file is complete all the same
+            logged("BTCUSDC-trades-2024-02.zip", 2),
+            FileIndexLogRow {
+                start_id: 0,
+                end_id: u32::MAX,
+                ..logged("BTCUSDC-trades-2024-03.zip", 5)
+            },
+        ]));
+
+        let key =
+            |month: &str| format!("data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-{month}.zip");
+        let mut files: FileCollection = ["2024-01", "2024-02", "2024-03", "2024-04"]
+            .iter()
+            .map(|month| {
+                File::new("BTCUSDC", &key(month), &format!("{}.CHECKSUM", key(month))).unwrap()
+            })
+            .collect();
+        table.skip_indexed(&mut files).await.unwrap();
+
+        let left: Vec<String> = files
+            .iter()
+            .map(|f| f.path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(left, vec!["BTCUSDC-trades-2024-04.zip"]);
+    }
+
+    /// Writes `<pair>-trades-2024-01.zip` with trades 1, 2 and 3
+    async fn write_trades_zip(dir: &Path, pair: &str) -> PathBuf {
+        let csv = "1,10.5,1.0,10.5,100,true,true\n\
+                   2,10.5,2.0,21.0,200,false,true\n\
+                   3,10.5,3.0,31.5,300,false,true\n";
+        write_csv_zip(dir, pair, csv).await
+    }
+
+    async fn write_csv_zip(dir: &Path, pair: &str, csv: &str) -> PathBuf {
+        let path = dir.join(format!("{pair}-trades-2024-01.zip"));
+        crate::test_utils::write_zip(&path, &[(&format!("{pair}-trades-2024-01.csv"), csv)]).await;
+        path
+    }
+
+    /// Lists the given files, whichever pair they are for
+    struct LocalSource(FileCollection);
+
+    impl TradeSource for LocalSource {
+        fn name(&self) -> &str {
+            "local"
+        }
+
+        fn list_pairs(&self) -> BoxFuture<'_, DataResult<Vec<Pair>>> {
+            let pairs = self.0.iter().map(|f| Pair::new("", &f.pair)).collect();
+            Box::pin(async move { Ok(pairs) })
+        }
+
+        fn list_files<'a>(
+            &'a self,
+            pairs: &'a [Pair],
+        ) -> BoxFuture<'a, DataResult<FileCollection>> {
+            let files = self
+                .0
+                .iter()
+                .filter(|f| pairs.iter().any(|p| p.name == f.pair))
+                .cloned()
+                .collect();
+            Box::pin(async move { Ok(files) })
+        }
+    }
+
+    /// Reads no rows from its files, but only once the gate lets it. The first file
+    /// started fails to be read
+    struct GatedSource {
+        files: LocalSource,
+        started: Arc<Mutex<Vec<String>>>,
+        gate: Arc<Semaphore>,
+    }
+
+    impl TradeSource for GatedSource {
+        fn name(&self) -> &str {
+            "gated"
+        }
+
+        fn list_pairs(&self) -> BoxFuture<'_, DataResult<Vec<Pair>>> {
+            self.files.list_pairs()
+        }
+
+        fn list_files<'a>(
+            &'a self,
+            pairs: &'a [Pair],
+        ) -> BoxFuture<'a, DataResult<FileCollection>> {
+            self.files.list_files(pairs)
+        }
+
+        fn records<'a>(
+            &'a self,
+            file: &'a File,
+            _: EmptyFieldPolicy,
+        ) -> BoxFuture<'a, DataResult<BoxStream<'static, DataResult<FileRow>>>> {
+            Box::pin(async move {
+                let first = {
+                    let mut started = self.started.lock().unwrap();
+                    started.push(file.pair.to_string());
+                    started.len() == 1
+                };
+                let _open = self.gate.acquire().await.unwrap();
+                if first {
+                    return Err(DataError::InvalidData("unreadable".to_string()));
+                }
+                Ok(stream::empty().boxed())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        for pair in ["BTCUSDC", "ETHUSDC", "SOLUSDC", "XRPUSDC"] {
+            files.push(File::from_path(
+                pair,
+                &write_trades_zip(dir.path(), pair).await,
+            ));
+        }
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let gate = Arc::new(Semaphore::new(0));
+        let source = GatedSource {
+            files: LocalSource(FileCollection::new(files)),
+            started: Arc::clone(&started),
+            gate: Arc::clone(&gate),
+        };
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", source)
+            .with_ctrl_c(false)
+            .with_incremental(false)
+            .with_index_concurrency(3);
+
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        // two of the three started files are logged, the first one fails
+        let logged = mock.add(handlers::record::<FileIndexLogRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+
+        let shutdown = table.shutdown_token();
+        let cancel = async {
+            while started.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            shutdown.cancel();
+            gate.add_permits(3);
+        };
+        let (report, ()) = tokio::time::timeout(
+            Duration::from_secs(10),
+            futures::future::join(table.index(), cancel),
+        )
+        .await
+        .expect("index returns once the in-flight files are done");
+        let report = report.unwrap();
+
+        assert!(report.cancelled);
+        // every started file was committed or recorded as a failure, no other was started
+        let started = started.lock().unwrap().clone();
+        assert_eq!(started.len(), 3);
+        assert_eq!(report.files_indexed + report.failures.len(), 3);
+        assert_eq!(report.files_indexed, 2);
+        assert_eq!(
+            report.failures[0].pair.as_deref(),
+            Some(started[0].as_str())
+        );
+        let log: Vec<FileIndexLogRow> = logged.collect().await;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].status, IndexStatus::Complete);
+    }
+
+    #[tokio::test]
+    async fn test_reindex_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = FileCollection::new(vec![
+            File::from_path("BTCUSDC", &write_trades_zip(dir.path(), "BTCUSDC").await),
+            File::from_path("ETHUSDC", &write_trades_zip(dir.path(), "ETHUSDC").await),
+        ]);
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", LocalSource(files))
+            .with_ctrl_c(false);
+
+        let logged = log_row("BTCUSDC-trades-2024-01.zip", 100, 300);
+        // the table and the index log
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::provide(vec![logged.clone()]));
+        let delete_trades = mock.add(handlers::record_ddl());
+        let delete_log = mock.add(handlers::record_ddl());
+        let inserted = mock.add(handlers::record::<TradesRow>());
+        let relogged = mock.add(handlers::record::<FileIndexLogRow>());
+
+        let report = table.reindex_pair("BTCUSDC").await.unwrap().unwrap();
+        assert_eq!(report.files_indexed, 1);
+        assert_eq!(
+            delete_trades.query().await,
+            "ALTER TABLE `TRADES` DELETE WHERE pair = 'BTCUSDC'"
+        );
+        assert!(delete_log
+            .query()
+            .await
+            .contains("startsWith(filename, 'BTCUSDC-')"));
+
+        let rows: Vec<TradesRow> = inserted.collect().await;
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|row| row.pair.as_ref() == "BTCUSDC"));
+        let log: Vec<FileIndexLogRow> = relogged.collect().await;
+        assert_eq!(log[0].filename, logged.filename);
+
+        // never indexed
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::provide(Vec::<FileIndexLogRow>::new()));
+        assert!(table.reindex_pair("ETHUSDC").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reindex_unlisted_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = FileCollection::new(vec![File::from_path(
+            "ETHUSDC",
+            &write_trades_zip(dir.path(), "ETHUSDC").await,
+        )]);
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", LocalSource(files))
+            .with_ctrl_c(false);
+
+        let logged = log_row("BTCUSDC-trades-2024-01.zip", 100, 300);
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::provide(vec![logged]));
+
+        // an ALTER TABLE ... DELETE would be a request without a handler, which fails the mock
+        let error = table.reindex_pair("BTCUSDC").await.unwrap_err();
+        assert!(error.to_string().contains("is not listed"));
+    }
+
+    #[tokio::test]
+    async fn test_index_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let corrupt = dir.path().join("SOLUSDC-trades-2024-01.zip");
+        std::fs::write(&corrupt, b"not a zip").unwrap();
+        let files = FileCollection::new(vec![
+            File::from_path("BTCUSDC", &write_trades_zip(dir.path(), "BTCUSDC").await),
+            File::from_path("ETHUSDC", &write_trades_zip(dir.path(), "ETHUSDC").await),
+            File::from_path("SOLUSDC", &corrupt),
+        ]);
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", LocalSource(files))
+            .with_ctrl_c(false)
+            .with_index_concurrency(1);
+
+        // the table and the index log
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        // BTCUSDC is already indexed
+        mock.add(handlers::provide(vec![log_row(
+            "BTCUSDC-trades-2024-01.zip",
+            100,
+            300,
+        )]));
+        // the corrupt zip fails before anything is inserted
+        mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+
+        let report = table.index().await.unwrap();
+        let summary = report.summary();
+        assert_eq!(summary.files, 1);
+        assert_eq!(summary.rows, 3);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(
+            report.failures[0].file.as_deref(),
+            Some("SOLUSDC-trades-2024-01.zip")
+        );
+    }
+
+    #[derive(Default)]
+    struct FakeNotifier {
+        completed: Mutex<Vec<IndexSummary>>,
+        errors: Mutex<Vec<Option<String>>>,
+    }
+
+    impl Notifier for Arc<FakeNotifier> {
+        fn on_complete<'a>(&'a self, summary: &'a IndexSummary) -> BoxFuture<'a, Result<()>> {
+            self.completed.lock().unwrap().push(*summary);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn on_error<'a>(
+            &'a self,
+            pair: Option<&'a str>,
+            _error: &'a str,
+        ) -> BoxFuture<'a, Result<()>> {
+            self.errors.lock().unwrap().push(pair.map(str::to_string));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notifier() {
+        let dir = tempfile::tempdir().unwrap();
+        let corrupt = dir.path().join("SOLUSDC-trades-2024-01.zip");
+        std::fs::write(&corrupt, b"not a zip").unwrap();
+        let files = FileCollection::new(vec![
+            File::from_path("BTCUSDC", &write_trades_zip(dir.path(), "BTCUSDC").await),
+            File::from_path("SOLUSDC", &corrupt),
+        ]);
+        let notifier = Arc::new(FakeNotifier::default());
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", LocalSource(files))
+            .with_ctrl_c(false)
+            .with_incremental(false)
+            .with_index_concurrency(1)
+            .with_notifier(Arc::clone(&notifier));
+
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        table.index().await.unwrap();
+
+        let completed = notifier.completed.lock().unwrap();
+        assert_eq!(completed.len(), 1);
+        assert_eq!((completed[0].files, completed[0].rows), (1, 3));
+        assert_eq!(completed[0].failed, 1);
+        assert_eq!(
+            *notifier.errors.lock().unwrap(),
+            vec![Some("SOLUSDC".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skip_covered_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::from_path("BTCUSDC", &write_trades_zip(dir.path(), "BTCUSDC").await);
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(
+            client,
+            "test",
+            "trades",
+            LocalSource(FileCollection::new(vec![])),
+        )
+        .with_skip_covered_ids(true);
+
+        // a logged file with ids 1..=5 covers the ids 1..=3 of this one
+        mock.add(handlers::provide(vec![1u64]));
+        // nothing inserted, there is no handler for it
+        let stats = table.index_file(file.clone()).await.unwrap();
+        assert_eq!(stats.rows, 0);
+
+        mock.add(handlers::provide(vec![0u64]));
+        let inserted = mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        let stats = table.index_file(file).await.unwrap();
+        assert_eq!(stats.rows, 3);
+        assert_eq!(inserted.collect::<Vec<TradesRow>>().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_lenient_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = "1,10.5,1.0,10.5,100,true,true\n\
+                   2,10.5,not a qty,21.0,200,false,true\n\
+                   3,10.5,3.0,31.5,300,false,true\n";
+        let file = File::from_path("BTCUSDC", &write_csv_zip(dir.path(), "BTCUSDC", csv).await);
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        // the row before the malformed one is still inserted
+        mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        assert!(table.index_file(file.clone()).await.is_err());
+
+        let table = table.with_lenient_rows(true);
+        let inserted = mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        let stats = table.index_file(file).await.unwrap();
+        assert_eq!(stats.rows, 2);
+        assert_eq!(stats.skipped_rows, 1);
+        let ids: Vec<u32> = inserted
+            .collect::<Vec<TradesRow>>()
+            .await
+            .iter()
+            .map(|row| row.id)
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_row_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        // row 2 has a quote_qty far off price * qty
+        let csv = "1,10.5,1.0,10.5,100,true,true\n\
+                   2,10.5,2.0,99.0,200,false,true\n\
+                   3,10.5,3.0,31.5,300,false,true\n";
+        let file = File::from_path("BTCUSDC", &write_csv_zip(dir.path(), "BTCUSDC", csv).await);
+        let mock = Mock::new();
+        let table = mock_table(&mock).with_row_validation(Some(RowValidation::default()));
+        let inserted = mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        let stats = table.index_file(file.clone()).await.unwrap();
+        assert_eq!(stats.rows, 2);
+        assert_eq!(stats.invalid_rows, 1);
+        let ids: Vec<u32> = inserted
+            .collect::<Vec<TradesRow>>()
+            .await
+            .iter()
+            .map(|row| row.id)
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        let table = table.with_row_validation(Some(RowValidation {
+            action: InvalidRowAction::Error,
+            ..Default::default()
+        }));
+        mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        let error = table.index_file(file).await.unwrap_err();
+        assert!(error.to_string().contains("Row 2 is invalid"));
+    }
+
+    #[test]
+    fn test_timestamp_policy() {
+        // 2024-01-01
+        let now_ms = 1_704_067_200_000;
+        let row = |time| FileRow {
+            time,
+            ..file_row(10.0, 1.0)
+        };
+        let seconds = row(now_ms / 1000);
+        assert!(TimestampPolicy::Error.apply(seconds, now_ms).is_err());
+        for time in [now_ms / 1000, now_ms, now_ms * 1000, now_ms * 1_000_000] {
+            let rescaled = TimestampPolicy::Rescale.apply(row(time), now_ms).unwrap();
+            assert_eq!(rescaled.time, now_ms);
+        }
+        // plausible at no scale
+        assert!(TimestampPolicy::Rescale.apply(row(100), now_ms).is_err());
+        assert_eq!(
+            TimestampPolicy::Keep.apply(row(100), now_ms).unwrap().time,
+            100
+        );
+        // a day ahead is still fine, clocks of mirrors drift
+        assert!(TimestampPolicy::Error
+            .apply(row(now_ms + DAY_MS), now_ms)
+            .is_ok());
+        assert!(TimestampPolicy::Error
+            .apply(row(now_ms + DAY_MS + 1), now_ms)
+            .is_err());
+    }
+
+    #[test]
+    fn test_row_violations() {
+        let validation = RowValidation::default();
+        assert!(validation.violation(&file_row(10.0, 2.0), None).is_none());
+        assert!(validation.violation(&file_row(0.0, 2.0), None).is_some());
+        assert!(validation
+            .violation(&file_row(10.0, f32::NAN), None)
+            .is_some());
+        // ids must increase
+        assert!(validation
+            .violation(&file_row(10.0, 2.0), Some(1))
+            .is_some());
+        assert!(validation
+            .violation(&file_row(10.0, 2.0), Some(0))
+            .is_none());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_index_metrics() {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let _guard = ::metrics::set_default_local_recorder(&recorder);
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::from_path("BTCUSDC", &write_trades_zip(dir.path(), "BTCUSDC").await);
+        let mock = Mock::new();
+        mock.add(handlers::record::<TradesRow>());
+        mock.add(handlers::record::<FileIndexLogRow>());
+        mock_table(&mock).index_file(file.clone()).await.unwrap();
+
+        // the first INSERT and every time it is sent again
+        let table = mock_table(&mock);
+        for _ in 0..table.endpoints.retry().max_attempts {
+            mock.add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        }
+        assert!(table.index_file(file).await.is_err());
+
+        let rendered = handle.render();
+        assert!(rendered.contains(&format!("{} 3", metrics::ROWS_INSERTED)));
+        assert!(rendered.contains(&format!("{} 1", metrics::INSERT_ERRORS)));
+        assert!(rendered.contains(&format!("{}{{task=\"index\"}} 0", metrics::TASKS_IN_FLIGHT)));
+    }
+
+    #[tokio::test]
+    async fn test_error_mid_file_logs_written_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = "1,10.5,1.0,10.5,100,true,true\n\
+                   2,10.5,2.0,21.0,200,false,true\n\
+                   3,10.5,not a qty,31.5,300,false,true\n\
+                   4,10.5,4.0,42.0,400,false,true\n";
+        let file = File::from_path("BTCUSDC", &write_csv_zip(dir.path(), "BTCUSDC", csv).await);
+
+        let mock = Mock::new();
+        // nothing is committed before the error, rows 1 and 2 are still buffered
+        let inserted = mock.add(handlers::record::<TradesRow>());
+        let logged = mock.add(handlers::record::<FileIndexLogRow>());
+
+        let result = mock_table(&mock).index_file(file).await;
+        assert!(result.unwrap_err().to_string().contains("CSV"));
+
+        let ids: Vec<u32> = inserted
+            .collect::<Vec<TradesRow>>()
+            .await
+            .iter()
+            .map(|row| row.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        let log: Vec<FileIndexLogRow> = logged.collect().await;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].status, IndexStatus::Partial);
+        assert_eq!((log[0].start_id, log[0].end_id, log[0].num_rows), (1, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_logs_committed_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_trades_zip(dir.path(), "BTCUSDC").await;
+
+        let mock = Mock::new();
+        let table = mock_table(&mock).with_inserter_config(InserterConfig {
+            max_rows: 1,
+            period_secs: 60,
+            commit_rows: 1,
+        });
+        // cancelled mid-file: the first commit lands, then the worker stops
+        table.shutdown_token().cancel();
+        let inserted = mock.add(handlers::record::<TradesRow>());
+        let logged = mock.add(handlers::record::<FileIndexLogRow>());
+
+        let result = table.index_file(File::from_path("BTCUSDC", &path)).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Shutdown requested"));
+
+        let rows: Vec<TradesRow> = inserted.collect().await;
+        assert_eq!(rows.len(), 1);
+        let log: Vec<FileIndexLogRow> = logged.collect().await;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].status, IndexStatus::Partial);
+        assert_eq!((log[0].start_id, log[0].end_id, log[0].num_rows), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_failed_commit_is_sent_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_trades_zip(dir.path(), "BTCUSDC").await;
+
+        let mock = Mock::new();
+        let table = mock_table(&mock).with_inserter_config(InserterConfig {
+            max_rows: 2,
+            period_secs: 60,
+            commit_rows: 1,
+        });
+        // the INSERT of rows 1 and 2 hits a blip, then goes through
+        mock.add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        let resent = mock.add(handlers::record::<TradesRow>());
+        let last = mock.add(handlers::record::<TradesRow>());
+        let logged = mock.add(handlers::record::<FileIndexLogRow>());
+
+        let stats = table
+            .index_file(File::from_path("BTCUSDC", &path))
+            .await
+            .unwrap();
+        assert_eq!(stats.rows, 3);
+
+        let ids = |rows: Vec<TradesRow>| rows.iter().map(|row| row.id).collect::<Vec<_>>();
+        assert_eq!(ids(resent.collect().await), vec![1, 2]);
+        assert_eq!(ids(last.collect().await), vec![3]);
+        let log: Vec<FileIndexLogRow> = logged.collect().await;
+        assert_eq!(log[0].status, IndexStatus::Complete);
+        assert_eq!((log[0].start_id, log[0].end_id, log[0].num_rows), (1, 3, 3));
+    }
+
+    fn file_row(price: f32, qty: f32) -> FileRow {
+        FileRow {
+            id: 1,
+            price,
+            qty,
+            quote_qty: price * qty,
+            time: 1,
+            is_buyer_maker: true,
+            is_best_match: true,
+        }
+    }
+
+    #[test]
+    fn test_side() {
+        let row = TradesRow::new(
+            &Arc::from("BTCUSDC"),
+            file_row(1.0, 1.0),
+            &Default::default(),
+        );
+        // file_row has the buyer as the maker
+        assert_eq!(row.side, Side::Sell);
+        assert_eq!(row.side.to_string(), "sell");
+        assert_eq!(Side::from_buyer_maker(false), Side::Buy);
+
+        // the same bytes as the bool column had
+        let json = serde_json::to_string(&row).unwrap();
+        assert!(json.contains(r#""side":false"#));
+        assert_eq!(serde_json::from_str::<TradesRow>(&json).unwrap(), row);
+    }
+
+    #[test]
+    fn test_rounding() {
+        let rounding = RoundingPolicy {
+            price: Some(Rounding::Step(0.5)),
+            qty: Some(Rounding::Decimals(2)),
+        };
+        let row = TradesRow::new(&Arc::from("BTCUSDC"), file_row(100.26, 1.23456), &rounding);
+        assert_eq!(row.price, 100.5);
+        assert_eq!(row.qty, 1.23);
+
+        let row = TradesRow::new(
+            &Arc::from("BTCUSDC"),
+            file_row(100.26, 1.23456),
+            &Default::default(),
+        );
+        assert_eq!(row.price, 100.26);
+        assert_eq!(row.qty, 1.23456);
+    }
+
+    #[tokio::test]
+    async fn test_rows_share_pair() {
+        let pair: Arc<str> = Arc::from("BTCUSDC");
+        let rows: Vec<TradesRow> = (0..3)
+            .map(|_| TradesRow::new(&pair, file_row(1.0, 1.0), &Default::default()))
+            .collect();
+        assert!(rows.iter().all(|row| Arc::ptr_eq(&row.pair, &pair)));
+
+        // RowBinary is the same as for an owned String
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let recording = mock.add(handlers::record());
+        let mut insert = client.insert("TRADES").unwrap();
+        for row in &rows {
+            insert.write(row).await.unwrap();
+        }
+        insert.end().await.unwrap();
+        let recorded: Vec<TradesRow> = recording.collect().await;
+        assert_eq!(recorded, rows);
+    }
+
+    #[test]
+    fn test_rounding_never_zeroes_qty() {
+        let rounding = RoundingPolicy {
+            price: None,
+            qty: Some(Rounding::Decimals(2)),
+        };
+        let row = TradesRow::new(&Arc::from("BTCUSDC"), file_row(100.0, 0.001), &rounding);
+        assert_eq!(row.qty, 0.01);
+        let row = TradesRow::new(&Arc::from("BTCUSDC"), file_row(100.0, 0.0), &rounding);
+        assert_eq!(row.qty, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_check_clickhouse() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        mock.add(handlers::provide(vec![1u8]));
+        assert!(table.check_clickhouse().await.is_ok());
+
+        mock.add(handlers::failure(
+            clickhouse::test::status::SERVICE_UNAVAILABLE,
+        ));
+        assert!(table.check_clickhouse().await.is_err());
+
+        let client = Client::default().with_url("http://127.0.0.1:1");
+        let table = TradesTable::from_client(
+            client,
+            "test",
+            "trades",
+            LocalSource(FileCollection::new(vec![])),
+        );
+        let result = tokio::time::timeout(Duration::from_secs(10), table.check_clickhouse())
+            .await
+            .expect("check should fail promptly");
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("ClickHouse is not reachable"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_legacy_dt_column() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        mock.add(handlers::provide(vec![0u64]));
+        assert!(!table.upgrade_legacy_dt_column("TRADES").await.unwrap());
+
+        mock.add(handlers::provide(vec![1u64]));
+        let ddl = mock.add(handlers::record_ddl());
+        assert!(table.upgrade_legacy_dt_column("TRADES").await.unwrap());
+        assert_eq!(
+            ddl.query().await,
+            "ALTER TABLE `TRADES` ADD COLUMN IF NOT EXISTS dt DateTime64(3, 'UTC') ALIAS time"
+        );
+    }
+
+    #[test]
+    fn test_run_plan_readiness() {
+        let plan = RunPlan {
+            pairs: 1,
+            files: 2,
+            files_to_download: 1,
+            total_bytes: 200,
+            bytes_to_download: 100,
+            estimated_rows: 8,
+            estimated_duration: Duration::from_secs(1),
+            available_disk_bytes: Some(50),
+            clickhouse_error: None,
+        };
+        assert!(!plan.has_enough_disk());
+        assert!(!plan.is_ready());
+
+        let plan = RunPlan {
+            available_disk_bytes: None,
+            ..plan
+        };
+        assert!(plan.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_create_on_cluster() {
+        let mock = Mock::new();
+        let table = mock_table(&mock).with_cluster("analytics");
+        let local_ddl = mock.add(handlers::record_ddl());
+        let distributed_ddl = mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+
+        table.create().await.unwrap();
+        let local_ddl = local_ddl.query().await;
+        assert!(
+            local_ddl.contains("CREATE TABLE IF NOT EXISTS `TRADES_LOCAL` ON CLUSTER `analytics`")
+        );
+        assert!(local_ddl.contains("ENGINE = ReplacingMergeTree"));
+        let distributed_ddl = distributed_ddl.query().await;
+        assert!(distributed_ddl.contains(
+            "CREATE TABLE IF NOT EXISTS `TRADES` ON CLUSTER `analytics` AS `TRADES_LOCAL`"
+        ));
+        assert!(distributed_ddl.contains(
+            "Distributed(`analytics`, currentDatabase(), `TRADES_LOCAL`, cityHash64(pair))"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_with_schema() {
+        let mock = Mock::new();
+        let ddl = mock.add(handlers::record_ddl());
+        let log_ddl = mock.add(handlers::record_ddl());
+        let log_migration = mock.add(handlers::record_ddl());
+        mock_table(&mock).create().await.unwrap();
+        assert!(log_ddl
+            .query()
+            .await
+            .contains("CREATE TABLE IF NOT EXISTS `TRADES_INDEX_LOG`"));
+        assert!(log_migration
+            .query()
+            .await
+            .contains("ADD COLUMN IF NOT EXISTS"));
+        let ddl = ddl.query().await;
+        assert!(ddl.contains("ENGINE = ReplacingMergeTree"));
+        assert!(ddl.contains("ORDER BY (dt, id, pair)"));
+        assert!(!ddl.contains("PARTITION BY"));
+
+        let table = mock_table(&mock).with_schema(TableSchema {
+            partition_by: Some("toYYYYMM(dt)".to_string()),
+            order_by: "(pair, dt, id)".to_string(),
+            ..Default::default()
+        });
+        let ddl = mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        table.create().await.unwrap();
+        let ddl = ddl.query().await;
+        assert!(ddl.contains("PARTITION BY toYYYYMM(dt)"));
+        assert!(ddl.contains("PRIMARY KEY (pair, dt, id)"));
+        assert!(ddl.contains("ORDER BY (pair, dt, id)"));
+    }
+
+    #[tokio::test]
+    async fn test_table_for_pair() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        assert_eq!(table.table_for_pair("BTCUSDT"), "TRADES");
+
+        let table = table.with_table_strategy(TableStrategy::PerPair);
+        assert_eq!(table.table_for_pair("BTCUSDT"), "TRADES_BTCUSDT");
+        assert_eq!(
+            table.table_for_pair("1000sats-usdt"),
+            "TRADES_1000SATS_USDT"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_without_pairs() {
+        use crate::data::binance::store::MemoryStore;
+
+        let store = MemoryStore::new().with_object(
+            "data/spot/monthly/trades/BTCUSDC/BTCUSDC-trades-2024-01.zip",
+            "zip",
+        );
+        let downloader = Downloader::new("test", Asset::Spot, Cadence::Monthly, DataType::Trades)
+            .unwrap()
+            .with_object_store(Arc::new(store))
+            .with_pair_starts_with(&["DOGE"]);
+        // no handlers, nothing may reach ClickHouse
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", downloader);
+        let report = table.index().await.unwrap();
+        assert_eq!(report.files, 0);
+        assert_eq!(report.summary(), IndexSummary::default());
+        assert!(report.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_per_pair_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new();
+        let table = mock_table(&mock).with_table_strategy(TableStrategy::PerPair);
+        for pair in ["BTCUSDC", "ETHUSDC"] {
+            let file = File::from_path(pair, &write_trades_zip(dir.path(), pair).await);
+            let created = mock.add(handlers::record_ddl());
+            let inserted = mock.add(handlers::record::<TradesRow>());
+            let logged = mock.add(handlers::record::<FileIndexLogRow>());
+            table.index_file(file).await.unwrap();
+
+            let expected = format!("TRADES_{pair}");
+            assert!(created.query().await.contains(&format!("`{expected}`")));
+            let rows: Vec<TradesRow> = inserted.collect().await;
+            assert!(rows.iter().all(|row| row.pair.as_ref() == pair));
+            let log: Vec<FileIndexLogRow> = logged.collect().await;
+            assert_eq!(log[0].table, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_optimize_table() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        let ddl = mock.add(handlers::record_ddl());
+        table
+            .optimize_table("TRADES", OptimizeMode::Final)
+            .await
+            .unwrap();
+        assert_eq!(ddl.query().await.trim(), "OPTIMIZE TABLE `TRADES` FINAL");
+
+        let table = table.with_cluster("analytics");
+        let ddl = mock.add(handlers::record_ddl());
+        table
+            .optimize_table("TRADES", OptimizeMode::Merge)
+            .await
+            .unwrap();
+        assert_eq!(
+            ddl.query().await.trim(),
+            "OPTIMIZE TABLE `TRADES_LOCAL` ON CLUSTER `analytics`"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coverage() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        mock.add(handlers::provide(vec![PairBounds {
+            rows: 0,
+            start_dt: 0,
+            end_dt: 0,
+        }]));
+        let logged = log_row("BTCUSDC-trades-2024-01.zip", 100, 300);
+        mock.add(handlers::provide(vec![logged]));
+
+        let report = table.coverage("BTCUSDC").await.unwrap();
+        assert_eq!(report.table, None);
+        assert_eq!(
+            report.log,
+            Some(CoverageRange {
+                start_dt: 100,
+                end_dt: 300
+            })
+        );
+        assert_eq!(report.logged_rows, 3);
+        assert!(report.is_missing_data());
+        assert!(!report.is_consistent());
+    }
+
+    #[tokio::test]
+    async fn test_list_tables() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        let tables = vec![
+            TableInfo {
+                name: "TRADES".to_string(),
+                rows: 3,
+                bytes: 120,
+            },
+            TableInfo {
+                name: "TRADES_INDEX_LOG".to_string(),
+                rows: 1,
+                bytes: 40,
+            },
+        ];
+        mock.add(handlers::provide(tables.clone()));
+
+        let listed = table.list_tables().await.unwrap();
+        assert_eq!(listed, tables);
+        let trades = listed.iter().find(|t| t.name == "TRADES").unwrap();
+        assert!(trades.rows > 0);
+
+        mock.add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        assert!(table.list_tables().await.is_err());
+
+        // only the active parts of the tables of the current database are summed
+        let sql = sent_query(|table| async move {
+            assert!(table.list_tables().await.unwrap().is_empty());
+        })
+        .await;
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(sql.contains("FROM system.parts WHERE database = currentDatabase() AND active"));
+        assert!(sql.contains("WHERE t.database = currentDatabase()"));
+    }
+
+    #[test]
+    fn test_month_bounds() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let utc = |y, m, d| Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap();
+        assert_eq!(
+            month_bounds(date(2023, 12, 15)).unwrap(),
+            (utc(2023, 12, 1), utc(2024, 1, 1))
+        );
+        assert_eq!(
+            month_bounds(date(2024, 2, 29)).unwrap(),
+            (utc(2024, 2, 1), utc(2024, 3, 1))
+        );
+        assert_eq!(
+            month_bounds(date(2024, 1, 1)).unwrap(),
+            (utc(2024, 1, 1), utc(2024, 2, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trades_for_month() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        let expected = vec![trade(1, "BTCUSDC", 10), trade(2, "BTCUSDC", 11)];
+        mock.add(handlers::provide(expected.clone()));
+        let month = NaiveDate::from_ymd_opt(2023, 12, 1).unwrap();
+        assert_eq!(
+            table.trades_for_month("BTCUSDC", month).await.unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merged_stream() {
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        let expected = vec![
+            trade(1, "BTCUSDC", 10),
+            trade(1, "ETHUSDC", 5),
+            trade(2, "BTCUSDC", 11),
+        ];
+        mock.add(handlers::provide(expected.clone()));
+
+        let start = Utc.timestamp_millis_opt(0).unwrap();
+        let end = Utc.timestamp_millis_opt(10).unwrap();
+        let rows: Vec<TradesRow> = table
+            .merged_stream(&["BTCUSDC", "ETHUSDC"], start, end)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows, expected);
+    }
+
+    #[tokio::test]
+    async fn test_merged_stream_per_pair() {
+        let mock = Mock::new();
+        let table = mock_table(&mock).with_table_strategy(TableStrategy::PerPair);
+        // a query per table, TRADES_BTCUSDC before TRADES_ETHUSDC
+        let btc = vec![trade(1, "BTCUSDC", 10), trade(3, "BTCUSDC", 11)];
+        let eth = vec![trade(1, "ETHUSDC", 5), trade(2, "ETHUSDC", 6)];
+        mock.add(handlers::provide(btc.clone()));
+        mock.add(handlers::provide(eth.clone()));
+
+        let start = Utc.timestamp_millis_opt(0).unwrap();
+        let end = Utc.timestamp_millis_opt(10).unwrap();
+        let rows: Vec<TradesRow> = table
+            .merged_stream(&["ETHUSDC", "BTCUSDC"], start, end)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                btc[0].clone(),
+                eth[0].clone(),
+                eth[1].clone(),
+                btc[1].clone()
+            ]
+        );
+
+        let query = sent_query(|table| async move {
+            let table = table.with_table_strategy(TableStrategy::PerPair);
+            let _ = table
+                .merged_stream(&["BTCUSDC"], start, end)
+                .try_collect::<Vec<_>>()
+                .await;
+        })
+        .await;
+        assert!(query.contains("FROM `TRADES_BTCUSDC`"), "{}", query);
+    }
+
+    #[tokio::test]
+    async fn test_export_parquet() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::UInt32Type;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new();
+        let table = mock_table(&mock);
+        let expected = vec![trade(1, "BTCUSDC", 10), trade(2, "BTCUSDC", 11)];
+
+        mock.add(handlers::provide(expected.clone()));
+        let path = dir.path().join("BTCUSDC.parquet");
+        assert_eq!(
+            table.export_parquet("BTCUSDC", &path, None).await.unwrap(),
+            2
+        );
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(
+            batches[0].schema(),
+            crate::data::db::export::trades_schema()
+        );
+        let ids = batches[0]
+            .column_by_name("id")
+            .unwrap()
+            .as_primitive::<UInt32Type>();
+        assert_eq!(ids.values(), &[10, 11]);
+
+        // no trades in range still writes the schema
+        mock.add(handlers::provide(Vec::<TradesRow>::new()));
+        let path = dir.path().join("empty.parquet");
+        let start = Utc.timestamp_millis_opt(0).unwrap();
+        let end = Utc.timestamp_millis_opt(10).unwrap();
+        let rows = table
+            .export_parquet("BTCUSDC", &path, Some((start, end)))
+            .await
+            .unwrap();
+        assert_eq!(rows, 0);
+
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(builder.schema(), &crate::data::db::export::trades_schema());
+        assert_eq!(builder.build().unwrap().count(), 0);
+
+        // more rows than a batch reach the writer in several sends
+        let many: Vec<TradesRow> = (0..crate::data::db::export::BATCH_ROWS as u32 + 1)
+            .map(|id| trade(id as u64, "BTCUSDC", id))
+            .collect();
+        mock.add(handlers::provide(many));
+        let path = dir.path().join("many.parquet");
+        let rows = table.export_parquet("BTCUSDC", &path, None).await.unwrap();
+        assert_eq!(rows, crate::data::db::export::BATCH_ROWS as u64 + 1);
+
+        // the error of the writer is returned
+        mock.add(handlers::provide(vec![trade(1, "BTCUSDC", 10)]));
+        let path = dir.path().join("missing").join("BTCUSDC.parquet");
+        let error = table.export_parquet("BTCUSDC", &path, None).await;
+        assert!(error.unwrap_err().to_string().contains("Could not create"));
+    }
+
+    #[tokio::test]
+    async fn test_time_bounds_are_int64() {
+        // an unsigned millisecond count is not accepted by fromUnixTimestamp64Milli
+        let bounds = "dt >= fromUnixTimestamp64Milli(toInt64(1701388800000)) \
+                      AND dt < fromUnixTimestamp64Milli(toInt64(1704067200000))";
+        let start = Utc.with_ymd_and_hms(2023, 12, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let normalized = |sql: String| sql.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let sql = sent_query(|table| async move {
+            let month = NaiveDate::from_ymd_opt(2023, 12, 15).unwrap();
+            table.trades_for_month("BTCUSDC", month).await.unwrap();
+        })
+        .await;
+        assert!(normalized(sql).contains(bounds));
+
+        let sql = sent_query(|table| async move {
+            let rows: Vec<TradesRow> = table
+                .merged_stream(&["BTCUSDC"], start, end)
+                .try_collect()
+                .await
+                .unwrap();
+            assert!(rows.is_empty());
+        })
+        .await;
+        assert!(normalized(sql).contains(bounds));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDC.parquet");
+        let sql = sent_query(|table| async move {
+            table
+                .export_parquet("BTCUSDC", &path, Some((start, end)))
+                .await
+                .unwrap();
+        })
+        .await;
+        assert!(normalized(sql).contains(bounds));
+    }
+
+    /// Records the fields of every span created and how often each is entered
+    struct RecordedSpan {
+        name: &'static str,
+        fields: String,
+        entered: usize,
+    }
+
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<std::sync::Mutex<Vec<RecordedSpan>>>,
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            struct Fields(String);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0 += &format!("{}={:?};", field.name(), value);
+                }
+            }
+            let mut fields = Fields(String::new());
+            attrs.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(RecordedSpan {
+                name: attrs.metadata().name(),
+                fields: fields.0,
+                entered: 0,
+            });
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.spans.lock().unwrap()[span.into_u64() as usize - 1].entered += 1;
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_index_file_spans() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = FileCollection::new(vec![
+            File::from_path("BTCUSDC", &write_trades_zip(dir.path(), "BTCUSDC").await),
+            File::from_path("ETHUSDC", &write_trades_zip(dir.path(), "ETHUSDC").await),
+        ]);
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = TradesTable::from_client(client, "test", "trades", LocalSource(files))
+            .with_ctrl_c(false)
+            .with_incremental(false)
+            .with_index_concurrency(1);
+
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        mock.add(handlers::record_ddl());
+        for _ in 0..2 {
+            mock.add(handlers::record::<TradesRow>());
+            mock.add(handlers::record::<FileIndexLogRow>());
+        }
+
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+        let report = table.index().await.unwrap();
+        assert_eq!(report.files_indexed, 2);
+
+        let spans = recorder.spans.lock().unwrap();
+        let mut indexed: Vec<&String> = spans
+            .iter()
+            .filter(|span| span.name == "index_file" && span.entered > 0)
+            .map(|span| &span.fields)
+            .collect();
+        indexed.sort();
+        assert_eq!(indexed.len(), 2);
+        assert!(indexed[0].contains("pair=BTCUSDC;"));
+        assert!(indexed[0].contains("BTCUSDC-trades-2024-01.zip"));
+        assert!(indexed[1].contains("pair=ETHUSDC;"));
+        assert!(spans.iter().any(|span| span.name == "index"));
+    }
+}
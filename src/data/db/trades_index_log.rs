@@ -1,10 +1,13 @@
+use std::collections::BTreeSet;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
 use clickhouse::{sql, Client, Row};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 use super::utils::create_client;
+use crate::data::binance::data_types::Cadence;
 
 #[derive(Clone)]
 pub struct TradesIndexLogTable {
@@ -14,13 +17,23 @@ pub struct TradesIndexLogTable {
 }
 
 impl TradesIndexLogTable {
+    /// Connects to the log of `database`, creating or migrating it first
     pub async fn new(database: &str) -> Result<Self> {
-        Ok(TradesIndexLogTable {
-            client: create_client(database).await?,
+        let table = TradesIndexLogTable::from_client(create_client(database).await?, database);
+        table.create().await?;
+        Ok(table)
+    }
+
+    pub(crate) fn from_client(client: Client, database: &str) -> Self {
+        TradesIndexLogTable {
+            client,
             database: Arc::from(database),
             name: "TRADES_INDEX_LOG".into(),
-        })
+        }
     }
+
+    /// Creates the log, or adds the columns a log of an earlier version lacks. The other
+    /// methods expect it to be done, see `TradesTable::create`
     pub async fn create(&self) -> Result<()> {
         self.client
             .query(
@@ -36,6 +49,7 @@ impl TradesIndexLogTable {
                     table String COMMENT 'Table name into which the records have been indexed to',
                     num_rows UInt32 COMMENT 'Number of rows indexed from this file',
                     index_dt DateTime64(3, 'UTC') COMMENT 'Datetime (dt) when file was indexed in ms',
+                    status LowCardinality(String) DEFAULT 'complete' COMMENT 'complete | partial',
                 )
                 ENGINE = ReplacingMergeTree(index_dt)
                 PRIMARY KEY (filename, start_id, table)
@@ -45,13 +59,24 @@ impl TradesIndexLogTable {
             .bind(sql::Identifier(&self.name))
             .execute()
             .await
-            .map_err(|e| anyhow!("Could not create table: {}", e))
+            .map_err(|e| anyhow!("Could not create table: {}", e))?;
+
+        // Logs created before the status column existed
+        self.client
+            .query(
+                "
+                ALTER TABLE ?
+                ADD COLUMN IF NOT EXISTS
+                    status LowCardinality(String) DEFAULT 'complete' COMMENT 'complete | partial'
+                ",
+            )
+            .bind(sql::Identifier(&self.name))
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Could not add status column: {}", e))
     }
 
     pub async fn index_row(&self, row: FileIndexLogRow) -> Result<()> {
-        // TODO: Db initialization procedure otw this will get called multiple times
-        self.create().await?;
-
         let mut insert = self.client.insert(&self.name)?;
         insert
             .write(&row)
@@ -66,9 +91,227 @@ impl TradesIndexLogTable {
             )
         })
     }
+
+    /// Returns the files whose indexed period overlaps `[start_period_dt, end_period_dt]`,
+    /// both given in epoch ms.
+    pub async fn files_indexed_between(
+        &self,
+        start_period_dt: u64,
+        end_period_dt: u64,
+    ) -> Result<Vec<FileIndexLogRow>> {
+        self.client
+            .query(
+                "
+                SELECT ?fields FROM ? FINAL
+                WHERE start_period_dt <= fromUnixTimestamp64Milli(toInt64(?))
+                    AND end_period_dt >= fromUnixTimestamp64Milli(toInt64(?))
+                ORDER BY start_period_dt, filename
+                ",
+            )
+            .bind(sql::Identifier(&self.name))
+            .bind(end_period_dt)
+            .bind(start_period_dt)
+            .fetch_all::<FileIndexLogRow>()
+            .await
+            .with_context(|| format!("Could not query {}.{}", self.database, self.name))
+    }
+
+    /// Returns the files fully indexed into `database`
+    pub async fn complete_files(&self, database: &str) -> Result<Vec<FileIndexLogRow>> {
+        self.client
+            .query(
+                "
+                SELECT ?fields FROM ? FINAL
+                WHERE status = ? AND database = ?
+                ORDER BY filename, start_id
+                ",
+            )
+            .bind(sql::Identifier(&self.name))
+            .bind(IndexStatus::Complete.as_str())
+            .bind(database)
+            .fetch_all::<FileIndexLogRow>()
+            .await
+            .with_context(|| format!("Could not query {}.{}", self.database, self.name))
+    }
+
+    /// Returns the files of a pair completely indexed into `table` of this database.
+    /// Binance filenames are prefixed with the pair name, e.g. BTCUSDC-trades-2024-01.zip
+    pub async fn files_for_pair(&self, pair: &str, table: &str) -> Result<Vec<FileIndexLogRow>> {
+        self.pair_files(pair, table, &[IndexStatus::Complete]).await
+    }
+
+    /// Like `files_for_pair`, but partially indexed files are returned as well
+    pub async fn logged_files(&self, pair: &str, table: &str) -> Result<Vec<FileIndexLogRow>> {
+        self.pair_files(pair, table, &[IndexStatus::Complete, IndexStatus::Partial])
+            .await
+    }
+
+    async fn pair_files(
+        &self,
+        pair: &str,
+        table: &str,
+        statuses: &[IndexStatus],
+    ) -> Result<Vec<FileIndexLogRow>> {
+        let statuses: Vec<&str> = statuses.iter().map(IndexStatus::as_str).collect();
+        self.client
+            .query(
+                "
+                SELECT ?fields FROM ? FINAL
+                WHERE has(?, status) AND database = ? AND table = ? AND startsWith(filename, ?)
+                ORDER BY start_period_dt, filename
+                ",
+            )
+            .bind(sql::Identifier(&self.name))
+            .bind(statuses)
+            .bind(&*self.database)
+            .bind(table)
+            .bind(format!("{}-", pair))
+            .fetch_all::<FileIndexLogRow>()
+            .await
+            .with_context(|| format!("Could not query {}.{}", self.database, self.name))
+    }
+
+    /// Whether a completely indexed file of `pair` in `table` covers `[start_id, end_id]`
+    /// and logged at least the `rows` found in that range. Its own ids may have gaps, as
+    /// Binance files drop rows
+    pub async fn covers_ids(
+        &self,
+        pair: &str,
+        table: &str,
+        start_id: u32,
+        end_id: u32,
+        rows: u32,
+    ) -> Result<bool> {
+        let covering = self
+            .client
+            .query(
+                "
+                SELECT count() FROM ? FINAL
+                WHERE status = ? AND database = ? AND table = ? AND startsWith(filename, ?)
+                    AND start_id <= ? AND end_id >= ? AND num_rows >= ?
+                ",
+            )
+            .bind(sql::Identifier(&self.name))
+            .bind(IndexStatus::Complete.as_str())
+            .bind(&*self.database)
+            .bind(table)
+            .bind(format!("{}-", pair))
+            .bind(start_id)
+            .bind(end_id)
+            .bind(rows)
+            .fetch_one::<u64>()
+            .await
+            .with_context(|| format!("Could not query {}.{}", self.database, self.name))?;
+        Ok(covering > 0)
+    }
+
+    /// Removes the rows of `pair` logged for `table`, e.g. before re-indexing the pair
+    pub async fn delete_pair(&self, pair: &str, table: &str) -> Result<()> {
+        self.client
+            .clone()
+            .with_option("mutations_sync", "2")
+            .query("ALTER TABLE ? DELETE WHERE startsWith(filename, ?) AND table = ?")
+            .bind(sql::Identifier(&self.name))
+            .bind(format!("{}-", pair))
+            .bind(table)
+            .execute()
+            .await
+            .with_context(|| {
+                format!(
+                    "Could not delete {} from {}.{}",
+                    pair, self.database, self.name
+                )
+            })
+    }
+
+    /// Time ranges completely indexed for a pair into `table`, merged into contiguous
+    /// intervals so the gaps in between are the missing data. Files are contiguous when
+    /// their ranges overlap or the trade ids of one carry on from the other.
+    pub async fn coverage(
+        &self,
+        pair: &str,
+        table: &str,
+    ) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>> {
+        let rows = self.files_for_pair(pair, table).await?;
+        Ok(merge_intervals(rows)
+            .into_iter()
+            .filter_map(|(start, end)| {
+                Some((
+                    DateTime::from_timestamp_millis(start as i64)?,
+                    DateTime::from_timestamp_millis(end as i64)?,
+                ))
+            })
+            .collect())
+    }
+
+    /// Periods of `cadence` between the first and the last file of `pair` logged for
+    /// `table` which have no completely indexed file, e.g. to backfill them with a date
+    /// range download. The period holding `today` is still being published, it is never
+    /// missing
+    pub async fn missing_periods(
+        &self,
+        pair: &str,
+        table: &str,
+        cadence: Cadence,
+        today: NaiveDate,
+    ) -> Result<Vec<NaiveDate>> {
+        let rows = self.logged_files(pair, table).await?;
+        Ok(missing_periods(&rows, cadence, today))
+    }
 }
 
-#[derive(Debug, Row, Serialize, Deserialize)]
+/// See `TradesIndexLogTable::missing_periods`. Files of the other cadence are ignored
+fn missing_periods(rows: &[FileIndexLogRow], cadence: Cadence, today: NaiveDate) -> Vec<NaiveDate> {
+    let logged: Vec<(NaiveDate, IndexStatus)> = rows
+        .iter()
+        .filter_map(|row| Some((cadence.parse_date(&row.filename)?, row.status)))
+        .collect();
+    let complete: BTreeSet<NaiveDate> = logged
+        .iter()
+        .filter(|(_, status)| *status == IndexStatus::Complete)
+        .map(|(date, _)| *date)
+        .collect();
+    let (Some(first), Some(last)) = (
+        logged.iter().map(|(date, _)| *date).min(),
+        logged.iter().map(|(date, _)| *date).max(),
+    ) else {
+        return Vec::new();
+    };
+
+    let current = cadence.period_start(today);
+    let mut missing = Vec::new();
+    let mut period = Some(first);
+    while let Some(date) = period.filter(|date| *date <= last && *date < current) {
+        if !complete.contains(&date) {
+            missing.push(date);
+        }
+        period = cadence.next_period(date);
+    }
+    missing
+}
+
+/// Merges the periods of `rows` into `(start, end)` epoch ms intervals, see `coverage`
+fn merge_intervals(mut rows: Vec<FileIndexLogRow>) -> Vec<(u64, u64)> {
+    rows.sort_by_key(|row| (row.start_period_dt, row.start_id));
+
+    let mut intervals: Vec<(u64, u64)> = Vec::new();
+    let mut last_id = 0;
+    for row in rows {
+        match intervals.last_mut() {
+            Some((_, end)) if row.start_period_dt <= *end || row.start_id as u64 <= last_id + 1 => {
+                *end = (*end).max(row.end_period_dt);
+                last_id = last_id.max(row.end_id as u64);
+            }
+            _ => {
+                intervals.push((row.start_period_dt, row.end_period_dt));
+                last_id = row.end_id as u64;
+            }
+        }
+    }
+    intervals
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Row, Serialize, Deserialize)]
 pub struct FileIndexLogRow {
     /// Filename: basename ==> name.ext
     pub filename: String,
@@ -88,4 +331,189 @@ pub struct FileIndexLogRow {
     pub num_rows: u32,
     /// Datetime instant when this file finished indexing
     pub index_dt: u64,
+    /// Whether the whole file was indexed or only the rows up to `end_id`
+    pub status: IndexStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexStatus {
+    /// Every row of the file has been indexed
+    Complete,
+    /// Indexing failed part way; only the logged range has been indexed
+    Partial,
+}
+
+impl IndexStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexStatus::Complete => "complete",
+            IndexStatus::Partial => "partial",
+        }
+    }
+}
+
+// RowBinary has no support for unit variants, so the status travels as a String
+impl Serialize for IndexStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for IndexStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "complete" => Ok(IndexStatus::Complete),
+            "partial" => Ok(IndexStatus::Partial),
+            other => Err(de::Error::unknown_variant(other, &["complete", "partial"])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::log_row;
+    use clickhouse::test::{handlers, Mock};
+
+    #[tokio::test]
+    async fn test_files_indexed_between() {
+        let mock = Mock::new();
+        let table =
+            TradesIndexLogTable::from_client(Client::default().with_url(mock.url()), "test");
+        let expected = vec![log_row("BTCUSDC-trades-2024-01.zip", 0, 100)];
+        mock.add(handlers::provide(expected.clone()));
+
+        let rows = table.files_indexed_between(50, 150).await.unwrap();
+        assert_eq!(rows, expected);
+    }
+
+    #[tokio::test]
+    async fn test_files_for_pair() {
+        let mock = Mock::new();
+        let table =
+            TradesIndexLogTable::from_client(Client::default().with_url(mock.url()), "test");
+        let expected = vec![
+            log_row("BTCUSDC-trades-2024-01.zip", 0, 100),
+            log_row("BTCUSDC-trades-2024-02.zip", 101, 200),
+        ];
+        mock.add(handlers::provide(expected.clone()));
+
+        let rows = table.files_for_pair("BTCUSDC", "TRADES").await.unwrap();
+        assert_eq!(rows, expected);
+    }
+
+    #[tokio::test]
+    async fn test_pair_files_filter_on_table() {
+        let sent = |complete_only: bool| async move {
+            let (url, server) = crate::test_utils::serve_once();
+            let client = Client::default()
+                .with_url(url)
+                .with_compression(clickhouse::Compression::None);
+            let table = TradesIndexLogTable::from_client(client, "test");
+            let _ = if complete_only {
+                table.files_for_pair("BTCUSDC", "TRADES_BTCUSDC").await
+            } else {
+                table.logged_files("BTCUSDC", "TRADES_BTCUSDC").await
+            };
+            server.join().unwrap().query()
+        };
+
+        let query = sent(true).await;
+        assert!(query.contains("has(['complete'], status)"), "{}", query);
+        assert!(query.contains("database = 'test'"), "{}", query);
+        assert!(query.contains("table = 'TRADES_BTCUSDC'"), "{}", query);
+        assert!(
+            query.contains("startsWith(filename, 'BTCUSDC-')"),
+            "{}",
+            query
+        );
+        let query = sent(false).await;
+        assert!(
+            query.contains("has(['complete','partial'], status)"),
+            "{}",
+            query
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coverage() {
+        let mock = Mock::new();
+        let table =
+            TradesIndexLogTable::from_client(Client::default().with_url(mock.url()), "test");
+        let row = |start_id, end_id, start_period_dt, end_period_dt| FileIndexLogRow {
+            start_id,
+            end_id,
+            ..log_row("BTCUSDT-trades-2024-01.zip", start_period_dt, end_period_dt)
+        };
+        mock.add(handlers::provide(vec![
+            row(1, 10, 100, 200),
+            // ids carry on, the time in between had no trades
+            row(11, 20, 300, 400),
+            // ids 21..=30 are missing
+            row(31, 40, 600, 700),
+            row(35, 50, 650, 800),
+        ]));
+
+        let ms = |ms| DateTime::from_timestamp_millis(ms).unwrap();
+        let coverage = table.coverage("BTCUSDT", "TRADES").await.unwrap();
+        assert_eq!(coverage, vec![(ms(100), ms(400)), (ms(600), ms(800))]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_periods() {
+        let mock = Mock::new();
+        let table =
+            TradesIndexLogTable::from_client(Client::default().with_url(mock.url()), "test");
+        let mut partial = log_row("BTCUSDT-trades-2024-04.zip", 0, 0);
+        partial.status = IndexStatus::Partial;
+        mock.add(handlers::provide(vec![
+            log_row("BTCUSDT-trades-2024-01.zip", 0, 0),
+            log_row("BTCUSDT-trades-2024-02.zip", 0, 0),
+            // March is missing
+            partial,
+            log_row("BTCUSDT-trades-2024-05.zip", 0, 0),
+            // daily files are not monthly ones
+            log_row("BTCUSDT-trades-2023-06-15.zip", 0, 0),
+        ]));
+
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let missing = table
+            .missing_periods("BTCUSDT", "TRADES", Cadence::Monthly, date(2024, 10, 16))
+            .await
+            .unwrap();
+        assert_eq!(missing, vec![date(2024, 3, 1), date(2024, 4, 1)]);
+    }
+
+    #[test]
+    fn test_missing_periods_of_the_current_period() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let mut today = log_row("BTCUSDT-trades-2024-03-16.zip", 0, 0);
+        today.status = IndexStatus::Partial;
+        let rows = vec![
+            log_row("BTCUSDT-trades-2024-03-13.zip", 0, 0),
+            log_row("BTCUSDT-trades-2024-03-14.zip", 0, 0),
+            today,
+        ];
+
+        // the day being published is not missing, the day before is
+        assert_eq!(
+            missing_periods(&rows, Cadence::Daily, date(2024, 3, 16)),
+            vec![date(2024, 3, 15)]
+        );
+        assert!(missing_periods(&[], Cadence::Daily, date(2024, 3, 16)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_index_row_status() {
+        let mock = Mock::new();
+        let table =
+            TradesIndexLogTable::from_client(Client::default().with_url(mock.url()), "test");
+        let mut row = log_row("BTCUSDC-trades-2024-01.zip", 0, 100);
+        row.status = IndexStatus::Partial;
+        let recording = mock.add(handlers::record::<FileIndexLogRow>());
+
+        table.index_row(row.clone()).await.unwrap();
+        let rows: Vec<FileIndexLogRow> = recording.collect().await;
+        assert_eq!(rows, vec![row]);
+    }
 }
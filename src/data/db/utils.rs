@@ -1,11 +1,18 @@
-use anyhow::{Context, Result};
-use clickhouse::inserter::Quantities;
-use clickhouse::{sql, Client};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clickhouse::inserter::{Inserter, Quantities};
+use clickhouse::{sql, Client, Compression, Row};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
 use std::ops::AddAssign;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::utils::config;
+use crate::utils::config::{self, ClickhouseCompression, InserterConfig};
+use crate::utils::retry::RetryPolicy;
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub struct AddableQuantities {
     /// The number of uncompressed bytes.
     pub bytes: u64,
@@ -13,6 +20,10 @@ pub struct AddableQuantities {
     pub rows: u64,
     /// The number of nonempty transactions (calls of [`Inserter::commit`]).
     pub transactions: u64,
+    /// The number of malformed rows dropped instead of being inserted.
+    pub skipped_rows: u64,
+    /// The number of rows failing `RowValidation` dropped instead of being inserted.
+    pub invalid_rows: u64,
 }
 
 impl std::ops::Add for AddableQuantities {
@@ -23,6 +34,8 @@ impl std::ops::Add for AddableQuantities {
             bytes: self.bytes + other.bytes,
             rows: self.rows + other.rows,
             transactions: self.transactions + other.transactions,
+            skipped_rows: self.skipped_rows + other.skipped_rows,
+            invalid_rows: self.invalid_rows + other.invalid_rows,
         }
     }
 }
@@ -40,31 +53,569 @@ impl AddAssign for AddableQuantities {
         self.bytes += rhs.bytes;
         self.rows += rhs.rows;
         self.transactions += rhs.transactions;
+        self.skipped_rows += rhs.skipped_rows;
+        self.invalid_rows += rhs.invalid_rows;
+    }
+}
+
+pub fn inserter<T: Row>(
+    client: &Client,
+    table: &str,
+    config: &InserterConfig,
+) -> clickhouse::error::Result<Inserter<T>> {
+    Ok(client
+        .inserter::<T>(table)?
+        .with_max_rows(config.max_rows)
+        .with_period(Some(Duration::from_secs(config.period_secs))))
+}
+
+/// Whether a failed request to ClickHouse may succeed when sent again: the connection
+/// broke or timed out, or something in front of ClickHouse, e.g. a proxy, answered. Errors
+/// of ClickHouse itself, e.g. about the schema or the data, start with `Code:`
+pub fn is_transient(error: &clickhouse::error::Error) -> bool {
+    use clickhouse::error::Error;
+
+    match error {
+        Error::Network(_) | Error::TimedOut => true,
+        Error::BadResponse(reason) => !reason.starts_with("Code:"),
+        _ => false,
+    }
+}
+
+/// A client per url of `clickhouse.url`, all of the same database. Their order is the
+/// order of the config, so a key starts at the same endpoint in every run
+#[derive(Clone)]
+pub struct Endpoints {
+    clients: Arc<[Client]>,
+    /// How failed INSERTs are sent again, see `RetryingInserter`
+    retry: RetryPolicy,
+}
+
+impl Endpoints {
+    /// Clients of `database` at every configured url. The database is not created, see
+    /// `create_client`
+    pub fn from_config(database: &str) -> Result<Self> {
+        let config = config::Config::create()?;
+        let cfg = &config.clickhouse;
+        let database = database.to_uppercase();
+        let clients: Vec<Client> = cfg
+            .url
+            .iter()
+            .map(|url| base_client(cfg, url).with_database(&database))
+            .collect();
+        if clients.is_empty() {
+            return Err(anyhow!("No ClickHouse url configured"));
+        }
+        Ok(Endpoints {
+            clients: clients.into(),
+            retry: RetryPolicy::clickhouse(cfg),
+        })
+    }
+
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Index of the endpoint which the inserts of `key`, e.g. a pair, go to first
+    pub fn start_for(&self, key: &str) -> usize {
+        crc32fast::hash(key.as_bytes()) as usize % self.clients.len()
+    }
+
+    /// The client at `index`, wrapping around
+    pub fn get(&self, index: usize) -> &Client {
+        &self.clients[index % self.clients.len()]
+    }
+}
+
+impl From<Client> for Endpoints {
+    fn from(client: Client) -> Self {
+        Endpoints {
+            clients: Arc::from([client]),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// An `Inserter` whose INSERTs are sent again when ending them fails with a transient
+/// error, see `is_transient`, up to the `max_attempts` of the endpoints' retry policy.
+/// The rows of the open INSERT are kept for that, up to `max_rows` of them. It starts at
+/// the endpoint of its key and fails over to the next endpoint whenever an INSERT is sent
+/// again
+pub struct RetryingInserter<T> {
+    endpoints: Endpoints,
+    /// Index into `endpoints` of the client of the open INSERT
+    endpoint: usize,
+    table: String,
+    config: InserterConfig,
+    /// Only None while `end` sends the last INSERT
+    inserter: Option<Inserter<T>>,
+    /// Clones of the rows written since the last INSERT ended. That is up to `max_rows`
+    /// rows, 500k by default, held in memory by every file being indexed concurrently.
+    /// None when INSERTs are not sent again
+    unsent: Option<Vec<T>>,
+}
+
+impl<T: Row + Serialize + Clone> RetryingInserter<T> {
+    pub fn new(
+        endpoints: &Endpoints,
+        key: &str,
+        table: &str,
+        config: &InserterConfig,
+    ) -> Result<Self> {
+        let endpoint = endpoints.start_for(key);
+        Ok(RetryingInserter {
+            endpoints: endpoints.clone(),
+            endpoint,
+            table: table.to_string(),
+            config: *config,
+            inserter: Some(inserter::<T>(endpoints.get(endpoint), table, config)?),
+            unsent: (endpoints.retry.max_attempts > 1).then(Vec::new),
+        })
+    }
+
+    fn inserter(&mut self) -> &mut Inserter<T> {
+        self.inserter.as_mut().expect("only taken by end")
+    }
+
+    pub fn write(&mut self, row: &T) -> Result<()> {
+        self.inserter().write(row)?;
+        if let Some(unsent) = &mut self.unsent {
+            unsent.push(row.clone());
+        }
+        Ok(())
+    }
+
+    /// `Inserter::commit`, the rows are only sent once the limits of the config are reached
+    pub async fn commit(&mut self) -> Result<Quantities> {
+        let committed = match self.inserter().commit().await {
+            Err(e) if self.unsent.is_some() && is_transient(&e) => {
+                tracing::warn!(
+                    "Could not commit into {}, sending it again: {}",
+                    self.table,
+                    e
+                );
+                self.resend().await?
+            }
+            committed => committed?,
+        };
+        if let Some(unsent) = self.unsent.as_mut().filter(|_| committed.rows > 0) {
+            unsent.clear();
+        }
+        Ok(committed)
+    }
+
+    pub async fn end(mut self) -> Result<Quantities> {
+        let inserter = self.inserter.take().expect("only taken by end");
+        match inserter.end().await {
+            Err(e) if self.unsent.is_some() && is_transient(&e) => {
+                tracing::warn!(
+                    "Could not end the insert into {}, sending it again: {}",
+                    self.table,
+                    e
+                );
+                self.resend().await
+            }
+            ended => Ok(ended?),
+        }
+    }
+
+    /// Sends the unsent rows in a new INSERT to the next endpoint, which the inserter
+    /// carries on with. The failed one may have landed after all, the tables replace
+    /// duplicate rows
+    async fn resend(&mut self) -> Result<Quantities> {
+        let unsent = self.unsent.as_ref().expect("only resent with retries");
+        // the failed INSERT was the first attempt
+        let retry = RetryPolicy {
+            max_attempts: self.endpoints.retry().max_attempts - 1,
+            ..*self.endpoints.retry()
+        };
+        let next = AtomicUsize::new(self.endpoint + 1);
+        let (endpoint, inserter, committed) = retry
+            .run_when(
+                &format!("Inserting {} rows into {}", unsent.len(), self.table),
+                is_transient,
+                || async {
+                    let endpoint = next.fetch_add(1, Ordering::Relaxed);
+                    let client = self.endpoints.get(endpoint);
+                    let mut inserter = inserter::<T>(client, &self.table, &self.config)?;
+                    for row in unsent {
+                        inserter.write(row)?;
+                    }
+                    let committed = inserter.force_commit().await?;
+                    Ok((endpoint, inserter, committed))
+                },
+            )
+            .await?;
+        self.endpoint = endpoint;
+        self.inserter = Some(inserter);
+        if let Some(unsent) = &mut self.unsent {
+            unsent.clear();
+        }
+        Ok(committed)
     }
 }
 
+/// Streams `rows` into `table` through the endpoint of `key`, committing every
+/// `config.commit_rows` rows
+pub async fn insert_rows<T, S>(
+    endpoints: &Endpoints,
+    key: &str,
+    table: &str,
+    config: &InserterConfig,
+    rows: S,
+) -> Result<AddableQuantities>
+where
+    T: Row + Serialize + Clone,
+    S: Stream<Item = Result<T>>,
+{
+    let mut inserter = RetryingInserter::<T>::new(endpoints, key, table, config)?;
+    let mut stats = AddableQuantities::default();
+    let mut rows = std::pin::pin!(rows);
+    let mut written = 0;
+
+    while let Some(row) = rows.next().await {
+        inserter.write(&row?)?;
+        written += 1;
+        if written % config.commit_rows.max(1) == 0 {
+            stats += inserter.commit().await?;
+        }
+    }
+    stats += inserter.end().await?;
+    Ok(stats)
+}
+
+/// Ports of ClickHouse's native TCP protocol, which the clickhouse crate does not speak
+const NATIVE_PORTS: [&str; 2] = ["9000", "9440"];
+
+/// Index into `clickhouse.url` of the endpoint the next client starts with
+static NEXT_ENDPOINT: AtomicUsize = AtomicUsize::new(0);
+
+/// A client of `database`, created if need be. With several urls configured, consecutive
+/// clients go to the endpoints round-robin.
 pub async fn create_client(database: &str) -> Result<Client> {
-    let cfg = config::Config::create().clickhouse;
+    let config = config::Config::create()?;
+    connect(&config.clickhouse, database, &NEXT_ENDPOINT).await
+}
+
+/// Tries the endpoints of `cfg` starting at `next`, which is advanced. An endpoint which
+/// cannot create the database is failed over to the one after it. A client sticks to its
+/// endpoint once it is created.
+async fn connect(
+    cfg: &config::ClickhouseConfig,
+    database: &str,
+    next: &AtomicUsize,
+) -> Result<Client> {
+    if cfg.url.is_empty() {
+        return Err(anyhow!("No ClickHouse url configured"));
+    }
+    for url in &cfg.url {
+        validate_url(url)?;
+    }
     let database = &database.to_uppercase();
+    let start = next.fetch_add(1, Ordering::Relaxed);
 
-    Ok(Client::default()
-        .with_url(cfg.url)
-        .with_user(cfg.user)
-        .with_password(cfg.password)
-        .with_database(create_database(database).await?))
+    let mut error = None;
+    for offset in 0..cfg.url.len() {
+        let url = &cfg.url[(start + offset) % cfg.url.len()];
+        let client = base_client(cfg, url);
+        match create_database(&client, cfg, database).await {
+            Ok(()) => return Ok(client.with_database(database)),
+            Err(e) => {
+                tracing::warn!("ClickHouse at {} failed: {:#}", url, e);
+                error = Some(e);
+            }
+        }
+    }
+    Err(error
+        .unwrap()
+        .context("Every configured ClickHouse endpoint failed"))
+}
+
+/// The clickhouse crate only talks to the HTTP interface, so reject urls which would
+/// fail later with an obscure connection error
+fn validate_url(url: &str) -> Result<()> {
+    let Some(rest) = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))
+    else {
+        return Err(anyhow!(
+            "ClickHouse url must use the HTTP interface (http:// or https://), got: {}",
+            url
+        ));
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    if let Some((_, port)) = authority.rsplit_once(':') {
+        if NATIVE_PORTS.contains(&port) {
+            return Err(anyhow!(
+                "ClickHouse url points at the native protocol port {}, use the HTTP port (8123 or 8443): {}",
+                port,
+                url
+            ));
+        }
+    }
+    Ok(())
 }
 
-async fn create_database(database: &str) -> Result<&str> {
-    let cfg = config::Config::create().clickhouse;
-    let client = Client::default()
-        .with_url(cfg.url)
-        .with_user(cfg.user)
-        .with_password(cfg.password);
+fn base_client(cfg: &config::ClickhouseConfig, url: &str) -> Client {
+    Client::default()
+        .with_url(url)
+        .with_user(&cfg.user)
+        .with_password(&cfg.password)
+        .with_compression(compression(cfg.compression))
+}
+
+fn compression(codec: ClickhouseCompression) -> Compression {
+    match codec {
+        ClickhouseCompression::None => Compression::None,
+        ClickhouseCompression::Lz4 => Compression::Lz4,
+        // deprecated as lz4_flex has no HC mode yet, it is compressed like lz4 until it has
+        #[allow(deprecated)]
+        ClickhouseCompression::Lz4Hc(level) => Compression::Lz4Hc(level),
+    }
+}
+
+/// Creates `name` on every node of `cluster` as a Distributed table over `<name>_LOCAL`,
+/// which has to exist already, sharded by pair
+pub async fn create_distributed(client: &Client, name: &str, cluster: &str) -> Result<()> {
+    let local_name = local_table(name);
     client
-        .query("CREATE DATABASE IF NOT EXISTS ?")
-        .bind(sql::Identifier(database))
+        .query(
+            "
+            CREATE TABLE IF NOT EXISTS ? ON CLUSTER ? AS ?
+            ENGINE = Distributed(?, currentDatabase(), ?, cityHash64(pair))
+            ",
+        )
+        .bind(sql::Identifier(name))
+        .bind(sql::Identifier(cluster))
+        .bind(sql::Identifier(&local_name))
+        .bind(sql::Identifier(cluster))
+        .bind(sql::Identifier(&local_name))
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Could not create distributed table: {}", e))
+}
+
+/// Table holding the rows of the Distributed table `name` on every node of a cluster
+pub fn local_table(name: &str) -> String {
+    format!("{}_LOCAL", name)
+}
+
+async fn create_database(
+    client: &Client,
+    cfg: &config::ClickhouseConfig,
+    database: &str,
+) -> Result<()> {
+    let query = match &cfg.cluster {
+        Some(cluster) => client
+            .query("CREATE DATABASE IF NOT EXISTS ? ON CLUSTER ?")
+            .bind(sql::Identifier(database))
+            .bind(sql::Identifier(cluster)),
+        None => client
+            .query("CREATE DATABASE IF NOT EXISTS ?")
+            .bind(sql::Identifier(database)),
+    };
+    query
         .execute()
         .await
         .with_context(|| format!("Could not create database: {}", database))?;
-    Ok(database)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::db::trades::{Side, TradesRow};
+    use clickhouse::test::{handlers, status, Mock};
+
+    #[tokio::test]
+    async fn test_insert_rows_honours_config() {
+        let mock = Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let config = InserterConfig {
+            max_rows: 2,
+            period_secs: 60,
+            commit_rows: 1,
+        };
+        let first = mock.add(handlers::record());
+        let second = mock.add(handlers::record());
+
+        let rows = (0..3).map(|id| {
+            Ok(TradesRow {
+                dt: id,
+                pair: Arc::from("BTCUSDC"),
+                side: Side::Buy,
+                price: 1.0,
+                qty: 1.0,
+                notional: 1.0,
+                id: id as u32,
+            })
+        });
+        let endpoints = Endpoints::from(client);
+        let stats = insert_rows(
+            &endpoints,
+            "BTCUSDC",
+            "TRADES",
+            &config,
+            futures::stream::iter(rows),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.rows, 3);
+
+        // max_rows ends the first INSERT after two rows
+        assert_eq!(first.collect::<Vec<TradesRow>>().await.len(), 2);
+        assert_eq!(second.collect::<Vec<TradesRow>>().await.len(), 1);
+    }
+
+    fn clickhouse_config(mocks: &[&Mock]) -> config::ClickhouseConfig {
+        config::ClickhouseConfig {
+            url: mocks.iter().map(|mock| mock.url().to_string()).collect(),
+            user: "default".to_string(),
+            password: String::new(),
+            cluster: None,
+            inserter: InserterConfig::default(),
+            compression: ClickhouseCompression::default(),
+            max_attempts: None,
+            retry_base_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients_spread_over_endpoints() {
+        let (first, second) = (Mock::new(), Mock::new());
+        let config = clickhouse_config(&[&first, &second]);
+        // each mock answers exactly two CREATE DATABASE, a third would fail its drop
+        for mock in [&first, &second] {
+            mock.add(handlers::record_ddl());
+            mock.add(handlers::record_ddl());
+        }
+        let next = AtomicUsize::new(0);
+        for _ in 0..4 {
+            connect(&config, "test", &next).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_over() {
+        let (down, up) = (Mock::new(), Mock::new());
+        let config = clickhouse_config(&[&down, &up]);
+        down.add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        let created = up.add(handlers::record_ddl());
+        let next = AtomicUsize::new(0);
+        connect(&config, "test", &next).await.unwrap();
+        assert!(created.query().await.contains("CREATE DATABASE"));
+
+        down.add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        up.add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        let next = AtomicUsize::new(1);
+        assert!(connect(&config, "test", &next).await.is_err());
+    }
+
+    fn endpoints(mocks: &[&Mock]) -> Endpoints {
+        Endpoints {
+            clients: mocks
+                .iter()
+                .map(|mock| Client::default().with_url(mock.url()))
+                .collect(),
+            retry: RetryPolicy {
+                base_delay: Duration::ZERO,
+                ..RetryPolicy::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_by_key() {
+        let (first, second) = (Mock::new(), Mock::new());
+        let endpoints = endpoints(&[&first, &second]);
+        let starts: Vec<usize> = ["BTCUSDC", "ETHUSDC", "SOLUSDC", "BNBUSDC"]
+            .iter()
+            .map(|pair| endpoints.start_for(pair))
+            .collect();
+        assert!(starts.contains(&0) && starts.contains(&1));
+        assert_eq!(endpoints.start_for("BTCUSDC"), starts[0]);
+        assert_eq!(Endpoints::from(Client::default()).start_for("BTCUSDC"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_insert_fails_over() {
+        let mocks = [Mock::new(), Mock::new()];
+        let endpoints = endpoints(&[&mocks[0], &mocks[1]]);
+        let start = endpoints.start_for("BTCUSDC");
+        mocks[start].add(handlers::failure(status::SERVICE_UNAVAILABLE));
+        let recorded = mocks[(start + 1) % 2].add(handlers::record());
+
+        let row = TradesRow {
+            dt: 1,
+            pair: Arc::from("BTCUSDC"),
+            side: Side::Buy,
+            price: 1.0,
+            qty: 1.0,
+            notional: 1.0,
+            id: 1,
+        };
+        let config = InserterConfig::default();
+        let stats = insert_rows(
+            &endpoints,
+            "BTCUSDC",
+            "TRADES",
+            &config,
+            futures::stream::iter([Ok(row.clone())]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.rows, 1);
+        assert_eq!(recorded.collect::<Vec<TradesRow>>().await, vec![row]);
+    }
+
+    #[tokio::test]
+    async fn test_insert_without_retries() {
+        let mocks = [Mock::new(), Mock::new()];
+        let mut endpoints = endpoints(&[&mocks[0], &mocks[1]]);
+        endpoints.retry.max_attempts = 1;
+        let start = endpoints.start_for("BTCUSDC");
+        // the other mock fails the test if the INSERT is sent again
+        mocks[start].add(handlers::failure(status::SERVICE_UNAVAILABLE));
+
+        let config = InserterConfig::default();
+        let mut inserter =
+            RetryingInserter::<TradesRow>::new(&endpoints, "BTCUSDC", "TRADES", &config).unwrap();
+        assert!(inserter.unsent.is_none());
+        inserter
+            .write(&TradesRow {
+                dt: 1,
+                pair: Arc::from("BTCUSDC"),
+                side: Side::Buy,
+                price: 1.0,
+                qty: 1.0,
+                notional: 1.0,
+                id: 1,
+            })
+            .unwrap();
+        assert!(inserter.end().await.is_err());
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        use clickhouse::error::Error;
+
+        assert!(is_transient(&Error::TimedOut));
+        assert!(is_transient(&Error::BadResponse(
+            "502 Bad Gateway".to_string()
+        )));
+        // ClickHouse itself refused the rows
+        assert!(!is_transient(&Error::BadResponse(
+            "Code: 60. DB::Exception: Table test.TRADES does not exist".to_string()
+        )));
+        assert!(!is_transient(&Error::NotEnoughData));
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("http://localhost:8123").is_ok());
+        assert!(validate_url("https://clickhouse.example.com").is_ok());
+        assert!(validate_url("tcp://localhost:9000").is_err());
+        assert!(validate_url("http://localhost:9000").is_err());
+        assert!(validate_url("localhost:8123").is_err());
+    }
 }
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Either object of a data and checksum pair, see `DataError::UnpairedObject`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectSide {
+    Data,
+    Checksum,
+}
+
+impl fmt::Display for ObjectSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ObjectSide::Data => "data",
+            ObjectSide::Checksum => "checksum",
+        })
+    }
+}
+
+/// Failures of the data layer, so callers can tell e.g. a corrupt download from a network
+/// error
+#[derive(Debug, Error)]
+pub enum DataError {
+    /// The downloaded file does not hash to the published checksum
+    #[error("Checksum does not match for {}: expected {expected}, got {actual}", path.display())]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// The download is not as large as the listed object, e.g. a truncated transfer
+    #[error("Size does not match for {}: expected {expected} bytes, got {actual}", path.display())]
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// A request to the bucket failed
+    #[error("{context}: {source}")]
+    S3 {
+        context: String,
+        #[source]
+        source: s3::error::S3Error,
+    },
+    /// An object the bucket should have is not there, e.g. the checksum of a zip
+    #[error("Missing object: {0}")]
+    MissingObject(String),
+    /// A listing has one object of a data and checksum pair but not the other. `key` is
+    /// the one missing, `listed` the one found
+    #[error("Missing the {missing} object {key}, only {listed} is listed")]
+    UnpairedObject {
+        missing: ObjectSide,
+        key: String,
+        listed: String,
+    },
+    /// The zip is truncated or otherwise not a whole archive
+    #[error("Corrupt zip {}: {reason}. Delete it so it is downloaded again", path.display())]
+    CorruptZip { path: PathBuf, reason: String },
+    #[error("Could not read zip {}: {source}", path.display())]
+    Zip {
+        path: PathBuf,
+        #[source]
+        source: async_zip::error::ZipError,
+    },
+    #[error("Could not parse CSV: {0}")]
+    Csv(#[from] csv_async::Error),
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// A request took longer than allowed, e.g. a hung connection
+    #[error("{context}: timed out after {after:?}")]
+    Timeout { context: String, after: Duration },
+    /// A listing task panicked or was cancelled
+    #[error("Task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Invalid config: {0}")]
+    Config(String),
+    /// Readable, but not what the exchange publishes, e.g. an empty checksum
+    #[error("{0}")]
+    InvalidData(String),
+}
+
+impl DataError {
+    pub fn s3(context: impl Into<String>, source: s3::error::S3Error) -> Self {
+        DataError::S3 {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        DataError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
+pub type Result<T, E = DataError> = std::result::Result<T, E>;
@@ -1,2 +1,4 @@
 pub mod binance;
 pub mod db;
+pub mod error;
+pub mod source;
@@ -0,0 +1,35 @@
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+
+use crate::data::binance::file::{EmptyFieldPolicy, File, Row, SkippedRows};
+use crate::data::binance::file_collection::FileCollection;
+use crate::data::binance::pair::Pair;
+use crate::data::error::Result;
+
+/// Where trades are ingested from. An exchange lists its pairs, resolves them into files
+/// and reads those files back as exchange neutral rows; `TradesTable` does the rest.
+pub trait TradeSource: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn list_pairs(&self) -> BoxFuture<'_, Result<Vec<Pair>>>;
+
+    fn list_files<'a>(&'a self, pairs: &'a [Pair]) -> BoxFuture<'a, Result<FileCollection>>;
+
+    /// Reads a downloaded file. Defaults to Binance's zipped CSV layout
+    fn records<'a>(
+        &'a self,
+        file: &'a File,
+        policy: EmptyFieldPolicy,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, Result<Row>>>> {
+        Box::pin(file.records_with_policy(policy))
+    }
+
+    /// Like `records`, dropping and counting the rows which cannot be deserialized
+    fn records_lenient<'a>(
+        &'a self,
+        file: &'a File,
+        policy: EmptyFieldPolicy,
+    ) -> BoxFuture<'a, Result<(BoxStream<'static, Result<Row>>, SkippedRows)>> {
+        Box::pin(file.records_lenient(policy))
+    }
+}
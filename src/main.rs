@@ -1,38 +1,409 @@
 pub mod data;
 pub mod test_utils;
 pub mod utils;
-pub use crate::data::binance::data_types::{Asset, Cadence, DataType};
+pub use crate::data::binance::data_types::{Asset, Cadence, DataType, FuturesMarket};
 pub use crate::data::binance::downloader::Downloader;
-pub use crate::data::db::trades::TradesTable;
+pub use crate::data::db::trades::{TableStrategy, TradesTable};
+pub use crate::data::error::DataError;
 
 use std::env;
+use std::path::PathBuf;
 use std::time::Instant;
 
 use anyhow::Result;
+use clap::Parser;
 use env_logger::{Builder, Target};
 
+use crate::data::binance::checksum::ChecksumOptions;
+use crate::data::db::agg_trades::AggTradesTable;
+use crate::data::db::klines::KLinesTable;
+use crate::data::db::notifier::WebhookNotifier;
+use crate::data::db::trades_index_log::TradesIndexLogTable;
+
+/// Files hashed concurrently by `--verify`
+const DEFAULT_VERIFY_CONCURRENCY: usize = 8;
+/// Files fetched concurrently by `--download-only`
+const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 50;
+
+/// Downloads Binance market data and indexes it into ClickHouse
+#[derive(Debug, Parser)]
+struct Args {
+    #[arg(long, default_value = "spot")]
+    asset: Asset,
+    /// Sub-market of futures: um or cm
+    #[arg(long, default_value = "um")]
+    futures_market: FuturesMarket,
+    #[arg(long, default_value = "monthly")]
+    cadence: Cadence,
+    /// trades, aggtrades or klines
+    #[arg(long, default_value = "trades")]
+    data_type: DataType,
+    /// Candle interval of klines
+    #[arg(long, default_value = "1m")]
+    kline_interval: String,
+    /// Pairs starting with any of these, comma separated
+    #[arg(long, value_delimiter = ',')]
+    starts_with: Vec<String>,
+    /// Pairs ending with any of these, comma separated
+    #[arg(long, value_delimiter = ',', default_value = "USDC")]
+    ends_with: Vec<String>,
+    /// Pairs containing any of these are skipped, comma separated
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Pairs whose name matches this regex, on top of the other filters
+    #[arg(long)]
+    pair_regex: Option<String>,
+    /// File listing the pairs to keep, one per line, on top of the other filters
+    #[arg(long)]
+    pairs_file: Option<PathBuf>,
+    #[arg(long, default_value = "test")]
+    database: String,
+    #[arg(long, default_value = "trades_any_usdc")]
+    table: String,
+    /// Only fetches the newest file of each pair
+    #[arg(long)]
+    latest_only: bool,
+    /// Downloads files without a published checksum unverified, instead of failing
+    #[arg(long)]
+    allow_missing_checksums: bool,
+    /// Downloads here instead of the data dir of the config
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Pairs listed concurrently
+    #[arg(long)]
+    list_concurrency: Option<usize>,
+    /// Files downloaded concurrently while indexing
+    #[arg(long)]
+    download_concurrency: Option<usize>,
+    /// Files indexed concurrently
+    #[arg(long)]
+    index_concurrency: Option<usize>,
+    /// Index every pair of trades into its own table
+    #[arg(long)]
+    table_per_pair: bool,
+    /// Writes the outcome of the run as JSON
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// POSTs failed files and the summary of the trades run here, e.g. a Slack webhook
+    #[arg(long)]
+    notify_url: Option<String>,
+    /// Lists what would be downloaded and exits
+    #[arg(long)]
+    dry_run: bool,
+    /// Checks the files already downloaded against their checksums and exits
+    #[arg(long)]
+    verify: bool,
+    /// Downloads the files missing on disk and exits, without ClickHouse
+    #[arg(long)]
+    download_only: bool,
+    /// Re-indexes files the index log already has
+    #[arg(long)]
+    full: bool,
+    /// Lists the tables of the database with their rows and size, and exits
+    #[arg(long)]
+    tables: bool,
+    /// Lists the periods of the cadence missing from the index log of this pair and exits
+    #[arg(long)]
+    gaps: Option<String>,
+    /// Deletes the trades of this pair and indexes it again
+    #[arg(long)]
+    reindex: Option<String>,
+    /// Also shows debug logs
+    #[arg(long)]
+    verbose: bool,
+    /// Demotes the per-file lines to debug
+    #[arg(long)]
+    quiet: bool,
+    /// Serves Prometheus metrics on this port
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_port: Option<u16>,
+}
+
+fn filters(values: &[String]) -> Vec<&str> {
+    values.iter().map(String::as_str).collect()
+}
+
+impl Args {
+    fn downloader(&self) -> Result<Downloader> {
+        let mut downloader = Downloader::new(
+            &format!("{} Downloader", self.table),
+            self.asset,
+            self.cadence,
+            self.data_type,
+        )?
+        .with_futures_market(self.futures_market)
+        .with_kline_interval(&self.kline_interval);
+        if !self.starts_with.is_empty() {
+            downloader = downloader.with_pair_starts_with(&filters(&self.starts_with));
+        }
+        if !self.ends_with.is_empty() {
+            downloader = downloader.with_pair_ends_with(&filters(&self.ends_with));
+        }
+        if !self.exclude.is_empty() {
+            downloader = downloader.with_pair_excluded(&filters(&self.exclude));
+        }
+        if let Some(pattern) = &self.pair_regex {
+            downloader = downloader.with_pair_matching(pattern)?;
+        }
+        if let Some(path) = &self.pairs_file {
+            downloader = downloader.with_pair_allowlist_file(path)?;
+        }
+        if let Some(concurrency) = self.list_concurrency {
+            downloader = downloader.with_list_concurrency(concurrency);
+        }
+        if let Some(dir) = &self.data_dir {
+            downloader = downloader.with_data_dir(dir);
+        }
+        if self.latest_only {
+            downloader = downloader.with_latest_only();
+        }
+        if self.allow_missing_checksums {
+            // keeps the algo and suffix of the downloader
+            let checksum = ChecksumOptions {
+                allow_missing: true,
+                ..downloader.checksum.clone()
+            };
+            downloader = downloader.with_checksum(checksum);
+        }
+        Ok(downloader)
+    }
+
+    fn table_strategy(&self) -> TableStrategy {
+        if self.table_per_pair {
+            TableStrategy::PerPair
+        } else {
+            TableStrategy::Single
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+    let default_filter = if args.verbose { "debug" } else { "info" };
+    // the bars take over from the per-file lines
+    #[cfg(feature = "progress")]
+    let progress_bars = utils::progress::install();
+    #[cfg(not(feature = "progress"))]
+    let progress_bars = false;
+    let file_log_level = if args.quiet || progress_bars {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+
     Builder::new()
-        .target(Target::Stdout)
-        .parse_filters(&env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()))
+        .target(if progress_bars {
+            Target::Pipe(Box::new(utils::progress::LogWriter))
+        } else {
+            Target::Stdout
+        })
+        .parse_filters(&env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string()))
         .init();
 
+    #[cfg(feature = "metrics")]
+    if let Some(port) = args.metrics_port {
+        utils::metrics::install_exporter(port)?;
+        log::info!("[main] Serving metrics on port {}", port);
+    }
+
     // perf start
     let now = Instant::now();
 
-    let downloader = Downloader::new(
-        "USDC Downloader",
-        Asset::Spot,
-        Cadence::Monthly,
-        DataType::Trades,
-    )?
-    .with_pair_ends_with(&["USDC"]);
+    if let Some(pair) = &args.gaps {
+        let missing = TradesIndexLogTable::new(&args.database)
+            .await?
+            .missing_periods(
+                pair,
+                &args.table_strategy().table_for_pair(&args.table, pair),
+                args.cadence,
+                chrono::Utc::now().date_naive(),
+            )
+            .await?;
+        for date in &missing {
+            log::info!(
+                "[main] Missing {}: {}",
+                pair,
+                args.cadence.format_period(*date)
+            );
+        }
+        log::info!(
+            "[main] {} {} periods missing for {}",
+            missing.len(),
+            args.cadence,
+            pair
+        );
+        return Ok(());
+    }
+
+    let downloader = args.downloader()?;
 
-    let table = TradesTable::new("test", "trades_any_usdc", downloader).await?;
-    table.index().await?;
+    if args.tables {
+        let table = TradesTable::new(&args.database, &args.table, downloader).await?;
+        for info in table.list_tables().await? {
+            log::info!(
+                "[main] {}: {} rows, {} bytes",
+                info.name,
+                info.rows,
+                info.bytes
+            );
+        }
+        return Ok(());
+    }
+
+    if args.dry_run {
+        downloader.plan().await?;
+        return Ok(());
+    }
+
+    if args.verify {
+        let plan = downloader.plan().await?;
+        let report = plan
+            .files
+            .verify_on_disk(
+                args.download_concurrency
+                    .unwrap_or(DEFAULT_VERIFY_CONCURRENCY),
+            )
+            .await;
+        for file in &report.mismatch {
+            log::error!("[main] Corrupt: {}", file.path.display());
+        }
+        if !report.is_ok() {
+            anyhow::bail!(
+                "{} files do not match their checksum, {} could not be verified",
+                report.mismatch.len(),
+                report.failed.len()
+            );
+        }
+        return Ok(());
+    }
+
+    // fail fast rather than midway through a long run
+    downloader.check_s3().await?;
+
+    if args.download_only {
+        let summary = downloader
+            .download_all(
+                args.download_concurrency
+                    .unwrap_or(DEFAULT_DOWNLOAD_CONCURRENCY),
+            )
+            .await?;
+        if !summary.is_ok() {
+            anyhow::bail!("{} files could not be downloaded", summary.failed.len());
+        }
+        log::info!("[main] Execution took: {:.2?}", now.elapsed());
+        return Ok(());
+    }
+
+    let report = match args.data_type {
+        DataType::Trades => {
+            let mut table = TradesTable::new(&args.database, &args.table, downloader)
+                .await?
+                .with_file_log_level(file_log_level)
+                .with_table_strategy(args.table_strategy())
+                .with_incremental(!args.full);
+            if let Some(concurrency) = args.download_concurrency {
+                table = table.with_download_concurrency(concurrency);
+            }
+            if let Some(concurrency) = args.index_concurrency {
+                table = table.with_index_concurrency(concurrency);
+            }
+            if let Some(url) = &args.notify_url {
+                table = table.with_notifier(WebhookNotifier::new(url));
+            }
+            table.check_clickhouse().await?;
+            match &args.reindex {
+                Some(pair) => table.reindex_pair(pair).await?,
+                None => Some(table.index().await?),
+            }
+        }
+        DataType::AggTrades => {
+            let mut table = AggTradesTable::new(&args.database, &args.table, downloader)
+                .await?
+                .with_file_log_level(file_log_level);
+            if let Some(concurrency) = args.download_concurrency {
+                table = table.with_download_concurrency(concurrency);
+            }
+            if let Some(concurrency) = args.index_concurrency {
+                table = table.with_index_concurrency(concurrency);
+            }
+            Some(table.index().await?)
+        }
+        DataType::KLines => {
+            let mut table = KLinesTable::new(&args.database, &args.table, downloader)
+                .await?
+                .with_file_log_level(file_log_level);
+            if let Some(concurrency) = args.download_concurrency {
+                table = table.with_download_concurrency(concurrency);
+            }
+            if let Some(concurrency) = args.index_concurrency {
+                table = table.with_index_concurrency(concurrency);
+            }
+            Some(table.index().await?)
+        }
+    };
+    if let Some(report) = &report {
+        if let Some(path) = &args.report {
+            report.write_json(path)?;
+            log::info!("[main] Run report written to {}", path.display());
+        }
+        if report.cancelled {
+            anyhow::bail!("Indexing was cancelled");
+        }
+        if !report.is_success() {
+            anyhow::bail!("{} files could not be indexed", report.failures.len());
+        }
+    }
 
     log::info!("[main] Execution took: {:.2?}", now.elapsed());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let args = Args::try_parse_from([
+            "cryptoquant",
+            "--asset",
+            "futures",
+            "--cadence",
+            "daily",
+            "--data-type",
+            "aggTrades",
+            "--ends-with",
+            "USDT,USDC",
+            "--database",
+            "binance",
+            "--table",
+            "agg_usdt",
+        ])
+        .unwrap();
+        assert_eq!(args.database, "binance");
+        assert_eq!(args.table, "agg_usdt");
+        assert_eq!(args.ends_with, vec!["USDT", "USDC"]);
+
+        let downloader = args.downloader().unwrap();
+        assert_eq!(downloader.asset, Asset::Futures);
+        assert_eq!(downloader.futures_market, FuturesMarket::Um);
+        assert_eq!(downloader.cadence, Cadence::Daily);
+        assert_eq!(downloader.data_type, DataType::AggTrades);
+
+        let defaults = Args::try_parse_from(["cryptoquant"]).unwrap();
+        assert_eq!(defaults.asset, Asset::Spot);
+        assert_eq!(defaults.ends_with, vec!["USDC"]);
+        assert_eq!(defaults.table_strategy(), TableStrategy::Single);
+
+        assert!(Args::try_parse_from(["cryptoquant", "--asset", "gibberish"]).is_err());
+
+        let args = Args::try_parse_from(["cryptoquant", "--allow-missing-checksums"]).unwrap();
+        let checksum = args.downloader().unwrap().checksum;
+        assert!(checksum.allow_missing);
+        assert_eq!(checksum.algo, ChecksumOptions::default().algo);
+        assert_eq!(checksum.suffix, ChecksumOptions::default().suffix);
+    }
+}
@@ -1,2 +1,127 @@
 #[cfg(test)]
 pub fn is_normal<T: Sized + Send + Sync + Unpin>() {}
+
+/// Writes a zip at `path` holding `entries`, by name and contents
+#[cfg(test)]
+pub async fn write_zip(path: &std::path::Path, entries: &[(&str, &str)]) {
+    use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+
+    let mut writer = ZipFileWriter::with_tokio(tokio::fs::File::create(path).await.unwrap());
+    for (name, contents) in entries {
+        let entry = ZipEntryBuilder::new(name.to_string().into(), Compression::Deflate);
+        writer
+            .write_entry_whole(entry, contents.as_bytes())
+            .await
+            .unwrap();
+    }
+    writer.close().await.unwrap();
+}
+
+/// A completely indexed log row of `filename` in `test.TRADES`, holding trades 1 to 3
+#[cfg(test)]
+pub fn log_row(
+    filename: &str,
+    start_period_dt: u64,
+    end_period_dt: u64,
+) -> crate::data::db::trades_index_log::FileIndexLogRow {
+    use crate::data::db::trades_index_log::{FileIndexLogRow, IndexStatus};
+
+    FileIndexLogRow {
+        filename: filename.to_string(),
+        start_id: 1,
+        end_id: 3,
+        start_period_dt,
+        end_period_dt,
+        database: "test".to_string(),
+        table: "TRADES".to_string(),
+        num_rows: 3,
+        index_dt: 0,
+        status: IndexStatus::Complete,
+    }
+}
+
+/// A request received by `serve_once`
+#[cfg(test)]
+#[derive(Debug)]
+pub struct RecordedRequest {
+    /// e.g. `GET /?query=SELECT%201 HTTP/1.1`
+    pub request_line: String,
+    pub body: Vec<u8>,
+}
+
+#[cfg(test)]
+impl RecordedRequest {
+    /// The `query` parameter of the URL, decoded, or the body when it was sent there
+    pub fn query(&self) -> String {
+        let params = self
+            .request_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|target| target.split_once('?'))
+            .map_or("", |(_, params)| params);
+        params
+            .split('&')
+            .find_map(|param| param.strip_prefix("query="))
+            .map(percent_decode)
+            .unwrap_or_else(|| String::from_utf8_lossy(&self.body).to_string())
+    }
+}
+
+#[cfg(test)]
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[..2]).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+                continue;
+            }
+            _ => bytes.push(byte),
+        }
+        rest = tail;
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+/// Answers a single HTTP request on a local port with 200 OK and an empty body. Returns the
+/// URL of the server and a handle to the request, joined once it was answered
+#[cfg(test)]
+pub fn serve_once() -> (String, std::thread::JoinHandle<RecordedRequest>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+        RecordedRequest {
+            request_line: request_line.trim_end().to_string(),
+            body,
+        }
+    });
+    (url, server)
+}
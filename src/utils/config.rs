@@ -1,23 +1,165 @@
 // TODO: replace with config crate from crates.io
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::{env, fs};
 
+/// Env var with the path of the config file, `config.yaml` in the CWD when unset
+pub const CONFIG_ENV: &str = "CRYPTOQUANT_CONFIG";
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DataConfig {
     pub dir: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Where Binance's objects are read from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    #[default]
+    S3,
+    /// A directory laid out like the bucket, see `BinanceConfig::local_root`
+    Local,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct BinanceConfig {
     pub bucket_name: String,
+    /// s3 unless set to local
+    #[serde(default)]
+    pub backend: StoreBackend,
+    /// Root of the local mirror, holding e.g. data/spot/monthly/trades. Required by the
+    /// local backend
+    #[serde(default)]
+    pub local_root: Option<String>,
+    /// Region of the bucket, ap-northeast-1 (where Binance's bucket lives) when unset
+    #[serde(default)]
+    pub region: Option<String>,
+    /// URL of an S3 compatible store, e.g. MinIO or Cloudflare R2, instead of AWS. `region`
+    /// is sent along with it, us-east-1 when unset
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Keys for a private mirror, anonymous access when unset. `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY` take precedence when set
+    #[serde(default)]
+    pub access_key: Option<String>,
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// User-Agent header sent with every S3 request; the s3 crate default when unset
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Attempts per S3 request before giving up, 3 when unset
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Seconds a download may go without receiving a byte, 300 when unset. A download
+    /// which stalls is retried like any other failed request
+    #[serde(default)]
+    pub download_timeout_secs: Option<u64>,
+    /// Soft cap on the combined download speed of all files, unlimited when unset
+    #[serde(default)]
+    pub max_bytes_per_sec: Option<u64>,
+    /// Cap on the combined S3 requests per second, e.g. listings, unlimited when unset
+    #[serde(default)]
+    pub max_requests_per_sec: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ClickhouseConfig {
-    pub url: String,
+    /// HTTP url of ClickHouse, or a list of endpoints serving the same tables, e.g. the
+    /// replicas of `cluster`. Tables are spread over them, the inserts of every pair go to
+    /// an endpoint of their own and fail over to the next one
+    #[serde(deserialize_with = "one_or_many")]
+    pub url: Vec<String>,
     pub user: String,
     #[serde(default = "default_ch_password")]
     pub password: String,
+    /// Cluster to create tables `ON CLUSTER`; single node when unset
+    #[serde(default)]
+    pub cluster: Option<String>,
+    #[serde(default)]
+    pub inserter: InserterConfig,
+    /// Codec the bodies of inserts are compressed with, lz4 when unset
+    #[serde(default)]
+    pub compression: ClickhouseCompression,
+    /// Attempts per INSERT including the first one, 3 when unset. With 1 no copy of the
+    /// rows is kept to send them again
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Backoff in milliseconds before an INSERT is sent again, doubling every attempt
+    #[serde(default)]
+    pub retry_base_ms: Option<u64>,
+}
+
+/// Codecs of the clickhouse crate, written `none`, `lz4` or `lz4hc:<level>` in the config.
+/// It has no zstd, which is rejected with the config like any other unknown codec
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ClickhouseCompression {
+    /// Saves the CPU spent compressing, e.g. on a local network
+    None,
+    #[default]
+    Lz4,
+    /// LZ4HC at a level of 1 to 12, sent as plain LZ4 until lz4_flex supports HC
+    Lz4Hc(i32),
+}
+
+impl TryFrom<String> for ClickhouseCompression {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "none" => Ok(ClickhouseCompression::None),
+            "lz4" => Ok(ClickhouseCompression::Lz4),
+            other => {
+                let level = other
+                    .strip_prefix("lz4hc:")
+                    .and_then(|level| level.parse().ok())
+                    .filter(|level| (1..=12).contains(level));
+                level.map(ClickhouseCompression::Lz4Hc).ok_or_else(|| {
+                    format!(
+                        "Unknown ClickHouse compression {:?}, expected none, lz4 or lz4hc:<1-12>",
+                        other
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl From<ClickhouseCompression> for String {
+    fn from(value: ClickhouseCompression) -> Self {
+        match value {
+            ClickhouseCompression::None => "none".to_string(),
+            ClickhouseCompression::Lz4 => "lz4".to_string(),
+            ClickhouseCompression::Lz4Hc(level) => format!("lz4hc:{}", level),
+        }
+    }
+}
+
+/// Batching of inserts: memory use vs. throughput
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InserterConfig {
+    /// Rows after which an INSERT is ended. As many rows are kept in memory per file being
+    /// indexed, to send the INSERT again if it fails, unless `max_attempts` is 1
+    pub max_rows: u64,
+    /// Seconds after which an INSERT is ended
+    pub period_secs: u64,
+    /// Rows written between two commits
+    pub commit_rows: u64,
+}
+
+impl Default for InserterConfig {
+    fn default() -> Self {
+        InserterConfig {
+            max_rows: 500_000,
+            period_secs: 15,
+            // capsule size
+            commit_rows: 8192,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -28,17 +170,164 @@ pub struct Config {
 }
 
 impl Config {
-    pub fn create() -> Self {
-        // Read the YAML file
-        let config_content = fs::read_to_string("config.yaml").expect("Failed to read config.yaml");
+    /// The config at `Config::path`, read and parsed once per process. Failures are not
+    /// cached, so a config written later on is picked up
+    pub fn create() -> Result<Arc<Self>> {
+        Config::cached(Config::path())
+    }
+
+    /// Reads `path` the first time only, later calls share that config
+    fn cached(path: PathBuf) -> Result<Arc<Self>> {
+        static CACHE: OnceLock<Mutex<HashMap<PathBuf, Arc<Config>>>> = OnceLock::new();
+        let cache = CACHE.get_or_init(Default::default);
+        if let Some(config) = cache.lock().unwrap().get(&path) {
+            return Ok(Arc::clone(config));
+        }
+        // read outside of the lock, a concurrent first read keeps whichever config lands first
+        let config = Arc::new(Config::from_path(&path)?);
+        Ok(Arc::clone(
+            cache.lock().unwrap().entry(path).or_insert(config),
+        ))
+    }
 
-        // Parse the YAML content into the Config struct
-        let config: Config = serde_yaml::from_str(&config_content).expect("Failed to parse YAML");
+    /// `CRYPTOQUANT_CONFIG` if set, otherwise `config.yaml`
+    pub fn path() -> PathBuf {
+        env::var_os(CONFIG_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("config.yaml"))
+    }
+
+    /// Reads `path` every time, unlike `create`
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let config_content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+        serde_yaml::from_str(&config_content)
+            .with_context(|| format!("Failed to parse config: {}", path.display()))
+    }
+}
 
-        config
+/// A single string or a list of them
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
     }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
 
 fn default_ch_password() -> String {
     env::var("CLICKHOUSE_PASSWORD").unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Held by the tests which point `CONFIG_ENV` elsewhere
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_inserter_config() {
+        let config: ClickhouseConfig =
+            serde_yaml::from_str("url: http://localhost:8123\nuser: default").unwrap();
+        assert_eq!(config.inserter, InserterConfig::default());
+
+        let config: ClickhouseConfig = serde_yaml::from_str(
+            "url: http://localhost:8123\nuser: default\ninserter:\n  commit_rows: 1024",
+        )
+        .unwrap();
+        assert_eq!(config.inserter.commit_rows, 1024);
+        assert_eq!(config.inserter.max_rows, 500_000);
+    }
+
+    #[test]
+    fn test_clickhouse_compression() {
+        let parse = |compression: &str| {
+            serde_yaml::from_str::<ClickhouseConfig>(&format!(
+                "url: http://localhost:8123\nuser: default\ncompression: {compression}"
+            ))
+            .map(|config| config.compression)
+        };
+        let config: ClickhouseConfig =
+            serde_yaml::from_str("url: http://localhost:8123\nuser: default").unwrap();
+        assert_eq!(config.compression, ClickhouseCompression::Lz4);
+        assert_eq!(parse("none").unwrap(), ClickhouseCompression::None);
+        assert_eq!(parse("lz4").unwrap(), ClickhouseCompression::Lz4);
+        assert_eq!(parse("lz4hc:9").unwrap(), ClickhouseCompression::Lz4Hc(9));
+
+        for bad in ["zstd", "gzip", "lz4hc", "lz4hc:0", "lz4hc:13", "lz4hc:x"] {
+            let error = parse(bad).unwrap_err();
+            assert!(error.to_string().contains(bad), "{}", error);
+        }
+        assert_eq!(
+            String::from(ClickhouseCompression::Lz4Hc(4)),
+            "lz4hc:4".to_string()
+        );
+    }
+
+    #[test]
+    fn test_clickhouse_urls() {
+        let config: ClickhouseConfig =
+            serde_yaml::from_str("url: http://localhost:8123\nuser: default").unwrap();
+        assert_eq!(config.url, vec!["http://localhost:8123"]);
+
+        let config: ClickhouseConfig =
+            serde_yaml::from_str("url: [http://ch1:8123, http://ch2:8123]\nuser: default").unwrap();
+        assert_eq!(config.url, vec!["http://ch1:8123", "http://ch2:8123"]);
+    }
+
+    #[test]
+    fn test_store_backend() {
+        let config: BinanceConfig = serde_yaml::from_str("bucket_name: b").unwrap();
+        assert_eq!(config.backend, StoreBackend::S3);
+
+        let config: BinanceConfig =
+            serde_yaml::from_str("bucket_name: b\nbackend: local\nlocal_root: /mirror").unwrap();
+        assert_eq!(config.backend, StoreBackend::Local);
+        assert_eq!(config.local_root.as_deref(), Some("/mirror"));
+    }
+
+    #[test]
+    fn test_config_env() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let mut config: serde_yaml::Value =
+            serde_yaml::from_str(&fs::read_to_string("config.yaml").unwrap()).unwrap();
+        config["binance"]["user_agent"] = "from-env".into();
+        // kept on disk, other tests may read the config while the env var is set
+        let (mut file, path) = tempfile::NamedTempFile::new().unwrap().keep().unwrap();
+        serde_yaml::to_writer(&mut file, &config).unwrap();
+
+        env::set_var(CONFIG_ENV, &path);
+        let read = Config::create();
+        env::remove_var(CONFIG_ENV);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            read.unwrap().binance.user_agent.as_deref(),
+            Some("from-env")
+        );
+
+        assert!(Config::from_path(Path::new("does-not-exist.yaml")).is_err());
+    }
+
+    #[test]
+    fn test_create_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::copy("config.yaml", &path).unwrap();
+
+        let first = Config::cached(path.clone());
+        // a second read would fail now
+        fs::remove_file(&path).unwrap();
+        let second = Config::cached(path);
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+    }
+}
@@ -0,0 +1,58 @@
+//! Throughput of downloads and indexing, exported for Prometheus when built with
+//! `--features metrics`. Without the feature every function here is a no-op.
+
+pub const FILES_DOWNLOADED: &str = "cryptoquant_files_downloaded_total";
+pub const BYTES_DOWNLOADED: &str = "cryptoquant_bytes_downloaded_total";
+pub const ROWS_INSERTED: &str = "cryptoquant_rows_inserted_total";
+pub const INSERT_ERRORS: &str = "cryptoquant_insert_errors_total";
+/// Gauge labelled with `task`, either download or index
+pub const TASKS_IN_FLIGHT: &str = "cryptoquant_tasks_in_flight";
+
+/// Serves the metrics on `0.0.0.0:<port>/metrics`. Needs a tokio runtime.
+#[cfg(feature = "metrics")]
+pub fn install_exporter(port: u16) -> anyhow::Result<()> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(([0, 0, 0, 0], port))
+        .install()
+        .map_err(|e| anyhow::anyhow!("Could not start the metrics exporter: {}", e))
+}
+
+pub fn file_downloaded(bytes: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!(FILES_DOWNLOADED).increment(1);
+        metrics::counter!(BYTES_DOWNLOADED).increment(bytes);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = bytes;
+}
+
+pub fn rows_inserted(rows: u64) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(ROWS_INSERTED).increment(rows);
+    #[cfg(not(feature = "metrics"))]
+    let _ = rows;
+}
+
+pub fn insert_error() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(INSERT_ERRORS).increment(1);
+}
+
+/// Counts a task as in flight until dropped
+pub struct InFlight(#[allow(dead_code)] &'static str);
+
+impl InFlight {
+    pub fn start(task: &'static str) -> Self {
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(TASKS_IN_FLIGHT, "task" => task).increment(1.0);
+        InFlight(task)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(TASKS_IN_FLIGHT, "task" => self.0).decrement(1.0);
+    }
+}
@@ -1 +1,6 @@
+pub mod clock;
 pub mod config;
+pub mod metrics;
+pub mod progress;
+pub mod retry;
+pub mod throttle;
@@ -0,0 +1,178 @@
+//! Progress bars of the downloads, drawn by indicatif when built with `--features progress`
+//! and once `install` found a terminal. Otherwise every function here is a no-op and the
+//! logs are all there is.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::data::binance::file_collection::DownloadProgress;
+
+#[cfg(feature = "progress")]
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+#[cfg(feature = "progress")]
+static UI: std::sync::OnceLock<ProgressUi> = std::sync::OnceLock::new();
+
+/// Draws the bars on stderr from now on, if it is a terminal. Returns whether it does
+#[cfg(feature = "progress")]
+pub fn install() -> bool {
+    use std::io::IsTerminal;
+
+    std::io::stderr().is_terminal()
+        && UI
+            .set(ProgressUi::new(ProgressDrawTarget::stderr()))
+            .is_ok()
+}
+
+/// Writes the logs to stdout. While the bars are drawn they are cleared for every line and
+/// redrawn after it, so the two don't tear each other
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "progress")]
+        if let Some(ui) = UI.get() {
+            return ui
+                .multi
+                .suspend(|| io::stdout().write_all(buf))
+                .map(|_| buf.len());
+        }
+        io::stdout().write_all(buf).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Moves the overall bars to where `event` says the downloads are
+pub fn on_event(event: &DownloadProgress) {
+    #[cfg(feature = "progress")]
+    if let Some(ui) = UI.get() {
+        ui.on_event(event);
+    }
+    #[cfg(not(feature = "progress"))]
+    let _ = event;
+}
+
+/// Shows a spinner for a download until dropped
+pub struct DownloadBar {
+    #[cfg(feature = "progress")]
+    bar: Option<ProgressBar>,
+}
+
+impl DownloadBar {
+    pub fn start(path: &Path) -> Self {
+        #[cfg(feature = "progress")]
+        return DownloadBar {
+            bar: UI.get().map(|ui| ui.start(path)),
+        };
+        #[cfg(not(feature = "progress"))]
+        {
+            let _ = path;
+            DownloadBar {}
+        }
+    }
+}
+
+impl Drop for DownloadBar {
+    fn drop(&mut self) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// A bar of the files done, one of their bytes and a spinner per download in flight
+#[cfg(feature = "progress")]
+struct ProgressUi {
+    multi: MultiProgress,
+    files: ProgressBar,
+    bytes: ProgressBar,
+}
+
+#[cfg(feature = "progress")]
+impl ProgressUi {
+    fn new(target: ProgressDrawTarget) -> Self {
+        let multi = MultiProgress::with_draw_target(target);
+        let files = multi.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template("{prefix:>9} [{bar:40}] {pos}/{len} {msg}")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            ),
+        );
+        files.set_prefix("files");
+        let bytes = multi.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "{prefix:>9} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}",
+                )
+                .expect("valid template")
+                .progress_chars("=> "),
+            ),
+        );
+        bytes.set_prefix("bytes");
+        ProgressUi {
+            multi,
+            files,
+            bytes,
+        }
+    }
+
+    fn on_event(&self, event: &DownloadProgress) {
+        self.files.set_length(event.total as u64);
+        self.files.set_position(event.completed as u64);
+        self.bytes.set_length(event.total_bytes);
+        self.bytes.set_position(event.downloaded_bytes);
+        if !event.ok {
+            self.files
+                .set_message(format!("failed: {}", event.path.display()));
+        }
+    }
+
+    fn start(&self, path: &Path) -> ProgressBar {
+        let bar = self.multi.add(ProgressBar::new_spinner());
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        bar.set_message(name.to_string_lossy().to_string());
+        bar.enable_steady_tick(std::time::Duration::from_millis(200));
+        bar
+    }
+}
+
+#[cfg(all(test, feature = "progress"))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn event(completed: usize, ok: bool, downloaded_bytes: u64) -> DownloadProgress {
+        DownloadProgress {
+            pair: Arc::from("BTCUSDC"),
+            path: Arc::from(Path::new("BTCUSDC-trades-2024-01.zip")),
+            bytes: 10,
+            ok,
+            completed,
+            total: 3,
+            downloaded_bytes,
+            total_bytes: 30,
+        }
+    }
+
+    #[test]
+    fn test_events_move_the_bars() {
+        let ui = ProgressUi::new(ProgressDrawTarget::hidden());
+        ui.on_event(&event(1, true, 10));
+        assert_eq!((ui.files.position(), ui.files.length()), (1, Some(3)));
+        assert_eq!((ui.bytes.position(), ui.bytes.length()), (10, Some(30)));
+
+        // a failed file counts as done, its bytes do not
+        ui.on_event(&event(2, false, 10));
+        assert_eq!(ui.files.position(), 2);
+        assert_eq!(ui.bytes.position(), 10);
+        assert!(ui.files.message().starts_with("failed"));
+
+        let bar = ui.start(Path::new("/data/BTCUSDC-trades-2024-02.zip"));
+        assert_eq!(bar.message(), "BTCUSDC-trades-2024-02.zip");
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config;
+
+/// Retries with exponential backoff and jitter. The error of the last attempt is returned
+/// unchanged, so callers see the same error as without retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Process wide policy for S3 requests, `binance.max_attempts` overrides the default
+    pub fn global() -> &'static RetryPolicy {
+        static GLOBAL: OnceLock<RetryPolicy> = OnceLock::new();
+        GLOBAL.get_or_init(|| {
+            let mut policy = RetryPolicy::default();
+            // a missing config fails the S3 requests themselves, with the actual error
+            let config = config::Config::create().ok();
+            if let Some(max_attempts) = config.and_then(|c| c.binance.max_attempts) {
+                policy.max_attempts = max_attempts.max(1);
+            }
+            policy
+        })
+    }
+
+    /// Policy of INSERTs into ClickHouse, from `clickhouse.max_attempts` and
+    /// `clickhouse.retry_base_ms`
+    pub fn clickhouse(cfg: &config::ClickhouseConfig) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        if let Some(max_attempts) = cfg.max_attempts {
+            policy.max_attempts = max_attempts.max(1);
+        }
+        if let Some(base_ms) = cfg.retry_base_ms {
+            policy.base_delay = Duration::from_millis(base_ms);
+        }
+        policy
+    }
+
+    /// Backoff before the retry following `attempt` (1-based): doubles every attempt up to
+    /// `max_delay`, then a random half of it is dropped so concurrent callers spread out
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let jitter = nanos as f64 / 1e9 / 2.0;
+        backoff.mul_f64(1.0 - jitter)
+    }
+
+    pub async fn run<T, E, F, Fut>(&self, what: &str, f: F) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.run_when(what, |_| true, f).await
+    }
+
+    /// Like `run`, but only errors `is_transient` holds for are retried, any other one is
+    /// returned straight away
+    pub async fn run_when<T, E, F, Fut>(
+        &self,
+        what: &str,
+        is_transient: impl Fn(&E) -> bool,
+        mut f: F,
+    ) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt >= self.max_attempts || !is_transient(&e) => return Err(e),
+                Err(e) => {
+                    let delay = self.delay(attempt);
+                    log::warn!(
+                        "{} failed (attempt {}/{}), retrying in {:.2?}: {}",
+                        what,
+                        attempt,
+                        self.max_attempts,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Result};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_success() {
+        let calls = AtomicU32::new(0);
+        let result = policy(3)
+            .run("flaky", || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(anyhow!("503 Slow Down")),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_returns_last_error() {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = policy(2)
+            .run("broken", || async {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow!("failure {}", n))
+            })
+            .await;
+        assert_eq!(result.unwrap_err().to_string(), "failure 1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_only_transient_errors_are_retried() {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = policy(5)
+            .run_when(
+                "picky",
+                |e: &anyhow::Error| e.to_string().starts_with("transient"),
+                || async {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => Err(anyhow!("transient failure")),
+                        _ => Err(anyhow!("permanent failure")),
+                    }
+                },
+            )
+            .await;
+        assert_eq!(result.unwrap_err().to_string(), "permanent failure");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(4),
+        };
+        assert!(policy.delay(1) <= Duration::from_secs(1));
+        assert!(policy.delay(1) >= Duration::from_millis(500));
+        assert!(policy.delay(10) <= Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_clickhouse_policy() {
+        let cfg: config::ClickhouseConfig =
+            serde_yaml::from_str("url: http://localhost:8123\nuser: default").unwrap();
+        assert_eq!(RetryPolicy::clickhouse(&cfg), RetryPolicy::default());
+
+        let cfg: config::ClickhouseConfig = serde_yaml::from_str(
+            "url: http://localhost:8123\nuser: default\nmax_attempts: 5\nretry_base_ms: 100",
+        )
+        .unwrap();
+        let policy = RetryPolicy::clickhouse(&cfg);
+        assert_eq!(policy.max_attempts, 5);
+        assert_eq!(policy.base_delay, Duration::from_millis(100));
+    }
+}
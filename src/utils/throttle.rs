@@ -0,0 +1,135 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::config;
+
+/// Token bucket limiting throughput in bytes/sec, or in requests/sec counting a request as
+/// one. Callers may overdraw the bucket and then sleep off the debt, so the limit is a soft
+/// cap shared by all concurrent callers.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: f64,
+    /// Up to this fraction is added to every wait, so callers throttled together don't all
+    /// wake up at once
+    jitter: f64,
+    state: Mutex<ThrottleState>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Throttle {
+            bytes_per_sec,
+            jitter: 0.0,
+            state: Mutex::new(ThrottleState {
+                available: bytes_per_sec,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Adds up to `fraction` of every wait on top of it
+    pub fn with_jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.max(0.0);
+        self
+    }
+
+    /// Process wide download throttle from `binance.max_bytes_per_sec`, if configured
+    pub fn global() -> Option<&'static Throttle> {
+        static GLOBAL: OnceLock<Option<Throttle>> = OnceLock::new();
+        GLOBAL
+            .get_or_init(|| {
+                config::Config::create()
+                    .ok()
+                    .and_then(|c| c.binance.max_bytes_per_sec)
+                    .map(Throttle::new)
+            })
+            .as_ref()
+    }
+
+    /// Process wide S3 request throttle from `binance.max_requests_per_sec`, if configured.
+    /// Every request consumes 1
+    pub fn requests() -> Option<&'static Throttle> {
+        static REQUESTS: OnceLock<Option<Throttle>> = OnceLock::new();
+        REQUESTS
+            .get_or_init(|| {
+                config::Config::create()
+                    .ok()
+                    .and_then(|c| c.binance.max_requests_per_sec)
+                    .map(|rate| Throttle::new(rate).with_jitter(0.1))
+            })
+            .as_ref()
+    }
+
+    /// Takes `bytes` from the bucket, waiting until the rate allows for them
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            // allow at most one second worth of burst
+            state.available =
+                (state.available + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+            state.refilled_at = now;
+            state.available -= bytes as f64;
+
+            if state.available < 0.0 {
+                Duration::from_secs_f64(-state.available / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .subsec_nanos();
+            let jitter = self.jitter * nanos as f64 / 1e9;
+            tokio::time::sleep(wait.mul_f64(1.0 + jitter)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn throttle_is_normal() {
+        test_utils::is_normal::<Throttle>();
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let throttle = Throttle::new(1_000);
+        let now = Instant::now();
+        // the initial burst is free
+        throttle.consume(1_000).await;
+        assert!(now.elapsed() < Duration::from_millis(100));
+
+        // 500 bytes at 1000 bytes/sec
+        throttle.consume(500).await;
+        assert!(now.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn test_request_rate() {
+        let throttle = Throttle::new(20).with_jitter(0.1);
+        let now = Instant::now();
+        // 20 requests of burst, then 10 more at 20 requests/sec
+        for _ in 0..30 {
+            throttle.consume(1).await;
+        }
+        let elapsed = now.elapsed();
+        assert!(elapsed >= Duration::from_millis(450));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+}